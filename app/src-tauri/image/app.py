@@ -3,32 +3,157 @@ import json
 import asyncio
 import os
 import base64
+import struct
+import uuid
+import zlib
 from collections import deque
 from pam import run_pam
 import platform
 
 #Config
 MOCKDATA = False
+# Version of the websocket messages, see protocol.rs in the app
+PROTOCOL_VERSION = 1
 
 # Global variables
-prompt_running = ["stopped"] #"running", "stopped", "loading", "na"
+prompt_running = ["idle"] #"idle", "running" or "errored", see run_state.rs in the app
 
 # Global message queue
 message_queue = deque()
 
+# Reliable delivery, see delivery.rs in the app. Messages after init are numbered and kept until the app
+# acknowledges them, and sent again after a reconnect if it didn't.
+session_id = [None]
+next_seq = [1]
+last_received = [0]  # Last seq received from the app
+unacked = deque()  # (seq, message) sent but not acknowledged
+session_ready = [False]  # Set once the app answered init, until then nothing is sent
+
 # Get host IP from environment variable, fallback to localhost
 
 HOST_IP = os.getenv("HOST_IP")
+# Authenticates this agent to the app's websocket server
+WS_TOKEN = os.getenv("RADAH_WS_TOKEN", "")
+# Port the app's websocket server listens on, 3030 unless it was taken
+WS_PORT = int(os.getenv("RADAH_WS_PORT", "3030"))
+# The app listens on the next free port when the configured one is taken, so the ports above it are tried too
+WS_PORT_RANGE = int(os.getenv("RADAH_WS_PORT_RANGE", "100"))
 RUNNING_LOCALLY = False
 if not HOST_IP:
     HOST_IP = "localhost"
     RUNNING_LOCALLY = True
 
 
+# Files the host can list and download, sent back in base64 chunks
+WORKSPACE_ROOT = os.path.realpath(os.path.expanduser("~"))
+FILE_CHUNK_SIZE = 512 * 1024
+MAX_FETCH_SIZE = 200 * 1024 * 1024
+
+
+# Binary frames, see frames.rs in the app: magic "RF", format version, 16 byte transfer id, chunk index,
+# chunk count and crc32 of the chunk, then the chunk. Used both ways for messages too big for one frame.
+FRAME_HEADER = struct.Struct(">2sB16sIII")
+FRAME_MAGIC = b"RF"
+FRAME_VERSION = 1
+MIN_FRAME_CHUNK = 16 * 1024
+MAX_FRAME_CHUNK = 512 * 1024
+TARGET_FRAME_CHUNKS = 32
+
+
+def split_frames(data):
+    chunk_size = min(max(len(data) // TARGET_FRAME_CHUNKS, MIN_FRAME_CHUNK), MAX_FRAME_CHUNK)
+    transfer_id = uuid.uuid4().bytes
+    count = max(1, (len(data) + chunk_size - 1) // chunk_size)
+    for index in range(count):
+        chunk = data[index * chunk_size:(index + 1) * chunk_size]
+        yield FRAME_HEADER.pack(FRAME_MAGIC, FRAME_VERSION, transfer_id, index, count, zlib.crc32(chunk)) + chunk
+
+
+class FrameReassembler:
+    def __init__(self):
+        self.transfers = {}
+
+    def push(self, frame):
+        """Adds a frame, returning the message bytes once all of its chunks are in"""
+        if len(frame) < FRAME_HEADER.size:
+            raise ValueError("Not a message frame")
+        magic, version, transfer_id, index, count, crc = FRAME_HEADER.unpack_from(frame)
+        if magic != FRAME_MAGIC or version != FRAME_VERSION:
+            raise ValueError("Unsupported frame format")
+        if count == 0 or index >= count:
+            raise ValueError(f"Invalid chunk {index} of {count}")
+        chunk = frame[FRAME_HEADER.size:]
+        if zlib.crc32(chunk) != crc:
+            self.transfers.pop(transfer_id, None)
+            raise ValueError(f"Checksum mismatch in chunk {index} of {count}")
+        chunks = self.transfers.setdefault(transfer_id, [None] * count)
+        if len(chunks) != count:
+            self.transfers.pop(transfer_id, None)
+            raise ValueError("Chunk count changed during a transfer")
+        chunks[index] = chunk
+        if any(c is None for c in chunks):
+            return None
+        del self.transfers[transfer_id]
+        return b"".join(chunks)
+
+
 def get_prompt_running():
     return prompt_running[0]
 
 
+def resolve_workspace_path(path):
+    full_path = os.path.realpath(os.path.join(WORKSPACE_ROOT, path or ""))
+    if full_path != WORKSPACE_ROOT and not full_path.startswith(WORKSPACE_ROOT + os.sep):
+        raise ValueError(f"{path} is outside the workspace")
+    return full_path
+
+
+def list_files(request_id, path):
+    try:
+        directory = resolve_workspace_path(path)
+        files = []
+        for entry in sorted(os.scandir(directory), key=lambda e: e.name):
+            try:
+                is_dir = entry.is_dir()
+                stat = entry.stat()
+            except OSError:
+                continue
+            files.append({
+                "path": os.path.relpath(entry.path, WORKSPACE_ROOT),
+                "is_dir": is_dir,
+                "size": 0 if is_dir else stat.st_size,
+                "modified": stat.st_mtime,
+            })
+        message_queue.append({"message-type": "file-list", "request_id": request_id, "files": files})
+    except Exception as e:
+        print(f"Error listing {path}: {e}")
+        message_queue.append({"message-type": "file-error", "request_id": request_id, "error": str(e)})
+
+
+def fetch_file(request_id, path):
+    try:
+        full_path = resolve_workspace_path(path)
+        if not os.path.isfile(full_path):
+            raise ValueError(f"{path} is not a file")
+        if os.path.getsize(full_path) > MAX_FETCH_SIZE:
+            raise ValueError(f"{path} is larger than {MAX_FETCH_SIZE // (1024 * 1024)}MB")
+        with open(full_path, "rb") as f:
+            encoded = base64.b64encode(f.read()).decode("ascii")
+
+        total_chunks = max(1, (len(encoded) + FILE_CHUNK_SIZE - 1) // FILE_CHUNK_SIZE)
+        for i in range(total_chunks):
+            message_queue.append({
+                "message-type": "file-chunk",
+                "request_id": request_id,
+                "chunk": i,
+                "total_chunks": total_chunks,
+                "data": encoded[i * FILE_CHUNK_SIZE:(i + 1) * FILE_CHUNK_SIZE],
+            })
+    except Exception as e:
+        print(f"Error fetching {path}: {e}")
+        message_queue.append({"message-type": "file-error", "request_id": request_id, "error": str(e)})
+
+
 async def process_incoming_files(agent_id, files):
     for file in files:
         file_name = file.get("name")
@@ -69,32 +194,86 @@ async def run_websocket_client(message_queue, agent_id):
     # Global websocket connection
     ws_connection = None
 
+    # Port the app was last found on, tried first
+    last_port = [WS_PORT]
+
+    async def open_session(port):
+        # Other instances of the app can hold ports in the range, only ours accepts the token and answers with a session
+        connection = await websockets.connect(f'ws://{HOST_IP}:{port}/ws', open_timeout=5)
+        try:
+            session_ready[0] = False
+            await connection.send(json.dumps({"connection-type": "agent", "container_id": agent_id, "agent_id": agent_id, "message-type": "init", "token": WS_TOKEN, "prompt_running": prompt_running[0], "session_id": session_id[0], "last_seq": last_received[0], "binary_frames": True, "show_ui": False, "protocol_version": PROTOCOL_VERSION}))
+            reply = json.loads(await asyncio.wait_for(connection.recv(), timeout=10))
+            if reply.get("message-type") != "session":
+                raise ConnectionError(f"rejected init: {reply.get('error')}")
+        except Exception:
+            await connection.close()
+            raise
+        resume_session(reply["session_id"], reply["last_seq"])
+        return connection
+
     async def connect():
         nonlocal ws_connection
         retry_delay = 1  # Initial delay in seconds
         max_delay = 12   # Maximum delay in seconds
         
         while True:
-            try:
-                ws_connection = await websockets.connect(f'ws://{HOST_IP}:3030/ws')
-                print(f"Successfully connected to WebSocket server at {HOST_IP}")
-                # Send initial dummy message
-                message_queue.appendleft({"connection-type": "agent", "container_id": agent_id, "agent_id": agent_id, "message-type": "init", "prompt_running": prompt_running[0], "show_ui": False})
-                return ws_connection
-            except Exception as e:
-                print(f"Failed to connect to WebSocket server: {e}")
-                print(f"Retrying in {retry_delay} seconds...")
-                await asyncio.sleep(retry_delay)
-                # Exponential backoff with max delay
-                retry_delay = min(retry_delay * 2, max_delay)
+            ports = [last_port[0]] + [port for port in range(WS_PORT, WS_PORT + WS_PORT_RANGE + 1) if port != last_port[0]]
+            for port in ports:
+                try:
+                    ws_connection = await open_session(port)
+                    last_port[0] = port
+                    print(f"Successfully connected to WebSocket server at {HOST_IP}:{port}")
+                    return ws_connection
+                except Exception as e:
+                    if port == last_port[0]:
+                        print(f"Failed to connect to WebSocket server at {HOST_IP}:{port}: {e}")
+            print(f"No WebSocket server between ports {WS_PORT} and {WS_PORT + WS_PORT_RANGE}, retrying in {retry_delay} seconds...")
+            await asyncio.sleep(retry_delay)
+            # Exponential backoff with max delay
+            retry_delay = min(retry_delay * 2, max_delay)
+
+    def resume_session(new_session_id, last_seq):
+        # A new session means the app restarted and lost track of everything, so all unacknowledged
+        # messages are sent again with new numbers. Otherwise only the ones it never got are sent again.
+        same_session = new_session_id == session_id[0]
+        session_id[0] = new_session_id
+        if not same_session:
+            last_received[0] = 0
+            next_seq[0] = 1
+        pending = [message for seq, message in unacked if not same_session or seq > last_seq]
+        unacked.clear()
+        for message in reversed(pending):
+            if not same_session:
+                message = {key: value for key, value in message.items() if key != "seq"}
+            message_queue.appendleft(message)
+        if pending:
+            print(f"Resending {len(pending)} messages the app did not acknowledge")
+        session_ready[0] = True
+
+    def acknowledged(seq):
+        while unacked and unacked[0][0] <= seq:
+            unacked.popleft()
+
+    async def send_ack(seq):
+        try:
+            await ws_connection.send(json.dumps({"message-type": "ack", "seq": seq, "protocol_version": PROTOCOL_VERSION}))
+        except Exception as e:
+            # The app sends the message again after the reconnect and it is acknowledged then
+            print(f"Failed to acknowledge message {seq}: {e}")
 
     async def message_handler():
         nonlocal ws_connection
         message_buffers = {}  # Use a dictionary to store message buffers by message_id
+        frames = FrameReassembler()
         
         async def promptMessageHandler(json_message):
             print(f"Prompt message handler: {json_message}")
-            if json_message["message-type"] != "prompt" or prompt_running[0] != "stopped" or "text" not in json_message:
+            if json_message["message-type"] != "prompt" or "text" not in json_message:
+                return
+            # The app queues prompts until the running one finishes, so this one would be lost
+            if prompt_running[0] == "running":
+                message_queue.append({"message-type": "message", "text": "A prompt arrived while another was running and was not run", "error": True, "show_ui": True})
                 return
             prompt_running[0] = "running"
             files = json_message.get("files", [])
@@ -107,21 +286,54 @@ async def run_websocket_client(message_queue, agent_id):
 
             additional_system_prompt = json_message.get("additional_system_prompt", "")
             print(f"Additional system prompt: {additional_system_prompt}")
+            final_state = "idle"
             try:
                 await run_pam(message_queue, json_message["text"], recent_messages, get_prompt_running, MOCKDATA, additional_system_prompt)
             except Exception as e:
+                final_state = "errored"
                 message_queue.append({"message-type": "message", "text": f"Error running Pam: {str(e)}", "error": True, "show_ui": True })
                 print(f"Error running Pam: {e}")
             finally:
-                prompt_running[0] = "stopped"
+                prompt_running[0] = final_state
                 message_queue.append({"message-type": "message", "text": "The agent has finished running", "end_message": True, "show_ui": True, "prompt_running": prompt_running[0] })
                 print("Pam finished")
 
+        async def handle_app_message(json_message):
+            # Messages already handled are replays after a reconnect, only acknowledged again
+            seq = json_message.get("seq")
+            if seq is not None:
+                if seq <= last_received[0]:
+                    await send_ack(seq)
+                    return
+                last_received[0] = seq
+
+            if json_message["message-type"] == "prompt":
+                asyncio.create_task(promptMessageHandler(json_message))
+            elif json_message["message-type"] == "stop":
+                print("Stopping the running prompt")
+                prompt_running[0] = "idle"
+            elif json_message["message-type"] == "list-files":
+                list_files(json_message.get("request_id"), json_message.get("path"))
+            elif json_message["message-type"] == "fetch-file":
+                fetch_file(json_message.get("request_id"), json_message.get("path"))
+
+            if seq is not None:
+                await send_ack(seq)
+
         while True:
             if ws_connection:
                 try:
                     while True:
                         message = await ws_connection.recv()
+                        if isinstance(message, bytes):
+                            try:
+                                data = frames.push(message)
+                            except ValueError as e:
+                                print(f"Dropped binary frame: {e}")
+                                continue
+                            if data is not None:
+                                await handle_app_message(json.loads(data))
+                            continue
                         json_message = json.loads(message)
                         print(f"Received message: {json_message}\n\n")
                         if "message_id" in json_message and "chunk" in json_message and "total_chunks" in json_message:
@@ -139,12 +351,13 @@ async def run_websocket_client(message_queue, agent_id):
                                 full_message = ''.join(message_buffers[message_id][i] for i in range(total_chunks))
                                 json_message = json.loads(full_message)
                                 del message_buffers[message_id]  # Clear buffer after processing
-
-                                if json_message["message-type"] == "prompt":
-                                    asyncio.create_task(promptMessageHandler(json_message))
-                                elif json_message["message-type"] == "stop":
-                                    print("Updating prompt running to stopped")
-                                    prompt_running[0] = "stopped"
+                                await handle_app_message(json_message)
+                        elif json_message.get("message-type") == "session":
+                            resume_session(json_message["session_id"], json_message["last_seq"])
+                        elif json_message.get("message-type") == "ack":
+                            acknowledged(json_message["seq"])
+                        elif json_message.get("message-type") == "error":
+                            print(f"App rejected a message: {json_message.get('error')}")
 
                 except websockets.exceptions.ConnectionClosed:
                     print("WebSocket connection closed, attempting to reconnect...")
@@ -157,12 +370,24 @@ async def run_websocket_client(message_queue, agent_id):
     async def process_queue():
         nonlocal ws_connection
         while True:
-            if ws_connection and message_queue:
+            if ws_connection and message_queue and session_ready[0]:
                 try:
                     message = message_queue[0]  # Peek at first message
-                    json_message = json.dumps(message)
-                    await ws_connection.send(json_message)
+                    # Every message is numbered, resent messages keep their number
+                    if "seq" not in message:
+                        message = {**message, "seq": next_seq[0]}
+                    json_message = json.dumps({**message, "protocol_version": PROTOCOL_VERSION})
+                    # Screenshots and other big messages go in binary frames
+                    if len(json_message) > MAX_FRAME_CHUNK:
+                        for frame in split_frames(json_message.encode()):
+                            await ws_connection.send(frame)
+                    else:
+                        await ws_connection.send(json_message)
                     message_queue.popleft()  # Only remove after successful send
+                    if "seq" in message:
+                        if message["seq"] == next_seq[0]:
+                            next_seq[0] += 1
+                        unacked.append((message["seq"], message))
                 except Exception as e:
                     print(f"Failed to send message: {e}")
                     ws_connection = None
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tauri::Manager;
+use tokio::sync::oneshot;
+use log::info;
+
+use crate::{storage, CONTAINERS};
+use crate::protocol::ToAgent;
+use crate::websocket::send_to_agent;
+
+//How long to wait for an agent to answer a list or to finish sending a file
+const LIST_TIMEOUT: Duration = Duration::from_secs(30);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+//Limits of a fetched file, matching MAX_FETCH_SIZE and FILE_CHUNK_SIZE in app.py.
+//Chunks are base64, so a file of MAX_FILE_SIZE bytes takes a third more characters.
+const MAX_FILE_SIZE: usize = 200 * 1024 * 1024;
+const CHUNK_SIZE: usize = 512 * 1024;
+const MAX_CHUNKS: usize = (MAX_FILE_SIZE.div_ceil(3) * 4).div_ceil(CHUNK_SIZE);
+
+//A file or directory in an agent's workspace, as reported by app.py
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AgentFile {
+    //Relative to the workspace root (the agent user's home directory)
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    //Unix timestamp
+    pub modified: f64,
+}
+
+//A file fetched from an agent and stored in the app data dir
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Artifact {
+    pub id: String,
+    pub agent_id: String,
+    //Conversation message the artifact belongs to
+    pub message_id: Option<String>,
+    //Path in the agent's workspace
+    pub path: String,
+    pub file_name: String,
+    //Where the file was saved on the host
+    pub local_path: String,
+    pub size: u64,
+    pub fetched_at: String,
+}
+
+enum FileResponse {
+    Files(Vec<AgentFile>),
+    Data(Vec<u8>),
+}
+
+//Requests waiting on an agent, by request id
+struct PendingRequest {
+    tx: oneshot::Sender<Result<FileResponse, String>>,
+    //Base64 chunks of a file transfer, in order
+    chunks: Vec<Option<String>>,
+}
+
+static PENDING: Lazy<Mutex<HashMap<String, PendingRequest>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+//Artifact index, loaded from artifacts.json on first use
+static ARTIFACTS: Lazy<Mutex<Option<Vec<Artifact>>>> = Lazy::new(|| Mutex::new(None));
+
+fn artifacts_dir(app_handle: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(app_handle.path().app_data_dir()
+        .map_err(|e| format!("Failed to get app data dir: {}", e))?
+        .join("artifacts"))
+}
+
+fn load_artifacts(app_handle: &tauri::AppHandle) -> Result<Vec<Artifact>, String> {
+    let file_path = artifacts_dir(app_handle)?.join("artifacts.json");
+    Ok(storage::read_json(&file_path, "downloaded files")?.unwrap_or_default())
+}
+
+fn save_artifacts(app_handle: &tauri::AppHandle, artifacts: &[Artifact]) -> Result<(), String> {
+    storage::write_json(&artifacts_dir(app_handle)?.join("artifacts.json"), &artifacts)
+}
+
+//Runs `f` on the artifact index, saving it afterwards when `save` is set
+fn with_artifacts<T>(app_handle: &tauri::AppHandle, save: bool, f: impl FnOnce(&mut Vec<Artifact>) -> T) -> Result<T, String> {
+    let mut artifacts = ARTIFACTS.lock().unwrap();
+    if artifacts.is_none() {
+        *artifacts = Some(load_artifacts(app_handle)?);
+    }
+    let artifacts = artifacts.as_mut().unwrap();
+    let result = f(artifacts);
+    if save {
+        save_artifacts(app_handle, artifacts)?;
+    }
+    Ok(result)
+}
+
+//Sends a request built from a fresh request id to the agent and waits for its answer
+async fn request(agent_id: &str, build: impl FnOnce(String) -> ToAgent, timeout: Duration) -> Result<FileResponse, String> {
+    let request_id = uuid::Uuid::new_v4().to_string();
+
+    let (tx, rx) = oneshot::channel();
+    PENDING.lock().unwrap().insert(request_id.clone(), PendingRequest { tx, chunks: Vec::new() });
+
+    if let Err(e) = send_to_agent(agent_id, build(request_id.clone())).await {
+        PENDING.lock().unwrap().remove(&request_id);
+        return Err(e);
+    }
+    let response = tokio::time::timeout(timeout, rx).await;
+    PENDING.lock().unwrap().remove(&request_id);
+    match response {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("Request was dropped".to_string()),
+        Err(_) => Err("Timed out waiting for the agent".to_string()),
+    }
+}
+
+fn complete(request_id: &str, result: Result<FileResponse, String>) {
+    if let Some(request) = PENDING.lock().unwrap().remove(request_id) {
+        let _ = request.tx.send(result);
+    }
+}
+
+//Reply to "list-files"
+pub fn handle_file_list(request_id: &str, files: Vec<AgentFile>) {
+    complete(request_id, Ok(FileResponse::Files(files)));
+}
+
+//Reply to "fetch-file", one piece of base64 data per chunk
+pub fn handle_file_chunk(request_id: &str, chunk: usize, total_chunks: usize, data: String) {
+    let encoded: Result<String, String> = {
+        let mut pending = PENDING.lock().unwrap();
+        let request = match pending.get_mut(request_id) {
+            Some(request) => request,
+            None => return,
+        };
+        //The chunk count is fixed by the first chunk, the agent can't grow the buffer afterwards
+        if request.chunks.is_empty() && total_chunks > 0 && total_chunks <= MAX_CHUNKS {
+            request.chunks = vec![None; total_chunks];
+        }
+        if request.chunks.len() != total_chunks || chunk >= total_chunks || data.len() > CHUNK_SIZE {
+            Err(format!("Invalid chunk {} of {}", chunk, total_chunks))
+        } else {
+            request.chunks[chunk] = Some(data);
+            if request.chunks.iter().any(Option::is_none) {
+                return;
+            }
+            Ok(request.chunks.drain(..).flatten().collect())
+        }
+    };
+    let result = encoded.and_then(|encoded| base64::decode(encoded)
+        .map(FileResponse::Data)
+        .map_err(|e| format!("Invalid file data: {}", e)));
+    complete(request_id, result);
+}
+
+pub fn handle_file_error(request_id: &str, error: String) {
+    complete(request_id, Err(error));
+}
+
+//Lists a directory of the agent's workspace
+pub async fn list_agent_files(agent_id: &str, path: Option<String>) -> Result<Vec<AgentFile>, String> {
+    let path = path.unwrap_or_default();
+    match request(agent_id, |request_id| ToAgent::ListFiles { request_id, path }, LIST_TIMEOUT).await? {
+        FileResponse::Files(files) => Ok(files),
+        FileResponse::Data(_) => Err("Unexpected response from agent".to_string()),
+    }
+}
+
+//Downloads a file from the agent's workspace into the app data dir and indexes it against a message,
+//by default the latest message of the conversation
+pub async fn fetch_agent_file(app_handle: &tauri::AppHandle, agent_id: &str, path: &str, message_id: Option<String>) -> Result<Artifact, String> {
+    let latest_message = CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.agent_id == agent_id)
+        .ok_or("Container not found")?
+        .message_ids.last().cloned();
+
+    let fetch = |request_id| ToAgent::FetchFile { request_id, path: path.to_string() };
+    let data = match request(agent_id, fetch, FETCH_TIMEOUT).await? {
+        FileResponse::Data(data) => data,
+        FileResponse::Files(_) => return Err("Unexpected response from agent".to_string()),
+    };
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let file_name = Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "file".to_string());
+    let dir = artifacts_dir(app_handle)?.join(agent_id).join(&id);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+    let local_path = dir.join(&file_name);
+    fs::write(&local_path, &data).map_err(|e| format!("Failed to save {}: {}", file_name, e))?;
+    info!("Fetched {} ({} bytes) from agent {}", path, data.len(), agent_id);
+
+    let artifact = Artifact {
+        id,
+        agent_id: agent_id.to_string(),
+        message_id: message_id.or(latest_message),
+        path: path.to_string(),
+        file_name,
+        local_path: local_path.to_string_lossy().to_string(),
+        size: data.len() as u64,
+        fetched_at: chrono::Utc::now().to_rfc3339(),
+    };
+    with_artifacts(app_handle, true, |artifacts| artifacts.push(artifact.clone()))?;
+    Ok(artifact)
+}
+
+pub fn get_artifacts(app_handle: &tauri::AppHandle, agent_id: &str, message_id: Option<String>) -> Result<Vec<Artifact>, String> {
+    with_artifacts(app_handle, false, |artifacts| {
+        artifacts.iter()
+            .filter(|a| a.agent_id == agent_id)
+            .filter(|a| message_id.is_none() || a.message_id == message_id)
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn delete_artifact(app_handle: &tauri::AppHandle, artifact_id: &str) -> Result<(), String> {
+    let removed = with_artifacts(app_handle, true, |artifacts| {
+        let index = artifacts.iter().position(|a| a.id == artifact_id)?;
+        Some(artifacts.remove(index))
+    })?.ok_or("Artifact not found")?;
+    if let Some(dir) = Path::new(&removed.local_path).parent() {
+        let _ = fs::remove_dir_all(dir);
+    }
+    Ok(())
+}
+
+//Forgets and deletes everything fetched from a deleted agent
+pub fn remove_agent_artifacts(app_handle: &tauri::AppHandle, agent_id: &str) -> Result<(), String> {
+    with_artifacts(app_handle, true, |artifacts| artifacts.retain(|a| a.agent_id != agent_id))?;
+    let dir = artifacts_dir(app_handle)?.join(agent_id);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).map_err(|e| format!("Failed to delete artifacts: {}", e))?;
+    }
+    Ok(())
+}
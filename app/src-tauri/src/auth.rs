@@ -0,0 +1,42 @@
+use once_cell::sync::Lazy;
+
+use crate::CONTAINERS;
+
+//Environment variable that hands an agent its token, read by app.py
+pub const AGENT_TOKEN_ENV: &str = "RADAH_WS_TOKEN";
+
+//Environment variables carrying secrets into agent containers. They are passed every time a container
+//is run and cleared from images committed from one, so snapshots and clones don't keep them.
+pub const SECRET_ENV: &[&str] = &["ANTHROPIC_API_KEY", AGENT_TOKEN_ENV];
+
+//Token the frontend authenticates with, new on every launch and only handed out through a Tauri command
+static CLIENT_TOKEN: Lazy<String> = Lazy::new(new_token);
+
+pub fn new_token() -> String {
+    format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+pub fn client_token() -> String {
+    CLIENT_TOKEN.clone()
+}
+
+//Compares every byte so the time taken doesn't tell how much of a token matched
+fn tokens_match(expected: &str, given: &str) -> bool {
+    !expected.is_empty()
+        && expected.len() == given.len()
+        && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub fn verify_client(token: Option<&str>) -> bool {
+    tokens_match(&CLIENT_TOKEN, token.unwrap_or_default())
+}
+
+pub fn verify_agent(agent_id: &str, token: Option<&str>) -> bool {
+    let expected = CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.agent_id == agent_id)
+        .map(|c| c.ws_token.clone());
+    match expected {
+        Some(expected) => tokens_match(&expected, token.unwrap_or_default()),
+        None => false,
+    }
+}
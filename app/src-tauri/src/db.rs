@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use serde::Serialize;
+use tauri::Manager;
+use log::{info, error};
+
+use crate::{migrations, storage, Container};
+use crate::protocol::UploadedFile;
+use crate::run_state::AgentRunState;
+use crate::scheduler::{RunOutcome, ScheduleRun};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS agents (
+    agent_id TEXT PRIMARY KEY,
+    position INTEGER NOT NULL,
+    -- Container as a versioned record (see migrations.rs), without message_ids (those come from messages.position)
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS messages (
+    message_id TEXT PRIMARY KEY,
+    agent_id TEXT,
+    -- Place in the agent's conversation, NULL once only a snapshot still references the message
+    position INTEGER,
+    -- Message JSON as a versioned record
+    data TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_by_agent ON messages (agent_id, position);
+CREATE TABLE IF NOT EXISTS attachments (
+    id TEXT PRIMARY KEY,
+    message_id TEXT NOT NULL REFERENCES messages (message_id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    mime_type TEXT,
+    size INTEGER NOT NULL,
+    data BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS attachments_by_message ON attachments (message_id);
+CREATE TABLE IF NOT EXISTS run_states (
+    agent_id TEXT PRIMARY KEY,
+    -- Last known AgentRunState of the agent, see run_state.rs
+    state TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS schedule_runs (
+    -- Also the id of the prompt in the agent's queue
+    id TEXT PRIMARY KEY,
+    schedule_id TEXT NOT NULL,
+    agent_id TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    scheduled_for TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    finished_at TEXT,
+    -- RunOutcome, see scheduler.rs
+    outcome TEXT NOT NULL,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS schedule_runs_by_agent ON schedule_runs (agent_id, started_at);
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+const BACKUP_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+static DB: Lazy<Mutex<Option<Connection>>> = Lazy::new(|| Mutex::new(None));
+
+//File uploaded with a prompt
+#[derive(Serialize, Clone, Debug)]
+pub struct Attachment {
+    pub id: String,
+    pub message_id: String,
+    pub name: String,
+    pub mime_type: Option<String>,
+    pub size: u64,
+    //data: URL, the same format the frontend uploads
+    pub data: String,
+}
+
+pub fn get_db_file(app_handle: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(app_handle.path().app_data_dir()
+        .map_err(|e| format!("Failed to get app data dir: {}", e))?
+        .join("radah.db"))
+}
+
+fn with_db<T>(f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> Result<T, String> {
+    let mut db = DB.lock().unwrap();
+    let conn = db.as_mut().ok_or("Database is not open")?;
+    f(conn).map_err(|e| format!("Database error: {}", e))
+}
+
+//Opens the database, creating the schema and importing the old JSON stores on first launch.
+//A database that fails its integrity check is replaced with the newest backup that passes,
+//any other error (e.g. no permission to open the file) is returned as is.
+pub fn init(app_handle: &tauri::AppHandle) -> Result<(), String> {
+    let file_path = get_db_file(app_handle)?;
+    if let Some(dir) = file_path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+
+    let conn = match open(&file_path)? {
+        Some(conn) => conn,
+        None => restore_backup(&file_path)?,
+    };
+    if let Err(e) = backup(&conn, &file_path) {
+        error!("Failed to back up database: {}", e);
+    }
+    *DB.lock().unwrap() = Some(conn);
+
+    import_json(app_handle)
+}
+
+//None when the database is corrupt
+fn open(file_path: &Path) -> Result<Option<Connection>, String> {
+    let conn = Connection::open(file_path).map_err(|e| format!("Failed to open database: {}", e))?;
+    let check = match conn.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0)) {
+        Ok(check) => check,
+        //A damaged header fails the check before it can report anything
+        Err(rusqlite::Error::SqliteFailure(e, message)) if matches!(e.code, ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase) => {
+            message.unwrap_or_else(|| e.to_string())
+        },
+        Err(e) => return Err(format!("Failed to read database: {}", e)),
+    };
+    if check != "ok" {
+        error!("Database {} is corrupt: {}", file_path.display(), check);
+        return Ok(None);
+    }
+    conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; PRAGMA foreign_keys = ON;")
+        .and_then(|_| conn.execute_batch(SCHEMA))
+        .map_err(|e| format!("Failed to create database schema: {}", e))?;
+    Ok(Some(conn))
+}
+
+//Empty database for tests, which have no app data dir
+#[cfg(test)]
+pub fn open_in_memory() {
+    *DB.lock().unwrap() = Some(open(Path::new(":memory:")).unwrap().unwrap());
+}
+
+//Backs the database up again every few hours, so a long session doesn't leave only the copy from its launch
+pub async fn run_backups(app_handle: tauri::AppHandle) {
+    let file_path = match get_db_file(&app_handle) {
+        Ok(file_path) => file_path,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::time::sleep(BACKUP_INTERVAL).await;
+        let result = match DB.lock().unwrap().as_ref() {
+            Some(conn) => backup(conn, &file_path),
+            None => continue,
+        };
+        match result {
+            Ok(()) => info!("Backed up database"),
+            Err(e) => error!("Failed to back up database: {}", e),
+        }
+    }
+}
+
+//Copies the database to radah.db.1, shifting older copies up
+fn backup(conn: &Connection, file_path: &Path) -> Result<(), String> {
+    let temp_path = storage::sibling(file_path, ".backup");
+    let _ = fs::remove_file(&temp_path);
+    conn.execute("VACUUM INTO ?1", params![temp_path.to_string_lossy()])
+        .map_err(|e| format!("Failed to copy database: {}", e))?;
+    storage::rotate_backups(file_path)?;
+    fs::rename(&temp_path, storage::backup_path(file_path, 1))
+        .map_err(|e| format!("Failed to save backup: {}", e))
+}
+
+//Sets the broken database aside and opens the newest backup that passes the integrity check,
+//or a new empty database if none does
+fn restore_backup(file_path: &Path) -> Result<Connection, String> {
+    let corrupt_path = storage::set_aside(file_path)?;
+    for suffix in ["-wal", "-shm"] {
+        let _ = fs::rename(storage::sibling(file_path, suffix), storage::sibling(&corrupt_path, suffix));
+    }
+
+    for index in 1..=storage::BACKUP_COUNT {
+        let backup = storage::backup_path(file_path, index);
+        let contents = match fs::read(&backup) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        storage::write_atomic(file_path, &contents)?;
+        match open(file_path) {
+            Ok(Some(conn)) => {
+                let saved_at = storage::saved_at(&backup);
+                info!("Restored database from {}", backup.display());
+                storage::notify("agents", format!(
+                    "Your agents and messages could not be read and were restored from a backup saved at {}. Changes made after that are lost. The unreadable database was kept at {}.",
+                    saved_at, corrupt_path.display()
+                ));
+                return Ok(conn);
+            },
+            Ok(None) => {
+                error!("Backup {} is corrupt too", backup.display());
+                let _ = fs::remove_file(file_path);
+            },
+            Err(e) => {
+                error!("Backup {} is unusable: {}", backup.display(), e);
+                let _ = fs::remove_file(file_path);
+            }
+        }
+    }
+
+    storage::notify("agents", format!(
+        "Your agents and messages could not be read and no usable backup was found, so the app starts without them. The unreadable database was kept at {}.",
+        corrupt_path.display()
+    ));
+    open(file_path)?.ok_or_else(|| "New database failed its integrity check".to_string())
+}
+
+//Moves containers.json and messages.json into the database once, keeping the files renamed to *.imported
+fn import_json(app_handle: &tauri::AppHandle) -> Result<(), String> {
+    let imported = with_db(|conn| {
+        conn.query_row("SELECT value FROM meta WHERE key = 'json_imported'", [], |row| row.get::<_, String>(0)).optional()
+    })?;
+    if imported.is_some() {
+        return Ok(());
+    }
+
+    let dir = app_handle.path().app_data_dir().map_err(|e| format!("Failed to get app data dir: {}", e))?;
+    let containers_file = dir.join("containers.json");
+    let messages_file = dir.join("messages.json");
+
+    //Records are decoded one by one so a single bad entry doesn't lose the whole file
+    let stored_containers: Vec<serde_json::Value> = storage::read_json(&containers_file, "agents")?.unwrap_or_default();
+    let stored_messages: HashMap<String, serde_json::Value> = storage::read_json(&messages_file, "messages")?.unwrap_or_default();
+    let mut containers = Vec::new();
+    for stored in stored_containers {
+        match migrations::decode_agent(stored.clone()) {
+            Ok(mut container) => {
+                container.message_ids = stored.get("message_ids").cloned()
+                    .and_then(|ids| serde_json::from_value(ids).ok())
+                    .unwrap_or_default();
+                containers.push(container);
+            },
+            Err(e) => storage::notify("agents", format!("An agent in containers.json could not be imported: {}", e)),
+        }
+    }
+    let mut messages = HashMap::new();
+    for (message_id, stored) in stored_messages {
+        match migrations::decode_message(stored) {
+            Ok(json) => { messages.insert(message_id, json); },
+            Err(e) => error!("Skipping unreadable message {}: {}", message_id, e),
+        }
+    }
+
+    let rows = containers.iter()
+        .map(|container| Ok((container.agent_id.as_str(), migrations::encode_agent(container)?)))
+        .collect::<Result<Vec<_>, String>>()?;
+    //Messages keep their place in the conversation, the ones only snapshots reference get none
+    let positions: HashMap<&str, (&str, i64)> = containers.iter()
+        .flat_map(|c| c.message_ids.iter().enumerate().map(move |(index, id)| (id.as_str(), (c.agent_id.as_str(), index as i64))))
+        .collect();
+    with_db(|conn| {
+        let tx = conn.transaction()?;
+        for (agent_id, data) in &rows {
+            upsert_agent(&tx, agent_id, data)?;
+        }
+        let now = chrono::Utc::now().to_rfc3339();
+        for (message_id, json) in &messages {
+            let (agent_id, position) = match positions.get(message_id.as_str()) {
+                Some((agent_id, position)) => (Some(*agent_id), Some(*position)),
+                None => (json.get("agent_id").and_then(|v| v.as_str()), None),
+            };
+            tx.execute(
+                "INSERT OR IGNORE INTO messages (message_id, agent_id, position, data, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![message_id, agent_id, position, migrations::encode_message(json), now],
+            )?;
+        }
+        tx.execute("INSERT INTO meta (key, value) VALUES ('json_imported', ?1)", params![now])?;
+        tx.commit()
+    })?;
+
+    for file in [containers_file, messages_file] {
+        if file.exists() {
+            let _ = fs::rename(&file, file.with_extension("json.imported"));
+        }
+    }
+    if !containers.is_empty() || !messages.is_empty() {
+        info!("Imported {} agents and {} messages into the database", containers.len(), messages.len());
+    }
+    Ok(())
+}
+
+pub fn load_agents() -> Result<Vec<Container>, String> {
+    let mut unreadable = 0;
+    let containers = with_db(|conn| {
+        let mut agents = conn.prepare("SELECT agent_id, data FROM agents ORDER BY position")?;
+        let mut conversation = conn.prepare("SELECT message_id FROM messages WHERE agent_id = ?1 AND position IS NOT NULL ORDER BY position")?;
+
+        let rows: Vec<(String, String)> = agents.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut containers = Vec::new();
+        for (agent_id, data) in rows {
+            let decoded = serde_json::from_str(&data).map_err(|e| e.to_string()).and_then(migrations::decode_agent);
+            let mut container = match decoded {
+                Ok(container) => container,
+                Err(e) => {
+                    error!("Skipping unreadable agent {}: {}", agent_id, e);
+                    unreadable += 1;
+                    continue;
+                }
+            };
+            container.message_ids = conversation.query_map(params![agent_id], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            containers.push(container);
+        }
+        Ok(containers)
+    })?;
+    if unreadable > 0 {
+        storage::notify("agents", format!(
+            "{} agent(s) could not be read and are hidden. They are left untouched in the database.",
+            unreadable
+        ));
+    }
+    Ok(containers)
+}
+
+pub fn load_messages() -> Result<HashMap<String, serde_json::Value>, String> {
+    let mut unreadable = 0;
+    let messages = with_db(|conn| {
+        let mut statement = conn.prepare("SELECT message_id, data FROM messages")?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut messages = HashMap::new();
+        for row in rows {
+            let (message_id, data) = row?;
+            match serde_json::from_str(&data).map_err(|e| e.to_string()).and_then(migrations::decode_message) {
+                Ok(json) => { messages.insert(message_id, json); },
+                Err(e) => {
+                    error!("Skipping unreadable message {}: {}", message_id, e);
+                    unreadable += 1;
+                },
+            }
+        }
+        Ok(messages)
+    })?;
+    if unreadable > 0 {
+        storage::notify("messages", format!(
+            "{} message(s) could not be read and are hidden. They are left untouched in the database.",
+            unreadable
+        ));
+    }
+    Ok(messages)
+}
+
+//Stores one agent, after the others when it is new. Its conversation is saved as messages are added,
+//see append_messages and set_conversation.
+pub fn save_agent(container: &Container) -> Result<(), String> {
+    let data = migrations::encode_agent(container)?;
+    with_db(|conn| upsert_agent(conn, &container.agent_id, &data))
+}
+
+fn upsert_agent(conn: &Connection, agent_id: &str, data: &str) -> rusqlite::Result<()> {
+    let updated = conn.execute("UPDATE agents SET data = ?2 WHERE agent_id = ?1", params![agent_id, data])?;
+    if updated == 0 {
+        conn.execute(
+            "INSERT INTO agents (agent_id, position, data) VALUES (?1, (SELECT COALESCE(MAX(position) + 1, 0) FROM agents), ?2)",
+            params![agent_id, data],
+        )?;
+    }
+    Ok(())
+}
+
+//Removes an agent with its run history and the messages it kept
+pub fn delete_agent(agent_id: &str, message_ids: &[String]) -> Result<(), String> {
+    with_db(|conn| {
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM agents WHERE agent_id = ?1", params![agent_id])?;
+        tx.execute("DELETE FROM run_states WHERE agent_id = ?1", params![agent_id])?;
+        tx.execute("DELETE FROM schedule_runs WHERE agent_id = ?1", params![agent_id])?;
+        delete_message_rows(&tx, message_ids)?;
+        tx.commit()
+    })
+}
+
+//Adds messages to the end of an agent's conversation
+pub fn append_messages(agent_id: &str, messages: &[(&str, &serde_json::Value)]) -> Result<(), String> {
+    with_db(|conn| {
+        let tx = conn.transaction()?;
+        {
+            let now = chrono::Utc::now().to_rfc3339();
+            let mut insert = tx.prepare(
+                "INSERT INTO messages (message_id, agent_id, position, data, created_at)
+                 VALUES (?1, ?2, (SELECT COALESCE(MAX(position) + 1, 0) FROM messages WHERE agent_id = ?2), ?3, ?4)"
+            )?;
+            for (message_id, json) in messages {
+                insert.execute(params![message_id, agent_id, migrations::encode_message(json), now])?;
+            }
+        }
+        tx.commit()
+    })
+}
+
+pub fn append_message(agent_id: &str, message_id: &str, json: &serde_json::Value) -> Result<(), String> {
+    append_messages(agent_id, &[(message_id, json)])
+}
+
+//Renumbers a conversation that was reordered or cut short. Messages left out keep their row
+//without a position while a snapshot still references them.
+pub fn set_conversation(agent_id: &str, message_ids: &[String]) -> Result<(), String> {
+    with_db(|conn| {
+        let tx = conn.transaction()?;
+        tx.execute("UPDATE messages SET position = NULL WHERE agent_id = ?1", params![agent_id])?;
+        {
+            let mut place = tx.prepare("UPDATE messages SET agent_id = ?1, position = ?2 WHERE message_id = ?3")?;
+            for (index, message_id) in message_ids.iter().enumerate() {
+                place.execute(params![agent_id, index as i64, message_id])?;
+            }
+        }
+        tx.commit()
+    })
+}
+
+pub fn delete_messages(message_ids: &[String]) -> Result<(), String> {
+    with_db(|conn| {
+        let tx = conn.transaction()?;
+        delete_message_rows(&tx, message_ids)?;
+        tx.commit()
+    })
+}
+
+fn delete_message_rows(conn: &Connection, message_ids: &[String]) -> rusqlite::Result<()> {
+    let mut delete = conn.prepare("DELETE FROM messages WHERE message_id = ?1")?;
+    for message_id in message_ids {
+        delete.execute(params![message_id])?;
+    }
+    Ok(())
+}
+
+pub fn clear_messages() -> Result<(), String> {
+    with_db(|conn| conn.execute("DELETE FROM messages", []).map(|_| ()))
+}
+
+//Deletes every agent and message
+pub fn clear_all() -> Result<(), String> {
+    with_db(|conn| {
+        conn.execute_batch("BEGIN; DELETE FROM messages; DELETE FROM agents; DELETE FROM run_states; DELETE FROM schedule_runs; COMMIT;")
+    })
+}
+
+pub fn save_run_state(agent_id: &str, state: AgentRunState) -> Result<(), String> {
+    with_db(|conn| {
+        conn.execute(
+            "INSERT INTO run_states (agent_id, state, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (agent_id) DO UPDATE SET state = excluded.state, updated_at = excluded.updated_at",
+            params![agent_id, state.to_string(), chrono::Utc::now().to_rfc3339()],
+        ).map(|_| ())
+    })
+}
+
+//None if the agent's state was never saved, or was saved by a newer version with states this one doesn't know
+pub fn load_run_state(agent_id: &str) -> Result<Option<AgentRunState>, String> {
+    let state: Option<String> = with_db(|conn| {
+        conn.query_row("SELECT state FROM run_states WHERE agent_id = ?1", params![agent_id], |row| row.get(0))
+            .optional()
+    })?;
+    Ok(state.and_then(|state| serde_json::from_value(serde_json::Value::String(state)).ok()))
+}
+
+pub fn insert_schedule_run(run: &ScheduleRun) -> Result<(), String> {
+    with_db(|conn| {
+        conn.execute(
+            "INSERT INTO schedule_runs (id, schedule_id, agent_id, prompt, scheduled_for, started_at, finished_at, outcome, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![run.id, run.schedule_id, run.agent_id, run.prompt, run.scheduled_for, run.started_at, run.finished_at, run.outcome.to_string(), run.error],
+        ).map(|_| ())
+    })
+}
+
+//Records how a pending run ended, a no-op for prompts that aren't schedule runs
+pub fn finish_schedule_run(id: &str, outcome: RunOutcome, error: Option<&str>) -> Result<(), String> {
+    with_db(|conn| {
+        conn.execute(
+            "UPDATE schedule_runs SET outcome = ?2, error = ?3, finished_at = ?4 WHERE id = ?1 AND outcome = ?5",
+            params![id, outcome.to_string(), error, chrono::Utc::now().to_rfc3339(), RunOutcome::Pending.to_string()],
+        ).map(|_| ())
+    })
+}
+
+//Fails every run still pending
+pub fn abandon_schedule_runs(error: &str) -> Result<(), String> {
+    with_db(|conn| {
+        conn.execute(
+            "UPDATE schedule_runs SET outcome = ?1, error = ?2, finished_at = ?3 WHERE outcome = ?4",
+            params![RunOutcome::Failed.to_string(), error, chrono::Utc::now().to_rfc3339(), RunOutcome::Pending.to_string()],
+        ).map(|_| ())
+    })
+}
+
+//An agent's latest runs, newest first
+pub fn load_schedule_runs(agent_id: &str, limit: usize) -> Result<Vec<ScheduleRun>, String> {
+    with_db(|conn| {
+        let mut statement = conn.prepare(
+            "SELECT id, schedule_id, prompt, scheduled_for, started_at, finished_at, outcome, error
+             FROM schedule_runs WHERE agent_id = ?1 ORDER BY started_at DESC LIMIT ?2"
+        )?;
+        let rows = statement.query_map(params![agent_id, limit as i64], |row| {
+            let outcome: String = row.get(6)?;
+            Ok(ScheduleRun {
+                id: row.get(0)?,
+                schedule_id: row.get(1)?,
+                agent_id: agent_id.to_string(),
+                prompt: row.get(2)?,
+                scheduled_for: row.get(3)?,
+                started_at: row.get(4)?,
+                finished_at: row.get(5)?,
+                //Outcomes from a newer version of the app read as failed
+                outcome: serde_json::from_value(serde_json::Value::String(outcome)).unwrap_or(RunOutcome::Failed),
+                error: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    })
+}
+
+//Stores the files sent with a prompt
+pub fn save_attachments(message_id: &str, files: &[UploadedFile]) -> Result<(), String> {
+    let mut rows = Vec::new();
+    for file in files {
+        let (header, encoded) = file.data.split_once(',').unwrap_or(("", &file.data));
+        let mime_type = header.strip_prefix("data:").and_then(|h| h.split(';').next()).filter(|m| !m.is_empty());
+        let data = base64::decode(encoded).map_err(|e| format!("Invalid data for {}: {}", file.name, e))?;
+        rows.push((uuid::Uuid::new_v4().to_string(), file.name.clone(), mime_type.map(str::to_string), data));
+    }
+
+    with_db(|conn| {
+        let tx = conn.transaction()?;
+        for (id, name, mime_type, data) in &rows {
+            tx.execute(
+                "INSERT INTO attachments (id, message_id, name, mime_type, size, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![id, message_id, name, mime_type, data.len() as i64, data],
+            )?;
+        }
+        tx.commit()
+    })
+}
+
+pub fn get_attachments(message_id: &str) -> Result<Vec<Attachment>, String> {
+    with_db(|conn| {
+        let mut statement = conn.prepare("SELECT id, name, mime_type, size, data FROM attachments WHERE message_id = ?1")?;
+        let rows = statement.query_map(params![message_id], |row| {
+            let mime_type: Option<String> = row.get(2)?;
+            let data: Vec<u8> = row.get(4)?;
+            Ok(Attachment {
+                id: row.get(0)?,
+                message_id: message_id.to_string(),
+                name: row.get(1)?,
+                size: row.get::<_, i64>(3)? as u64,
+                data: format!("data:{};base64,{}", mime_type.as_deref().unwrap_or("application/octet-stream"), base64::encode(&data)),
+                mime_type,
+            })
+        })?;
+        rows.collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_failed_integrity_check_counts_as_corruption() {
+        let dir = std::env::temp_dir().join(format!("radah-db-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let fresh = dir.join("fresh.db");
+        assert!(open(&fresh).unwrap().is_some());
+
+        let corrupt = dir.join("corrupt.db");
+        fs::write(&corrupt, vec![b'x'; 4096]).unwrap();
+        assert!(open(&corrupt).unwrap().is_none());
+
+        //A file that can't be opened is an error to report, not a reason to restore a backup
+        assert!(open(&dir.join("missing").join("radah.db")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex as AsyncMutex;
+use log::{info, error};
+
+use crate::protocol::{self, ToAgent};
+
+//Reliable delivery between the app and an agent. After init, every message either way carries a
+//per-agent sequence number ("seq") and is acknowledged ("ack") once handled. Unacknowledged messages
+//are kept by the sender and replayed when the agent reconnects with the same session id, so nothing
+//sent while the connection was down is lost. Sessions live as long as the app; after a restart the
+//agent gets a new one and sends everything it still holds again.
+
+//Bytes of messages kept for an agent that stopped acknowledging, oldest are dropped past this.
+//Messages are kept encoded, as sent, so a prompt with attachments is only held once.
+const MAX_UNACKED_BYTES: usize = 32 * 1024 * 1024;
+
+pub struct Session {
+    pub id: String,
+    //Agents without session support (images from before it) get no sequence numbers
+    reliable: bool,
+    //Whether the agent reads binary frames, see frames.rs
+    pub binary_frames: bool,
+    //Highest seq received from the agent
+    pub received: u64,
+    //Seq of the last message sent to the agent
+    sent: u64,
+    //Sent to the agent but not acknowledged, oldest first
+    unacked: VecDeque<(u64, Arc<str>)>,
+    unacked_bytes: usize,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            reliable: false,
+            binary_frames: false,
+            received: 0,
+            sent: 0,
+            unacked: VecDeque::new(),
+            unacked_bytes: 0,
+        }
+    }
+
+    //Picks up the agent's session if it still has the one we know, otherwise starts a new one.
+    //`last_seq` is the last message the agent got from us; returns what it missed, in order.
+    pub fn resume(&mut self, agent_id: &str, session_id: Option<&str>, last_seq: Option<u64>) -> Vec<(u64, Arc<str>)> {
+        if session_id != Some(self.id.as_str()) {
+            if !self.unacked.is_empty() {
+                info!("Agent {} started a new session, dropping {} unacknowledged messages", agent_id, self.unacked.len());
+            }
+            *self = Session::new();
+        }
+        self.reliable = last_seq.is_some();
+        self.ack(last_seq.unwrap_or(0));
+        self.unacked.iter().cloned().collect()
+    }
+
+    //Numbers a message for the agent and encodes it, keeping it until acknowledged.
+    //Agents without sessions get no seq and nothing is kept.
+    pub fn track(&mut self, message: &ToAgent) -> (Option<u64>, Arc<str>) {
+        if !self.reliable {
+            return (None, protocol::encode_sequenced(message, None).into());
+        }
+        self.sent += 1;
+        let encoded: Arc<str> = protocol::encode_sequenced(message, Some(self.sent)).into();
+        self.unacked_bytes += encoded.len();
+        self.unacked.push_back((self.sent, encoded.clone()));
+        //The newest message is kept even when it alone is over the limit
+        while self.unacked_bytes > MAX_UNACKED_BYTES && self.unacked.len() > 1 {
+            if let Some((seq, dropped)) = self.unacked.pop_front() {
+                self.unacked_bytes -= dropped.len();
+                error!("Dropped unacknowledged message {} to an agent", seq);
+            }
+        }
+        (Some(self.sent), encoded)
+    }
+
+    //Forgets a message that couldn't be sent and will be sent again as a new one
+    pub fn untrack(&mut self, seq: u64) {
+        if let Some(index) = self.unacked.iter().position(|(sent, _)| *sent == seq) {
+            if let Some((_, dropped)) = self.unacked.remove(index) {
+                self.unacked_bytes -= dropped.len();
+            }
+        }
+    }
+
+    //The agent handled everything up to `seq`
+    pub fn ack(&mut self, seq: u64) {
+        while self.unacked.front().map(|(sent, _)| *sent <= seq).unwrap_or(false) {
+            if let Some((_, acked)) = self.unacked.pop_front() {
+                self.unacked_bytes -= acked.len();
+            }
+        }
+    }
+
+    //Whether a message from the agent is new, false for a replay of one already handled
+    pub fn is_new(&self, seq: u64) -> bool {
+        seq > self.received
+    }
+}
+
+//Sessions by agent id. Each is locked while sending to the agent so messages go out in seq order.
+static SESSIONS: Lazy<Mutex<HashMap<String, Arc<AsyncMutex<Session>>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn session(agent_id: &str) -> Arc<AsyncMutex<Session>> {
+    SESSIONS.lock().unwrap()
+        .entry(agent_id.to_string())
+        .or_insert_with(|| Arc::new(AsyncMutex::new(Session::new())))
+        .clone()
+}
+
+//Drops the session of a deleted agent
+pub fn forget(agent_id: &str) {
+    SESSIONS.lock().unwrap().remove(agent_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch(path: String) -> ToAgent {
+        ToAgent::FetchFile { request_id: "r".to_string(), path }
+    }
+
+    #[test]
+    fn unacked_messages_are_capped_by_size() {
+        let mut session = Session::new();
+        session.resume("a", Some(&session.id.clone()), Some(0));
+        let large = "x".repeat(MAX_UNACKED_BYTES / 3);
+        for _ in 0..4 {
+            session.track(&fetch(large.clone()));
+        }
+        let kept: Vec<u64> = session.unacked.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(kept, vec![3, 4]);
+        assert!(session.unacked_bytes <= MAX_UNACKED_BYTES);
+
+        //A single message over the limit is still kept for replay
+        let (seq, _) = session.track(&fetch("x".repeat(MAX_UNACKED_BYTES + 1)));
+        assert_eq!(session.resume("a", Some(&session.id.clone()), Some(4)).len(), 1);
+        session.ack(seq.unwrap());
+        assert_eq!(session.unacked_bytes, 0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+//Binary framing for messages between the app and agents. A message's bytes are split into chunks,
+//each sent as one binary websocket frame:
+//  magic "RF" (2) | format version (1) | transfer id (16) | chunk index (u32) | chunk count (u32) | crc32 of the chunk (u32) | chunk
+//Numbers are big-endian. Unlike the JSON text chunks older agents get, chunks are raw bytes, so nothing
+//is cut in the middle of a character or escaped into a bigger message.
+//Framing only changes how a message travels: it is still one JSON document built whole in memory, with
+//file contents base64 encoded inside it, and uploads from clients still arrive as one text message.
+//Streaming file bodies as raw binary is left for a later protocol version.
+const MAGIC: &[u8; 2] = b"RF";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 2 + 1 + 16 + 4 + 4 + 4;
+
+//Chunks grow with the message so big transfers take fewer frames, but stay well under the 1MB
+//message limit of app.py's websockets client
+const MIN_CHUNK: usize = 16 * 1024;
+const MAX_CHUNK: usize = 512 * 1024;
+const TARGET_CHUNKS: usize = 32;
+
+//Most a connection may have buffered in unfinished transfers
+const MAX_BUFFERED: usize = 64 * 1024 * 1024;
+
+fn chunk_size(len: usize) -> usize {
+    (len / TARGET_CHUNKS).clamp(MIN_CHUNK, MAX_CHUNK)
+}
+
+//Frames of `data`, built one at a time so only one chunk is copied at once
+pub fn split(data: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    let transfer_id = uuid::Uuid::new_v4().into_bytes();
+    let size = chunk_size(data.len());
+    let count = data.len().div_ceil(size).max(1);
+    (0..count).map(move |index| {
+        let chunk = &data[(index * size).min(data.len())..((index + 1) * size).min(data.len())];
+        let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len());
+        frame.extend_from_slice(MAGIC);
+        frame.push(FORMAT_VERSION);
+        frame.extend_from_slice(&transfer_id);
+        frame.extend_from_slice(&(index as u32).to_be_bytes());
+        frame.extend_from_slice(&(count as u32).to_be_bytes());
+        frame.extend_from_slice(&crc32fast::hash(chunk).to_be_bytes());
+        frame.extend_from_slice(chunk);
+        frame
+    })
+}
+
+struct Transfer {
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+//Transfers being received on one connection, dropped with it
+#[derive(Default)]
+pub struct Reassembler {
+    transfers: HashMap<[u8; 16], Transfer>,
+    buffered: usize,
+}
+
+impl Reassembler {
+    //Adds a frame, returning the message once all of its chunks are in
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if frame.len() < HEADER_LEN || &frame[..2] != MAGIC {
+            return Err("Not a message frame".to_string());
+        }
+        if frame[2] != FORMAT_VERSION {
+            return Err(format!("Unsupported frame format {}", frame[2]));
+        }
+        let transfer_id: [u8; 16] = frame[3..19].try_into().unwrap();
+        let index = u32::from_be_bytes(frame[19..23].try_into().unwrap()) as usize;
+        let count = u32::from_be_bytes(frame[23..27].try_into().unwrap()) as usize;
+        let crc = u32::from_be_bytes(frame[27..31].try_into().unwrap());
+        let chunk = &frame[HEADER_LEN..];
+
+        if count == 0 || index >= count || count > MAX_BUFFERED / MIN_CHUNK {
+            return Err(format!("Invalid chunk {} of {}", index, count));
+        }
+        // A corrupted chunk spoils the whole message
+        if crc32fast::hash(chunk) != crc {
+            self.drop_transfer(&transfer_id);
+            return Err(format!("Checksum mismatch in chunk {} of {}", index, count));
+        }
+        if self.buffered + chunk.len() > MAX_BUFFERED {
+            self.drop_transfer(&transfer_id);
+            return Err("Message is too large".to_string());
+        }
+
+        let transfer = self.transfers.entry(transfer_id).or_insert_with(|| Transfer {
+            chunks: vec![None; count],
+            missing: count,
+        });
+        if transfer.chunks.len() != count {
+            self.drop_transfer(&transfer_id);
+            return Err("Chunk count changed during a transfer".to_string());
+        }
+        if transfer.chunks[index].is_none() {
+            transfer.chunks[index] = Some(chunk.to_vec());
+            transfer.missing -= 1;
+            self.buffered += chunk.len();
+        }
+        if transfer.missing > 0 {
+            return Ok(None);
+        }
+
+        let transfer = self.transfers.remove(&transfer_id).unwrap();
+        let data: Vec<u8> = transfer.chunks.into_iter().flatten().flatten().collect();
+        self.buffered -= data.len();
+        Ok(Some(data))
+    }
+
+    fn drop_transfer(&mut self, transfer_id: &[u8; 16]) {
+        if let Some(transfer) = self.transfers.remove(transfer_id) {
+            self.buffered -= transfer.chunks.iter().flatten().map(Vec::len).sum::<usize>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn split_frames_reassemble_to_the_message() {
+        for len in [0, 1, MIN_CHUNK, MIN_CHUNK * TARGET_CHUNKS + 7] {
+            let data = message(len);
+            let mut reassembler = Reassembler::default();
+            let mut result = None;
+            for frame in split(&data) {
+                assert!(result.is_none());
+                result = reassembler.push(&frame).unwrap();
+            }
+            assert_eq!(result, Some(data));
+            assert_eq!(reassembler.buffered, 0);
+        }
+    }
+
+    #[test]
+    fn frames_may_arrive_out_of_order_and_twice() {
+        let data = message(MIN_CHUNK * 3 + 5);
+        let frames: Vec<Vec<u8>> = split(&data).collect();
+        assert_eq!(frames.len(), 4);
+
+        let mut reassembler = Reassembler::default();
+        for index in [2, 0, 2, 3, 0] {
+            assert_eq!(reassembler.push(&frames[index]).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(&frames[1]).unwrap(), Some(data));
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn a_corrupted_chunk_drops_the_transfer() {
+        let data = message(MIN_CHUNK * 2);
+        let mut frames: Vec<Vec<u8>> = split(&data).collect();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&frames[0]).unwrap(), None);
+
+        let last = frames[1].len() - 1;
+        frames[1][last] ^= 0xff;
+        assert!(reassembler.push(&frames[1]).unwrap_err().contains("Checksum"));
+        assert!(reassembler.transfers.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn frames_with_a_bad_header_are_rejected() {
+        let mut frame = split(b"hello").next().unwrap();
+        assert!(Reassembler::default().push(&frame[..HEADER_LEN - 1]).is_err());
+        frame[2] = FORMAT_VERSION + 1;
+        assert!(Reassembler::default().push(&frame).is_err());
+    }
+}
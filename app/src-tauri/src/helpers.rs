@@ -1,10 +1,6 @@
 use std::process::Command;
-use std::fs;
-use std::path::PathBuf;
-use tauri::Runtime;
 use serde_json;
-use crate::{Container, CONTAINERS, MESSAGES};
-use tauri::Manager; // <-- Add this line
+use crate::{db, Container, ContainerStatus, CONTAINERS, MESSAGES};
 
 
 
@@ -44,88 +40,24 @@ pub fn is_port_in_use(port: u16) -> bool {
     !output.stdout.is_empty()
 }
 
-pub fn get_containers_file<R: Runtime>(app: &tauri::AppHandle<R>) -> PathBuf {
-    app.path().app_data_dir()
-        .expect("Failed to get app data dir")
-        .join("containers.json")
+// Persistence goes through the SQLite store in db.rs, which writes only the agent that changed
+pub fn save_container(container: &Container) -> Result<(), String> {
+    db::save_agent(container)
 }
 
-pub fn save_messages<R: Runtime>(app: &tauri::AppHandle<R>, messages: &std::collections::HashMap<String, serde_json::Value>) -> Result<(), String> {
-    let file_path = app.path().app_data_dir()
-        .expect("Failed to get app data dir")
-        .join("messages.json");
-    
-    if let Some(dir) = file_path.parent() {
-        fs::create_dir_all(dir)
-            .map_err(|e| format!("Failed to create directory: {}", e))?;
-    }
-
-    fs::write(
-        &file_path,
-        serde_json::to_string_pretty(messages)
-            .map_err(|e| format!("Failed to serialize messages: {}", e))?
-    )
-    .map_err(|e| format!("Failed to write messages file: {}", e))?;
-
-    Ok(())
-}
-
-pub fn save_containers<R: Runtime>(app: &tauri::AppHandle<R>, containers: &[Container]) -> Result<(), String> {
-    let file_path = get_containers_file(app);
-    
-    if let Some(dir) = file_path.parent() {
-        fs::create_dir_all(dir)
-            .map_err(|e| format!("Failed to create directory: {}", e))?;
-    }
-
-    fs::write(
-        &file_path,
-        serde_json::to_string_pretty(containers)
-            .map_err(|e| format!("Failed to serialize containers: {}", e))?
-    )
-    .map_err(|e| format!("Failed to write containers file: {}", e))?;
-
-    Ok(())
-}
-
-pub fn load_containers<R: Runtime>(app: &tauri::AppHandle<R>) -> Result<Vec<Container>, String> {
-    let file_path = get_containers_file(app);
-    
-    if !file_path.exists() {
-        return Ok(Vec::new());
-    }
-
-    let contents = fs::read_to_string(file_path)
-        .map_err(|e| format!("Failed to read containers file: {}", e))?;
-
-    serde_json::from_str(&contents)
-        .map_err(|e| format!("Failed to parse containers file: {}", e))
-}
-
-pub fn get_messages_file<R: Runtime>(app: &tauri::AppHandle<R>) -> PathBuf {
-    app.path().app_data_dir()
-        .expect("Failed to get app data dir")
-        .join("messages.json")
+pub fn load_containers() -> Result<Vec<Container>, String> {
+    db::load_agents()
 }
 
-pub fn load_messages<R: Runtime>(app: &tauri::AppHandle<R>) -> Result<std::collections::HashMap<String, serde_json::Value>, String> {
-    let file_path = get_messages_file(app);
-    
-    if !file_path.exists() {
-        return Ok(std::collections::HashMap::new());
-    }
-
-    let contents = fs::read_to_string(file_path)
-        .map_err(|e| format!("Failed to read messages file: {}", e))?;
-
-    serde_json::from_str(&contents)
-        .map_err(|e| format!("Failed to parse messages file: {}", e))
+pub fn load_messages() -> Result<std::collections::HashMap<String, serde_json::Value>, String> {
+    db::load_messages()
 }
 
+// Starts the agents that were running when the app closed; stopped and paused agents stay as they were
 pub async fn start_all_containers(containers: Vec<Container>) {
     let mut handles = Vec::new();
 
-    for container in containers {
+    for container in containers.into_iter().filter(|c| c.status == ContainerStatus::Running) {
         let container_id = container.id.clone();
         println!("Starting container: {}", container_id);
         
@@ -190,3 +122,35 @@ pub fn get_recent_agent_messages(agent_id: String, n: usize) -> Vec<serde_json::
     }
     result.into_iter().rev().collect()
 }
+
+// Duplicates messages under new ids at the end of another agent's conversation, so deleting one agent's history
+// leaves the other's intact
+pub fn copy_messages(message_ids: &[String], agent_id: &str) -> Result<Vec<String>, String> {
+    let mut messages = MESSAGES.lock().unwrap();
+    let mut copied = Vec::new();
+
+    for message_id in message_ids {
+        if let Some(json_value) = messages.get(message_id) {
+            let mut json_value = json_value.clone();
+            let new_id = uuid::Uuid::new_v4().to_string();
+            if let serde_json::Value::Object(ref mut map) = json_value {
+                if map.contains_key("agent_id") {
+                    map.insert("agent_id".to_string(), serde_json::Value::String(agent_id.to_string()));
+                }
+                if map.contains_key("message_id") {
+                    map.insert("message_id".to_string(), serde_json::Value::String(new_id.clone()));
+                }
+            }
+            copied.push((new_id, json_value));
+        }
+    }
+
+    let rows: Vec<(&str, &serde_json::Value)> = copied.iter().map(|(id, json)| (id.as_str(), json)).collect();
+    db::append_messages(agent_id, &rows)?;
+    Ok(copied.into_iter()
+        .map(|(new_id, json_value)| {
+            messages.insert(new_id.clone(), json_value);
+            new_id
+        })
+        .collect())
+}
@@ -0,0 +1,96 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use tauri::Manager;
+use log::{info, error};
+
+use crate::runtime::runtime;
+
+pub const AGENT_IMAGE_REPO: &str = "localhost/minimal-vnc-desktop";
+
+//Bundled files the Dockerfile COPYs; a change to any of them produces a new image tag
+const IMAGE_INPUTS: &[&str] = &["Dockerfile", "startup.sh", "novnc_startup.sh", "supervisord.conf", "image"];
+
+//Tag for the bundled resources, computed once per launch
+static CURRENT_TAG: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+//Serializes builds so concurrent agent creations don't build the same image twice
+static BUILD_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if path.is_dir() {
+        let entries = fs::read_dir(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == "__pycache__" || name.ends_with(".pyc") || name == ".DS_Store" {
+                continue;
+            }
+            collect_files(&entry.path(), files)?;
+        }
+    } else if path.is_file() {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+//Sha256 over the relative path and contents of every image input, in a stable order
+pub fn compute_image_hash(resource_dir: &Path) -> Result<String, String> {
+    let mut files = Vec::new();
+    for input in IMAGE_INPUTS {
+        collect_files(&resource_dir.join(input), &mut files)?;
+    }
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        let relative = file.strip_prefix(resource_dir).unwrap_or(&file);
+        let contents = fs::read(&file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        hasher.update(relative.to_string_lossy().replace('\\', "/").as_bytes());
+        hasher.update([0u8]);
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+    Ok(hex::encode(hasher.finalize())[..12].to_string())
+}
+
+//Image tag matching the bundled resources, e.g. localhost/minimal-vnc-desktop:3f2a9c01be47
+pub fn current_image_tag(app_handle: &tauri::AppHandle) -> Result<String, String> {
+    let mut current = CURRENT_TAG.lock().unwrap();
+    if let Some(tag) = current.as_ref() {
+        return Ok(tag.clone());
+    }
+    let resource_dir = app_handle.path().resource_dir().map_err(|e| format!("Failed to get resource dir: {}", e))?;
+    let tag = format!("{}:{}", AGENT_IMAGE_REPO, compute_image_hash(&resource_dir)?);
+    *current = Some(tag.clone());
+    Ok(tag)
+}
+
+//Returns the current image tag, building it only if the engine doesn't have it yet
+pub async fn ensure_agent_image(app_handle: &tauri::AppHandle) -> Result<String, String> {
+    let tag = current_image_tag(app_handle)?;
+    let runtime = runtime()?;
+
+    if runtime.image_exists(&tag).await? {
+        return Ok(tag);
+    }
+
+    let _guard = BUILD_LOCK.lock().await;
+    if runtime.image_exists(&tag).await? {
+        return Ok(tag);
+    }
+
+    info!("Building agent image {}", tag);
+    let dockerfile_path = app_handle.path().resource_dir()
+        .map_err(|e| format!("Failed to get resource dir: {}", e))?
+        .join("Dockerfile");
+    if let Err(error_message) = runtime.build_image(&tag, &dockerfile_path).await {
+        error!("Failed to build image: {}", error_message);
+        return Err(error_message);
+    }
+    info!("Built agent image {}", tag);
+    Ok(tag)
+}
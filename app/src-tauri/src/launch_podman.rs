@@ -1,33 +1,245 @@
-use std::process::Command;
+use std::path::Path;
+#[cfg(not(target_os = "linux"))]
+use tokio::process::Command;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use serde::Serialize;
 use log::{info, error};
 
+use crate::runtime::find_binary;
+
 //global string variable for podman name
+#[cfg(not(target_os = "linux"))]
 pub static PODMAN_NAME: &str = "radah-podman-machine-name";
 
+//Result of the last podman setup, exposed to the frontend
+static PODMAN_DIAGNOSIS: Lazy<Mutex<Option<PodmanDiagnosis>>> = Lazy::new(|| Mutex::new(None));
+
+//Structured report of what podman setup found, so the UI can explain what is missing
+#[derive(Serialize, Clone, Debug)]
+pub struct PodmanDiagnosis {
+    pub platform: String,
+    pub podman_path: Option<String>,
+    pub version: Option<String>,
+    pub rootless: Option<bool>,
+    pub uses_machine: bool,
+    pub checks: Vec<DiagnosisCheck>,
+    pub ok: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DiagnosisCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl PodmanDiagnosis {
+    fn new(uses_machine: bool) -> Self {
+        PodmanDiagnosis {
+            platform: std::env::consts::OS.to_string(),
+            podman_path: None,
+            version: None,
+            rootless: None,
+            uses_machine,
+            checks: Vec::new(),
+            ok: true,
+        }
+    }
+
+    fn check(&mut self, name: &str, passed: bool, detail: String, hint: Option<&str>) {
+        if passed {
+            info!("[podman check] {}: {}", name, detail);
+        } else {
+            error!("[podman check] {} failed: {}", name, detail);
+            self.ok = false;
+        }
+        self.checks.push(DiagnosisCheck {
+            name: name.to_string(),
+            passed,
+            detail,
+            hint: hint.map(String::from),
+        });
+    }
+
+    //One line summary of the failed checks
+    fn failure_summary(&self) -> String {
+        self.checks.iter()
+            .filter(|c| !c.passed)
+            .map(|c| format!("{}: {}", c.name, c.detail))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+pub fn get_podman_diagnosis() -> Option<PodmanDiagnosis> {
+    PODMAN_DIAGNOSIS.lock().unwrap().clone()
+}
+
+fn store_diagnosis(diagnosis: PodmanDiagnosis) -> Result<(), String> {
+    let result = if diagnosis.ok { Ok(()) } else { Err(diagnosis.failure_summary()) };
+    *PODMAN_DIAGNOSIS.lock().unwrap() = Some(diagnosis);
+    result
+}
+
 // Add this function to check for podman
+#[cfg(not(target_os = "linux"))]
 pub async fn podman_setup() -> Result<(), String> {
+    let mut diagnosis = PodmanDiagnosis::new(true);
     info!("Checking if podman is installed...");
-    let output = Command::new("/opt/homebrew/bin/podman")
-        .args(&["--version"])
-        .output();
-    
-    match output {
-        Ok(_output) => {
-            info!("Podman is already installed, proceeding with configuration");
-            configure_podman().await
+    let podman = match find_binary("podman") {
+        Some(podman) => {
+            info!("Podman is already installed at {}, proceeding with configuration", podman.display());
+            podman
         },
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                info!("Podman not found, starting installation process");
-                install_podman().await?;
-                configure_podman().await
-            } else {
-                let err_msg = format!("Error checking podman: {}", e);
-                error!("{}", err_msg);
-                Err(err_msg)
+        None => {
+            info!("Podman not found, starting installation process");
+            if let Err(e) = install_podman().await {
+                diagnosis.check("installed", false, e, Some("Install podman from https://podman.io and restart Radah"));
+                return store_diagnosis(diagnosis);
+            }
+            match find_binary("podman") {
+                Some(podman) => podman,
+                None => {
+                    diagnosis.check("installed", false, "Podman was installed but could not be found".to_string(), None);
+                    return store_diagnosis(diagnosis);
+                }
             }
         }
+    };
+    diagnosis.podman_path = Some(podman.display().to_string());
+    diagnosis.check("installed", true, podman.display().to_string(), None);
+
+    match configure_podman(&podman).await {
+        Ok(()) => diagnosis.check("machine", true, format!("{} is running", PODMAN_NAME), None),
+        Err(e) => diagnosis.check("machine", false, e, Some("Try `podman machine start` from a terminal")),
     }
+    store_diagnosis(diagnosis)
+}
+
+//Linux runs containers natively, so instead of a machine VM we verify rootless podman is usable
+#[cfg(target_os = "linux")]
+pub async fn podman_setup() -> Result<(), String> {
+    info!("Checking podman setup for Linux");
+    let mut diagnosis = PodmanDiagnosis::new(false);
+
+    let podman = match find_binary("podman") {
+        Some(podman) => podman,
+        None => {
+            diagnosis.check(
+                "installed",
+                false,
+                "podman was not found on PATH".to_string(),
+                Some("Install podman with your package manager, e.g. `sudo apt install podman` or `sudo dnf install podman`"),
+            );
+            return store_diagnosis(diagnosis);
+        }
+    };
+    diagnosis.podman_path = Some(podman.display().to_string());
+    diagnosis.check("installed", true, podman.display().to_string(), None);
+
+    let info = match podman_info(&podman).await {
+        Ok(info) => info,
+        Err(e) => {
+            diagnosis.check("podman info", false, e, Some("Run `podman info` in a terminal to see the full error"));
+            return store_diagnosis(diagnosis);
+        }
+    };
+    diagnosis.version = info["version"]["Version"].as_str().map(String::from);
+    diagnosis.check("podman info", true, format!("podman {}", diagnosis.version.as_deref().unwrap_or("unknown version")), None);
+
+    let rootless = info["host"]["security"]["rootless"].as_bool().unwrap_or_else(|| current_uid() != Some(0));
+    diagnosis.rootless = Some(rootless);
+    if rootless {
+        check_user_namespaces(&mut diagnosis);
+        check_subids(&mut diagnosis);
+        let newuidmap = find_binary("newuidmap");
+        diagnosis.check(
+            "newuidmap",
+            newuidmap.is_some(),
+            newuidmap.map(|p| p.display().to_string()).unwrap_or_else(|| "newuidmap was not found".to_string()),
+            Some("Install the uidmap (Debian/Ubuntu) or shadow-utils (Fedora) package"),
+        );
+    }
+
+    store_diagnosis(diagnosis)
+}
+
+#[cfg(target_os = "linux")]
+async fn podman_info(podman: &Path) -> Result<serde_json::Value, String> {
+    let output = tokio::process::Command::new(podman)
+        .args(["info", "--format", "json"])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run podman info: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("Failed to parse podman info output: {}", e))
+}
+
+#[cfg(target_os = "linux")]
+fn current_uid() -> Option<u32> {
+    std::fs::read_to_string("/proc/self/status").ok()?
+        .lines()
+        .find(|line| line.starts_with("Uid:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+#[cfg(target_os = "linux")]
+fn check_user_namespaces(diagnosis: &mut PodmanDiagnosis) {
+    let max = std::fs::read_to_string("/proc/sys/user/max_user_namespaces")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    match max {
+        Some(0) => diagnosis.check(
+            "user namespaces",
+            false,
+            "user.max_user_namespaces is 0".to_string(),
+            Some("Enable them with `sudo sysctl -w user.max_user_namespaces=15000`"),
+        ),
+        Some(max) => diagnosis.check("user namespaces", true, format!("max_user_namespaces = {}", max), None),
+        None => diagnosis.check("user namespaces", true, "could not read max_user_namespaces, assuming enabled".to_string(), None),
+    }
+}
+
+//Rootless podman needs a subordinate id range for the user in /etc/subuid and /etc/subgid
+#[cfg(target_os = "linux")]
+fn check_subids(diagnosis: &mut PodmanDiagnosis) {
+    let user = std::env::var("USER").unwrap_or_default();
+    let uid = current_uid().map(|u| u.to_string()).unwrap_or_default();
+
+    for file in ["/etc/subuid", "/etc/subgid"] {
+        let count = std::fs::read_to_string(file)
+            .map(|contents| subid_count(&contents, &user, &uid))
+            .unwrap_or(0);
+        let hint = format!("Add a range with `sudo usermod --add-subuids 100000-165535 --add-subgids 100000-165535 {}`", user);
+        diagnosis.check(
+            file,
+            count >= 65536,
+            format!("{} subordinate ids for {}", count, user),
+            Some(&hint),
+        );
+    }
+}
+
+//Sums the ranges in a subuid/subgid file that belong to the user (matched by name or uid)
+#[cfg(target_os = "linux")]
+fn subid_count(contents: &str, user: &str, uid: &str) -> u64 {
+    contents.lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().split(':');
+            let owner = parts.next()?;
+            let _start = parts.next()?;
+            let count = parts.next()?.parse::<u64>().ok()?;
+            (!owner.is_empty() && (owner == user || owner == uid)).then_some(count)
+        })
+        .sum()
 }
 
 
@@ -73,6 +285,7 @@ async fn install_podman() -> Result<(), String> {
     let install_output = Command::new("/opt/homebrew/bin/brew")
         .args(&["install", "podman"])
         .output()
+        .await
         .map_err(|e| {
             let err_msg = format!("Failed to install podman: {}", e);
             error!("{}", err_msg);
@@ -92,12 +305,14 @@ async fn install_podman() -> Result<(), String> {
 
 
 
-async fn configure_podman() -> Result<(), String> {
+#[cfg(not(target_os = "linux"))]
+async fn configure_podman(podman: &Path) -> Result<(), String> {
     info!("Starting podman configuration");
     
-    let machine_exists = Command::new("/opt/homebrew/bin/podman")
+    let machine_exists = Command::new(podman)
         .args(&["machine", "inspect", PODMAN_NAME])
         .output()
+        .await
         .map_err(|e| {
             let err_msg = format!("Failed to check if podman machine exists: {}", e);
             error!("{}", err_msg);
@@ -107,9 +322,10 @@ async fn configure_podman() -> Result<(), String> {
     info!("Machine exists status: {}", machine_exists.status);
     if !machine_exists.status.success() {
         info!("Initializing new podman machine: {}", PODMAN_NAME);
-        let init_output = Command::new("/opt/homebrew/bin/podman")
+        let init_output = Command::new(podman)
             .args(&["machine", "init", PODMAN_NAME])
             .output()
+            .await
             .map_err(|e| {
                 let err_msg = format!("Failed to initialize podman machine: {}", e);
                 error!("{}", err_msg);
@@ -126,9 +342,10 @@ async fn configure_podman() -> Result<(), String> {
     }
 
     info!("Checking podman machine state");
-    let inspect_output = Command::new("/opt/homebrew/bin/podman")
+    let inspect_output = Command::new(podman)
         .args(&["machine", "inspect", PODMAN_NAME])
         .output()
+        .await
         .map_err(|e| {
             let err_msg = format!("Failed to inspect podman machine: {}", e);
             error!("{}", err_msg);
@@ -148,9 +365,10 @@ async fn configure_podman() -> Result<(), String> {
             info!("Current podman machine state: {}", state);
             if state != "running" {
                 info!("Starting podman machine: {}", PODMAN_NAME);
-                let start_output = Command::new("/opt/homebrew/bin/podman")
+                let start_output = Command::new(podman)
                     .args(&["machine", "start", PODMAN_NAME])
                     .output()
+                    .await
                     .map_err(|e| {
                         let err_msg = format!("Failed to start podman machine: {}", e);
                         error!("{}", err_msg);
@@ -172,6 +390,12 @@ async fn configure_podman() -> Result<(), String> {
 }
 
 
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+async fn install_podman() -> Result<(), String> {
+    Err("Automatic podman installation is only supported on macOS".to_string())
+}
+
+
 #[cfg(target_os = "macos")]
 async fn install_homebrew() -> Result<(), String> {
     info!("Starting Homebrew installation");
@@ -182,6 +406,7 @@ async fn install_homebrew() -> Result<(), String> {
         .arg("-c")
         .arg(install_cmd)
         .output()
+        .await
         .map_err(|e| {
             let err_msg = format!("Failed to execute Homebrew installation: {}", e);
             error!("{}", err_msg);
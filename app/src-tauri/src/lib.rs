@@ -1,28 +1,77 @@
-use std::process::Command;
 use std::env;
+use std::future::Future;
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use serde::{Serialize, Deserialize};
 use tauri::Manager;
 use dotenv::dotenv;
 use tauri::utils::assets::{resource_relpath, EmbeddedAssets};
-use std::path::PathBuf;
 use tauri::Wry;
 use tauri::Runtime;
 use tauri::Emitter;
 
+use futures::StreamExt;
 use log::{info, error};
 
 //file imports
 mod launch_podman;
-use launch_podman::podman_setup;
+use launch_podman::{podman_setup, PodmanDiagnosis};
 
 mod websocket;
 pub use websocket::{ start_websocket_server, AGENT_CONNECTIONS, ConnectionInfo };
 
 
+mod runtime;
+pub use runtime::{ContainerRuntime, RunSpec, MountSpec, RuntimeKind, ResourceLimits, ResourceUsage, runtime, init_runtime, set_runtime};
+
+mod podman_api;
+
+mod image;
+use image::ensure_agent_image;
+
+mod lifecycle;
+pub use lifecycle::ContainerStatus;
+
+mod supervisor;
+use supervisor::HealthReport;
+
+mod logs;
+use logs::LogSource;
+
+mod snapshot;
+use snapshot::Snapshot;
+
+mod volumes;
+use volumes::Mount;
+
+mod artifacts;
+use artifacts::{AgentFile, Artifact};
+
 mod helpers;
-pub use helpers::{save_messages, get_containers_file, save_containers, get_recent_agent_messages, get_available_ports, is_port_in_use, start_all_containers, get_messages_file, load_messages, load_containers};
+pub use helpers::{save_container, get_recent_agent_messages, get_available_ports, is_port_in_use, start_all_containers, load_messages, load_containers};
+
+mod db;
+use db::Attachment;
+
+mod storage;
+use storage::StorageNotice;
+
+mod migrations;
+
+mod protocol;
+use protocol::{ChatMessage, QueuedPrompt};
+
+mod auth;
+
+mod delivery;
+
+mod frames;
+
+mod run_state;
+use run_state::AgentRunState;
+
+mod scheduler;
+use scheduler::{Schedule, ScheduleRun};
 
 // Long Term Storage
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -49,9 +98,56 @@ pub struct Container {
     pub number: i32,
     pub agent_type: String,
     pub agent_name: String,
+    //Not part of the stored agent record, filled in from the messages table on load
+    #[serde(default)]
     pub message_ids: Vec<String>,
     pub agent_id: String,
     pub system_prompt: String,
+    //Image the container was created from, empty for agents created before images were versioned
+    #[serde(default)]
+    pub image: String,
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+    #[serde(default)]
+    pub status: ContainerStatus,
+    //Stop the container after this many minutes without messages, None keeps it running
+    #[serde(default)]
+    pub idle_timeout_minutes: Option<u32>,
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    //Secret the agent authenticates to the websocket server with, passed in its environment
+    pub ws_token: String,
+    //Configured websocket port when the agent was created, passed in its environment. The agent searches
+    //up from it for the port the server is on, which differs when the configured one was taken.
+    pub ws_port: u16,
+    //Prompts sent to the agent on a schedule, see scheduler.rs
+    pub schedules: Vec<Schedule>,
+}
+
+//Agent record for tests, with no mounts, schedules or snapshots
+#[cfg(test)]
+pub fn test_agent(agent_id: &str) -> Container {
+    Container {
+        id: String::new(),
+        vnc_port: 6080,
+        number: 1,
+        agent_type: "claude".to_string(),
+        agent_name: "Test agent".to_string(),
+        message_ids: Vec::new(),
+        agent_id: agent_id.to_string(),
+        system_prompt: String::new(),
+        image: "radah-agent".to_string(),
+        resource_limits: ResourceLimits::agent_default(),
+        status: ContainerStatus::Running,
+        idle_timeout_minutes: None,
+        snapshots: Vec::new(),
+        mounts: Vec::new(),
+        ws_token: "test-token".to_string(),
+        ws_port: websocket::DEFAULT_WS_PORT,
+        schedules: Vec::new(),
+    }
 }
 
 //Containers
@@ -59,6 +155,10 @@ pub static CONTAINERS: Lazy<Mutex<Vec<Container>>> = Lazy::new(|| {
     Mutex::new(Vec::new())
 });
 
+//Held by tests that use the global database, runtime or containers, so they don't run at the same time
+#[cfg(test)]
+pub static TEST_GLOBALS: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
 // Add this near your other static variables
 static APP_HANDLE: Lazy<Mutex<Option<tauri::AppHandle>>> = Lazy::new(|| Mutex::new(None));
 
@@ -78,14 +178,124 @@ pub fn get_app_handle() -> Option<tauri::AppHandle> {
 
 
 #[tauri::command]
-async fn get_prompt_running(agent_id: String) -> String {
-    AGENT_CONNECTIONS.lock().await.get(&agent_id).map(|conn| conn.prompt_running.clone()).unwrap_or("na".to_string())
+async fn get_prompt_running(agent_id: String) -> AgentRunState {
+    run_state::get(&agent_id)
+}
+
+//Prompts waiting for the agent, next first
+#[tauri::command]
+async fn get_prompt_queue(agent_id: String) -> Vec<QueuedPrompt> {
+    websocket::prompt_queue(&agent_id).await
+}
+
+#[tauri::command]
+async fn cancel_queued_prompt(agent_id: String, prompt_id: String) -> Result<Vec<QueuedPrompt>, String> {
+    websocket::cancel_queued_prompt(&agent_id, &prompt_id).await
 }
 
-fn get_resource_path(app_handle: &tauri::AppHandle, resource: &str) -> PathBuf {
-    app_handle.path().resource_dir()
-        .expect("Failed to get resource dir")
-        .join(resource)
+#[tauri::command]
+async fn move_queued_prompt(agent_id: String, prompt_id: String, position: usize) -> Result<Vec<QueuedPrompt>, String> {
+    websocket::move_queued_prompt(&agent_id, &prompt_id, position).await
+}
+
+#[tauri::command]
+fn get_podman_diagnosis() -> Option<PodmanDiagnosis> {
+    launch_podman::get_podman_diagnosis()
+}
+
+// Builds the run spec for an agent container from its metadata
+fn agent_run_spec(runtime: &dyn ContainerRuntime, container: &Container, vnc_host_port: u16, api_key: String) -> RunSpec {
+    RunSpec {
+        name: format!("agent-{}", container.agent_id),
+        image: container.image.clone(),
+        env: vec![
+            ("DISPLAY".to_string(), ":0".to_string()),
+            ("CONTAINER_ID".to_string(), container.agent_id.clone()),
+            ("ANTHROPIC_API_KEY".to_string(), api_key),
+            ("GEOMETRY".to_string(), "1920x1080".to_string()),
+            ("HOST_IP".to_string(), runtime.host_gateway().to_string()),
+            (auth::AGENT_TOKEN_ENV.to_string(), container.ws_token.clone()),
+            (websocket::WS_PORT_ENV.to_string(), container.ws_port.to_string()),
+            (websocket::PORT_RANGE_ENV.to_string(), websocket::PORT_SEARCH_RANGE.to_string()),
+        ],
+        ports: vec![(vnc_host_port, 5900), (container.vnc_port, 6080)],
+        limits: container.resource_limits.clone(),
+        mounts: container.mounts.iter().map(|m| m.spec(&container.agent_id)).collect(),
+    }
+}
+
+// Creates the agent's volumes and runs its container, returning the container id
+async fn run_agent_container(runtime: &dyn ContainerRuntime, container: &Container, vnc_host_port: u16) -> Result<String, String> {
+    let api_key = env::var("ANTHROPIC_API_KEY").map_err(|_| "ANTHROPIC_API_KEY is not set".to_string())?;
+    let spec = agent_run_spec(runtime, container, vnc_host_port, api_key);
+    volumes::create_agent_volumes(runtime, container).await?;
+    let id = runtime.run_container(&spec).await?;
+
+    let mut started = container.clone();
+    started.id = id.clone();
+    volumes::chown_agent_volumes(runtime, &started).await;
+    Ok(id)
+}
+
+// Swaps the agent's container for one run from `container`, returning the new container's id.
+// The old container is stopped and set aside until the new one runs, and put back if it doesn't.
+async fn replace_agent_container(runtime: &dyn ContainerRuntime, container: &Container, vnc_host_port: u16) -> Result<String, String> {
+    replace_agent_container_with(runtime, &container.agent_id, || run_agent_container(runtime, container, vnc_host_port)).await
+}
+//Sets the agent's container aside while `create` makes its replacement under the same name, and puts it back if that fails
+async fn replace_agent_container_with<T, F, Fut>(runtime: &dyn ContainerRuntime, agent_id: &str, create: F) -> Result<T, String>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, String>>,
+{
+    let container_name = format!("agent-{}", agent_id);
+    let Some(old) = runtime.inspect_container(&container_name).await? else {
+        return create().await;
+    };
+    let aside_name = format!("{}-replaced", container_name);
+    // Left behind by a replacement that was interrupted
+    runtime.remove_container(&aside_name).await?;
+
+    // Stopping frees the ports the new container publishes
+    runtime.stop_container(&old.id).await?;
+    if let Err(e) = runtime.rename_container(&old.id, &aside_name).await {
+        restore_container_state(runtime, &old).await;
+        return Err(e);
+    }
+
+    match create().await {
+        Ok(created) => {
+            if let Err(e) = runtime.remove_container(&old.id).await {
+                error!("Failed to remove replaced container {}: {}", old.id, e);
+            }
+            Ok(created)
+        },
+        Err(e) => {
+            error!("Failed to replace container of agent {}, keeping the old one: {}", agent_id, e);
+            // A container that was created but failed to start still holds the name
+            if let Err(e) = runtime.remove_container(&container_name).await {
+                error!("Failed to remove container {}: {}", container_name, e);
+            }
+            if let Err(e) = runtime.rename_container(&old.id, &container_name).await {
+                error!("Failed to rename container {} back to {}: {}", old.id, container_name, e);
+            }
+            restore_container_state(runtime, &old).await;
+            Err(e)
+        }
+    }
+}
+
+async fn restore_container_state(runtime: &dyn ContainerRuntime, inspect: &runtime::ContainerInspect) {
+    if !inspect.state.running {
+        return;
+    }
+    let mut result = runtime.start_container(&inspect.id).await;
+    if inspect.state.paused {
+        result = result.and(runtime.pause_container(&inspect.id).await);
+    }
+    if let Err(e) = result {
+        error!("Failed to restore container {}: {}", inspect.id, e);
+    }
 }
 
 #[tauri::command]
@@ -96,75 +306,147 @@ async fn create_agent_container(
     agent_name: String,
     number: i32,
     message_ids: Vec<String>,
-    system_prompt: String
+    system_prompt: String,
+    resource_limits: Option<ResourceLimits>,
+    mounts: Option<Vec<Mount>>,
 ) -> Result<Container, String> {
     println!("Creating agent container!");
+    let mounts = mounts.unwrap_or_default();
+    volumes::validate_mounts(&mounts)?;
+    let runtime = runtime()?;
     let ports = get_available_ports().map_err(|e| e.to_string())?;
     let container_name = format!("agent-{}", agent_id);
 
-    // Check and remove existing container
-    if !String::from_utf8_lossy(&Command::new("/opt/homebrew/bin/podman").args(&["ps", "-a", "--filter", &format!("name={}", container_name)]).output().map_err(|e| e.to_string())?.stdout,).trim().is_empty(){
-        Command::new("/opt/homebrew/bin/podman").args(&["rm", "-f", &container_name]).output().map_err(|e| e.to_string())?;
-    }
-
-    let dockerfile_path = get_resource_path(&app_handle, "Dockerfile");
-
-    let build_output = Command::new("/opt/homebrew/bin/podman")
-        .args(&[
-            "build",
-            "-t",
-            "localhost/minimal-vnc-desktop:latest",
-            "-f",
-            &dockerfile_path.to_string_lossy(),
-            "."
-        ])
-        .current_dir(dockerfile_path.parent().unwrap())
-        .output()
-        .map_err(|e| format!("Build failed: {}", e.to_string()))?;
-
-    if !build_output.status.success() {
-        let error_message = String::from_utf8_lossy(&build_output.stderr).to_string();
-        error!("Failed to build image: {}", error_message);
-        return Err(error_message);
-    }
+    // Remove any leftover container with the same name
+    runtime.remove_container(&container_name).await?;
 
-    // Run container with the locally built image
-    let run_output = Command::new("/opt/homebrew/bin/podman")
-        .args(&["run", 
-        "-d", "--network", "bridge", 
-        "-e", "DISPLAY=:0", 
-        "-e", &format!("CONTAINER_ID={}", agent_id), 
-        "-e", &format!("ANTHROPIC_API_KEY={}", env::var("ANTHROPIC_API_KEY").unwrap()),
-        "-e", "GEOMETRY=1920x1080", 
-        "-e", "HOST_IP=host.containers.internal", 
-        "-p", &format!("{}:5900", ports[0]), 
-        "-p", &format!("{}:6080", ports[1]), 
-        "--name", &container_name, 
-        "localhost/minimal-vnc-desktop:latest"])  // Use the locally built image
-        .output()
-        .map_err(|e| e.to_string())?;
-
-    if !run_output.status.success() {
-        let error_message = String::from_utf8_lossy(&run_output.stderr).to_string();
-        error!("Failed to start container: {}", error_message);
-        return Err(error_message);
-    }
+    // Reuses the cached image unless the bundled image files changed
+    let image = ensure_agent_image(&app_handle).await?;
 
-    let container = Container {
-        id: String::from_utf8_lossy(&run_output.stdout).trim().to_string(),
+    let mut container = Container {
+        id: String::new(),
         vnc_port: ports[1],
         agent_id,
         agent_type,
         agent_name,
         number,
         message_ids,
-        system_prompt
+        system_prompt,
+        image,
+        resource_limits: resource_limits.unwrap_or_else(ResourceLimits::agent_default),
+        status: ContainerStatus::Running,
+        idle_timeout_minutes: None,
+        snapshots: Vec::new(),
+        mounts,
+        ws_token: auth::new_token(),
+        ws_port: websocket::configured_port(),
+        schedules: Vec::new(),
+    };
+
+    // Run container with the locally built image
+    container.id = match run_agent_container(runtime.as_ref(), &container, ports[0]).await {
+        Ok(id) => id,
+        Err(error_message) => {
+            error!("Failed to start container: {}", error_message);
+            return Err(error_message);
+        }
     };
 
+    save_container(&container)?;
+    CONTAINERS.lock().unwrap().push(container.clone());
+
+    Ok(container)
+}
+
+// Changes cpu, memory and pids limits on the running container right away.
+// A new storage limit is saved but only applies once the container is recreated (see upgrade_agent_container).
+#[tauri::command]
+async fn update_agent_resource_limits(agent_id: String, resource_limits: ResourceLimits) -> Result<Container, String> {
+    let container = get_agent_container(agent_id.clone()).ok_or("Container not found")?;
+    runtime()?.update_limits(&container.id, &resource_limits).await?;
+
+    let mut containers = CONTAINERS.lock().unwrap();
+    let container = containers.iter_mut().find(|c| c.agent_id == agent_id).ok_or("Container not found")?;
+    container.resource_limits = resource_limits;
+    save_container(container)?;
+    Ok(container.clone())
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct AgentResourceUsage {
+    agent_id: String,
+    limits: ResourceLimits,
+    usage: ResourceUsage,
+}
+
+#[tauri::command]
+async fn get_agent_resource_usage(agent_id: String) -> Result<AgentResourceUsage, String> {
+    let container = get_agent_container(agent_id.clone()).ok_or("Container not found")?;
+    let usage = runtime()?.usage(&container.id).await?;
+    Ok(AgentResourceUsage { agent_id, limits: container.resource_limits, usage })
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct AgentImageInfo {
+    agent_id: String,
+    agent_name: String,
+    image: String,
+    outdated: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct AgentImageStatus {
+    current_image: String,
+    agents: Vec<AgentImageInfo>,
+}
+
+// Which image each agent runs and whether it is behind the bundled one
+#[tauri::command]
+fn get_agent_image_status(app_handle: tauri::AppHandle) -> Result<AgentImageStatus, String> {
+    let current_image = image::current_image_tag(&app_handle)?;
+    let containers = CONTAINERS.lock().unwrap();
+    let agents = containers.iter()
+        .map(|c| AgentImageInfo {
+            agent_id: c.agent_id.clone(),
+            agent_name: c.agent_name.clone(),
+            image: c.image.clone(),
+            outdated: c.image != current_image,
+        })
+        .collect();
+    Ok(AgentImageStatus { current_image, agents })
+}
+
+// Recreates an agent's container from the current image, keeping its ports and history.
+// Anything written inside the old container's filesystem is lost, files in its mounts are kept.
+#[tauri::command]
+async fn upgrade_agent_container(app_handle: tauri::AppHandle, agent_id: String) -> Result<Container, String> {
+    let runtime = runtime()?;
+    let mut container = get_agent_container(agent_id.clone()).ok_or("Container not found")?;
+    let image = ensure_agent_image(&app_handle).await?;
+
+    let container_name = format!("agent-{}", agent_id);
+    let vnc_host_port = match runtime.inspect_container(&container_name).await?.and_then(|i| i.host_port(5900)) {
+        Some(port) => port,
+        None => get_available_ports()?[0],
+    };
+
+    let previous_image = std::mem::replace(&mut container.image, image);
+    container.ws_port = websocket::configured_port();
+    container.id = replace_agent_container(runtime.as_ref(), &container, vnc_host_port).await?;
+    info!("Upgraded agent {} to {}", agent_id, container.image);
+
     let mut containers = CONTAINERS.lock().unwrap();
-    containers.push(container.clone());
-    save_containers(&app_handle, &containers).map_err(|e| e.to_string())?;
+    if let Some(stored) = containers.iter_mut().find(|c| c.agent_id == agent_id) {
+        stored.id = container.id.clone();
+        stored.image = container.image.clone();
+        stored.ws_port = container.ws_port;
+        save_container(stored)?;
+    }
+    drop(containers);
 
+    if previous_image != container.image {
+        snapshot::release_image(&previous_image).await;
+    }
     Ok(container)
 }
 
@@ -178,21 +460,238 @@ fn get_agent_container(agent_id: String) -> Option<Container> {
 #[tauri::command]
 async fn start_container(container_id: String) -> Result<(), String> {
     println!("Starting container: {}", container_id);
-    tokio::process::Command::new("/opt/homebrew/bin/podman")
-        .args(&["start", &container_id])
-        .output()
-        .await
-        .map_err(|e| e.to_string())?;
+    runtime()?.start_container(&container_id).await?;
+
+    let agent_id = CONTAINERS.lock().unwrap().iter().find(|c| c.id == container_id).map(|c| c.agent_id.clone());
+    if let Some(agent_id) = agent_id {
+        lifecycle::set_container_status(&agent_id, ContainerStatus::Running)?;
+    }
+    Ok(())
+}
+
+fn agent_container_id(agent_id: &str) -> Result<String, String> {
+    CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.agent_id == agent_id)
+        .map(|c| c.id.clone())
+        .ok_or_else(|| "Container not found".to_string())
+}
+
+#[tauri::command]
+async fn stop_agent_container(agent_id: String) -> Result<(), String> {
+    runtime()?.stop_container(&agent_container_id(&agent_id)?).await?;
+    lifecycle::set_container_status(&agent_id, ContainerStatus::Stopped)
+}
+
+#[tauri::command]
+async fn pause_agent_container(agent_id: String) -> Result<(), String> {
+    runtime()?.pause_container(&agent_container_id(&agent_id)?).await?;
+    lifecycle::set_container_status(&agent_id, ContainerStatus::Paused)
+}
+
+// Resumes a paused agent, or starts it if the engine no longer has it paused (e.g. after a reboot)
+#[tauri::command]
+async fn unpause_agent_container(agent_id: String) -> Result<(), String> {
+    let runtime = runtime()?;
+    let container_id = agent_container_id(&agent_id)?;
+    let paused = runtime.inspect_container(&container_id).await?.map(|i| i.state.paused).unwrap_or(false);
+    if paused {
+        runtime.unpause_container(&container_id).await?;
+    } else {
+        runtime.start_container(&container_id).await?;
+    }
+    lifecycle::set_container_status(&agent_id, ContainerStatus::Running)
+}
+
+#[tauri::command]
+async fn restart_agent_container(agent_id: String) -> Result<(), String> {
+    runtime()?.restart_container(&agent_container_id(&agent_id)?).await?;
+    lifecycle::set_container_status(&agent_id, ContainerStatus::Running)
+}
+
+#[tauri::command]
+fn get_agent_health(agent_id: String) -> Option<HealthReport> {
+    supervisor::get_agent_health(&agent_id)
+}
+
+// Streams a log to the frontend as "agent-log" events, returns the stream id
+#[tauri::command]
+async fn start_agent_log_stream(agent_id: String, source: LogSource, follow: bool, tail: Option<u32>, filter: Option<String>) -> Result<String, String> {
+    logs::start_log_stream(agent_id, source, follow, tail, filter).await
+}
+
+#[tauri::command]
+async fn stop_agent_log_stream(stream_id: String) -> bool {
+    logs::stop_log_stream(&stream_id).await
+}
+
+#[tauri::command]
+async fn get_agent_logs(agent_id: String, source: LogSource, tail: Option<u32>, filter: Option<String>) -> Result<Vec<String>, String> {
+    logs::read_logs(&agent_id, source, tail, filter).await
+}
+
+// Saves the agent's desktop as a named snapshot, with a memory checkpoint where the engine supports it
+#[tauri::command]
+async fn create_agent_snapshot(app_handle: tauri::AppHandle, agent_id: String, name: String) -> Result<Snapshot, String> {
+    snapshot::create_snapshot(&app_handle, &agent_id, name).await
+}
+
+#[tauri::command]
+fn get_agent_snapshots(agent_id: String) -> Result<Vec<Snapshot>, String> {
+    get_agent_container(agent_id).map(|c| c.snapshots).ok_or_else(|| "Container not found".to_string())
+}
+
+#[tauri::command]
+async fn delete_agent_snapshot(agent_id: String, snapshot_id: String) -> Result<(), String> {
+    snapshot::delete_snapshot(&agent_id, &snapshot_id).await
+}
+
+// Rolls the agent back to the snapshot, including its conversation
+#[tauri::command]
+async fn restore_agent_snapshot(agent_id: String, snapshot_id: String) -> Result<Container, String> {
+    snapshot::restore_snapshot(&agent_id, &snapshot_id).await
+}
+
+#[tauri::command]
+async fn create_agent_from_snapshot(
+    source_agent_id: String,
+    snapshot_id: String,
+    agent_id: String,
+    agent_name: String,
+    number: i32,
+) -> Result<Container, String> {
+    snapshot::create_agent_from_snapshot(&source_agent_id, &snapshot_id, agent_id, agent_name, number).await
+}
+
+// Branches an agent into a new one with fresh ports, starting from its current desktop state
+#[tauri::command]
+async fn clone_agent(
+    source_agent_id: String,
+    agent_id: String,
+    agent_name: String,
+    number: i32,
+    include_messages: bool,
+) -> Result<Container, String> {
+    snapshot::clone_agent(&source_agent_id, agent_id, agent_name, number, include_messages).await
+}
+
+// Replaces the agent's mounts. Mounts can only be set when a container is created, so the container
+// is recreated with its current filesystem. Agent volumes that are no longer mounted are deleted.
+#[tauri::command]
+async fn set_agent_mounts(agent_id: String, mounts: Vec<Mount>) -> Result<Container, String> {
+    volumes::validate_mounts(&mounts)?;
+    let mut container = get_agent_container(agent_id.clone()).ok_or("Container not found")?;
+    let dropped: Vec<Mount> = container.mounts.iter().filter(|m| !mounts.contains(m)).cloned().collect();
+
+    container.mounts = mounts;
+    let container = snapshot::recreate_agent(container).await?;
+    volumes::remove_agent_volumes(&agent_id, &dropped).await;
+    Ok(container)
+}
+
+#[tauri::command]
+async fn get_shared_volumes() -> Result<Vec<String>, String> {
+    volumes::list_shared_volumes().await
+}
+
+#[tauri::command]
+async fn create_shared_volume(name: String) -> Result<(), String> {
+    volumes::create_shared_volume(&name).await
+}
+
+#[tauri::command]
+async fn delete_shared_volume(name: String) -> Result<(), String> {
+    volumes::delete_shared_volume(&name).await
+}
+
+// Lists a directory in the agent's home, path is relative to it
+#[tauri::command]
+async fn list_agent_files(agent_id: String, path: Option<String>) -> Result<Vec<AgentFile>, String> {
+    artifacts::list_agent_files(&agent_id, path).await
+}
+
+// Copies a file out of the agent into the app data dir, linked to message_id or the latest message
+#[tauri::command]
+async fn fetch_agent_file(app_handle: tauri::AppHandle, agent_id: String, path: String, message_id: Option<String>) -> Result<Artifact, String> {
+    artifacts::fetch_agent_file(&app_handle, &agent_id, &path, message_id).await
+}
+
+#[tauri::command]
+fn get_agent_artifacts(app_handle: tauri::AppHandle, agent_id: String, message_id: Option<String>) -> Result<Vec<Artifact>, String> {
+    artifacts::get_artifacts(&app_handle, &agent_id, message_id)
+}
+
+#[tauri::command]
+fn delete_agent_artifact(app_handle: tauri::AppHandle, artifact_id: String) -> Result<(), String> {
+    artifacts::delete_artifact(&app_handle, &artifact_id)
+}
+
+// Files uploaded with a prompt message
+#[tauri::command]
+fn get_message_attachments(message_id: String) -> Result<Vec<Attachment>, String> {
+    db::get_attachments(&message_id)
+}
+
+// Token the frontend sends in its websocket init message
+#[tauri::command]
+fn get_websocket_token() -> String {
+    auth::client_token()
+}
+
+// Port the websocket server listens on, which may not be the configured one if that was taken
+#[tauri::command]
+fn get_websocket_port() -> u16 {
+    websocket::ws_port()
+}
+
+// Problems found with stored data since launch, e.g. agents restored from a backup
+#[tauri::command]
+fn get_storage_notices() -> Vec<StorageNotice> {
+    storage::get_notices()
+}
+
+#[tauri::command]
+fn set_agent_idle_timeout(agent_id: String, idle_timeout_minutes: Option<u32>) -> Result<(), String> {
+    let mut containers = CONTAINERS.lock().unwrap();
+    let container = containers.iter_mut().find(|c| c.agent_id == agent_id).ok_or("Container not found")?;
+    container.idle_timeout_minutes = idle_timeout_minutes.filter(|minutes| *minutes > 0);
+    save_container(container)?;
+    lifecycle::touch_agent(&agent_id);
     Ok(())
 }
 
+#[tauri::command]
+fn get_agent_schedules(agent_id: String) -> Result<Vec<Schedule>, String> {
+    scheduler::get_schedules(&agent_id)
+}
+
+//cron is a five field expression in local time, e.g. "0 8 * * *" for every day at 8:00
+#[tauri::command]
+fn add_agent_schedule(agent_id: String, cron: String, prompt: String) -> Result<Schedule, String> {
+    scheduler::add_schedule(&agent_id, &cron, &prompt)
+}
+
+#[tauri::command]
+fn remove_agent_schedule(agent_id: String, schedule_id: String) -> Result<(), String> {
+    scheduler::remove_schedule(&agent_id, &schedule_id)
+}
+
+#[tauri::command]
+fn set_agent_schedule_enabled(agent_id: String, schedule_id: String, enabled: bool) -> Result<Schedule, String> {
+    scheduler::set_schedule_enabled(&agent_id, &schedule_id, enabled)
+}
+
+//Latest runs of the agent's schedules and how they ended, newest first
+#[tauri::command]
+fn get_schedule_runs(agent_id: String, limit: Option<usize>) -> Result<Vec<ScheduleRun>, String> {
+    scheduler::get_schedule_runs(&agent_id, limit.unwrap_or(50))
+}
+
 #[tauri::command]
 async fn update_agent_system_prompt(agent_id: String, system_prompt: String) -> Result<(), String> {
-    let app_handle = get_app_handle().ok_or("Failed to get app handle")?;
     let mut containers = CONTAINERS.lock().unwrap();
     let container = containers.iter_mut().find(|c| c.agent_id == agent_id).ok_or("Container not found")?;
     container.system_prompt = system_prompt;
-    save_containers(&app_handle, &containers).map_err(|e| e.to_string())?;
+    save_container(container)?;
     Ok(())
 }
 
@@ -207,14 +706,15 @@ fn get_all_containers() -> Vec<Container> {
 fn print_all_storage() {
     println!("User data: {:?}", USER.lock().unwrap());
     println!("Occupied ports: {:?}", OCCUPIED_PORTS.lock().unwrap());
-    println!("Messages: {:?}", MESSAGES.lock().unwrap());
-    println!("Containers: {:?}", CONTAINERS.lock().unwrap());
+    // Counts only, messages and agents carry prompts and tokens
+    println!("Messages: {}", MESSAGES.lock().unwrap().len());
+    println!("Containers: {}", CONTAINERS.lock().unwrap().len());
 }
 
 
 //Clear all storage (only used for testing)
 #[tauri::command]
-fn clear_all_storage(app_handle: tauri::AppHandle) {
+fn clear_all_storage() {
     // Clear user data
     let mut user = USER.lock().unwrap();
     user.show_controls = true; // Reset to default
@@ -231,37 +731,27 @@ fn clear_all_storage(app_handle: tauri::AppHandle) {
     let mut containers = CONTAINERS.lock().unwrap();
     containers.clear();
 
-    // Save updated containers to disk
-    let containers_file = app_handle.path().app_data_dir()
-        .expect("Failed to get app data dir")
-        .join("containers.json");
-    let _ = serde_json::to_string(&*containers).map(|json| std::fs::write(&containers_file, json));
-    
-    let messages_file = get_messages_file(&app_handle);
-    let _ = serde_json::to_string(&*messages).map(|json| std::fs::write(&messages_file, json));
+    // Save updated state to disk
+    if let Err(e) = db::clear_all() {
+        error!("Failed to clear storage: {}", e);
+    }
 }
 
 // Clear all messages and message IDs in the containers
 #[tauri::command]
-fn clear_all_messages(app_handle: tauri::AppHandle) {
+fn clear_all_messages() {
     // Clear message IDs from containers
     let mut containers = CONTAINERS.lock().unwrap();
     for container in containers.iter_mut() {
         container.message_ids.clear();
     }
 
-    // Save updated containers to disk
-    let containers_file = app_handle.path().app_data_dir()
-        .expect("Failed to get app data dir")
-        .join("containers.json");
-    
-    let _ = serde_json::to_string(&*containers)
-        .map(|json| std::fs::write(&containers_file, json));
-
     // Clear messages from memory and disk
     let mut messages = MESSAGES.lock().unwrap();
     messages.clear();
-    let _ = std::fs::remove_file(get_messages_file(&app_handle));
+    if let Err(e) = db::clear_messages() {
+        error!("Failed to clear messages: {}", e);
+    }
 }
 
 //read all user data
@@ -280,7 +770,7 @@ fn update_user_data(show_controls: bool) {
 }
 
 #[tauri::command]
-fn get_agent_messages(agent_id: String) -> Vec<serde_json::Value> {
+fn get_agent_messages(agent_id: String) -> Vec<ChatMessage> {
     let containers = CONTAINERS.lock().unwrap();
     let mut messages_array = Vec::new();
     
@@ -289,11 +779,13 @@ fn get_agent_messages(agent_id: String) -> Vec<serde_json::Value> {
         
         for message_id in container.message_ids.iter() {
             if let Some(json_value) = messages.get(message_id) {
-                let mut json_value = json_value.clone();
-                if let serde_json::Value::Object(ref mut map) = json_value {
-                    map.insert("message_id".to_string(), serde_json::Value::String(message_id.clone()));
+                match serde_json::from_value::<ChatMessage>(json_value.clone()) {
+                    Ok(mut message) => {
+                        message.set_ids(message_id, &agent_id);
+                        messages_array.push(message);
+                    },
+                    Err(e) => error!("Skipping invalid message {}: {}", message_id, e),
                 }
-                messages_array.push(json_value);
             }
         }
     }
@@ -303,7 +795,15 @@ fn get_agent_messages(agent_id: String) -> Vec<serde_json::Value> {
 
 //this command should delete the container from the database, then for each message id, delete the message from the database. then delete the podman container
 #[tauri::command]
-fn delete_agent_container(app_handle: tauri::AppHandle, agent_id: String) -> Result<(), String> {
+async fn delete_agent_container(app_handle: tauri::AppHandle, agent_id: String) -> Result<(), String> {
+    if !CONTAINERS.lock().unwrap().iter().any(|c| c.agent_id == agent_id) {
+        return Err("Container not found".to_string());
+    }
+
+    // Delete the container first so a failure leaves the agent in place
+    let container_name = format!("agent-{}", agent_id);
+    runtime()?.remove_container(&container_name).await?;
+
     // Find and remove container from CONTAINERS
     let mut containers = CONTAINERS.lock().unwrap();
     let container = containers
@@ -311,50 +811,101 @@ fn delete_agent_container(app_handle: tauri::AppHandle, agent_id: String) -> Res
         .position(|c| c.agent_id == agent_id)
         .ok_or("Container not found")?;
     
-    // Get message IDs before removing container
-    let message_ids = containers[container].message_ids.clone();
-    let container_name = format!("agent-{}", agent_id);
+    // Get message IDs before removing container, including the history kept by its snapshots
+    let mut message_ids = containers[container].message_ids.clone();
+    let snapshots = containers[container].snapshots.clone();
+    let image = containers[container].image.clone();
+    let mounts = containers[container].mounts.clone();
+    message_ids.extend(snapshots.iter().flat_map(|s| s.message_ids.iter().cloned()));
     
     // Remove container from memory
     containers.remove(container);
     
     // Delete messages
     let mut messages = MESSAGES.lock().unwrap();
-    for message_id in message_ids {
-        messages.remove(&message_id);
-    }
-    
-    // Delete podman container
-    let output = Command::new("/opt/homebrew/bin/podman")
-        .args(&["rm", "-f", &container_name])
-        .output()
-        .map_err(|e| format!("Failed to delete container: {}", e))?;
-
-    if !output.status.success() {
-        return Err(format!("Failed to delete container: {}", 
-            String::from_utf8_lossy(&output.stderr)));
+    for message_id in &message_ids {
+        messages.remove(message_id);
     }
     
     // Save updated state to disk
-    save_containers(&app_handle, &containers)?;
-    save_messages(&app_handle, &messages)?;
-
+    db::delete_agent(&agent_id, &message_ids)?;
+    drop(messages);
+    drop(containers);
+
+    snapshot::remove_agent_snapshots(&snapshots).await;
+    snapshot::release_image(&image).await;
+    volumes::remove_agent_volumes(&agent_id, &mounts).await;
+    if let Err(e) = artifacts::remove_agent_artifacts(&app_handle, &agent_id) {
+        error!("Failed to remove artifacts of agent {}: {}", agent_id, e);
+    }
+    delivery::forget(&agent_id);
+    run_state::forget(&agent_id);
+    websocket::clear_prompt_queue(&agent_id).await;
     Ok(())
 }
 
 #[tauri::command]
-fn update_agent_name(app_handle: tauri::AppHandle, agent_id: String, new_name: String) -> Result<(), String> {
+fn update_agent_name(agent_id: String, new_name: String) -> Result<(), String> {
     let mut containers = CONTAINERS.lock().unwrap();
     
     if let Some(container) = containers.iter_mut().find(|c| c.agent_id == agent_id) {
         container.agent_name = new_name;
-        save_containers(&app_handle, &containers)?;
+        save_container(container)?;
         Ok(())
     } else {
         Err("Container not found".to_string())
     }
 }
 
+// Sets up podman when it is the selected engine, then picks the runtime every command uses
+async fn init_container_runtime() {
+    if runtime::preferred_kind() == RuntimeKind::Podman {
+        if let Err(e) = podman_setup().await {
+            error!("Podman setup failed: {}", e);
+        }
+        if let (Some(app_handle), Some(diagnosis)) = (get_app_handle(), launch_podman::get_podman_diagnosis()) {
+            let _ = app_handle.emit("podman-diagnosis", diagnosis);
+        }
+    }
+    match init_runtime().await {
+        Ok(runtime) => {
+            tauri::async_runtime::spawn(forward_container_events(runtime));
+            // Build the agent image ahead of the first agent creation if it is missing
+            if let Some(app_handle) = get_app_handle() {
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = ensure_agent_image(&app_handle).await {
+                        error!("Failed to prepare agent image: {}", e);
+                    }
+                });
+            }
+        },
+        Err(e) => error!("Failed to initialize container runtime: {}", e),
+    }
+}
+
+// Relays engine events for agent containers to the frontend as "container-event"
+async fn forward_container_events(runtime: std::sync::Arc<dyn ContainerRuntime>) {
+    let mut events = match runtime.events().await {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to subscribe to container events: {}", e);
+            return;
+        }
+    };
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(event) if event.name.trim_start_matches('/').starts_with("agent-") => {
+                if let Some(app_handle) = get_app_handle() {
+                    let _ = app_handle.emit("container-event", event);
+                }
+            },
+            Ok(_) => {},
+            Err(e) => error!("Container event stream error: {}", e),
+        }
+    }
+    info!("Container event stream ended");
+}
+
 async fn setup_app(app: tauri::AppHandle) -> Result<(), String> {
     // Get windows before spawning
     let splashscreen = app.get_webview_window("splashscreen");
@@ -366,22 +917,32 @@ async fn setup_app(app: tauri::AppHandle) -> Result<(), String> {
         *handle = Some(app.clone());
     }
     // Check for podman and install if needed
-    podman_setup().await;  // Changed: remove block_on
+    init_container_runtime().await;
+
+    if let Err(e) = db::init(&app) {
+        storage::notify("agents", format!("Your agents could not be loaded: {}", e));
+    }
     
     // Load containers on startup
-    if let Ok(containers) = load_containers(&app) {
-        {
-            let mut stored_containers = CONTAINERS.lock().unwrap();
-            *stored_containers = containers.clone();
-        }
-        // Start all containers using the helper function
-        start_all_containers(containers).await;  // Changed: remove block_on
+    match load_containers() {
+        Ok(containers) => {
+            {
+                let mut stored_containers = CONTAINERS.lock().unwrap();
+                *stored_containers = containers.clone();
+            }
+            // Start all containers using the helper function
+            start_all_containers(containers).await;  // Changed: remove block_on
+        },
+        Err(e) => storage::notify("agents", format!("Your agents could not be loaded: {}", e)),
     }
 
     // Add this: Load messages on startup
-    if let Ok(messages) = load_messages(&app) {
-        let mut stored_messages = MESSAGES.lock().unwrap();
-        *stored_messages = messages;
+    match load_messages() {
+        Ok(messages) => {
+            let mut stored_messages = MESSAGES.lock().unwrap();
+            *stored_messages = messages;
+        },
+        Err(e) => storage::notify("messages", format!("Your messages could not be loaded: {}", e)),
     }
 
     // Start the WebSocket server in an async task
@@ -414,6 +975,10 @@ async fn setup_app(app: tauri::AppHandle) -> Result<(), String> {
 pub fn run() {
     dotenv().ok();
 
+    // Choosing the websocket port binds it, before the app starts so the CSP can name it
+    let mut context = tauri::generate_context!();
+    websocket::restrict_csp(context.config_mut());
+
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
         .setup(|app| {
@@ -427,22 +992,31 @@ pub fn run() {
             info!("Setting up app here 2!");
             println!("Setting up app here 2!");
             // Check for podman and install if needed
-            let _ = tauri::async_runtime::block_on(podman_setup());
+            tauri::async_runtime::block_on(init_container_runtime());
             println!("Podman setup complete!");
+            // Open the database, importing the old JSON files on first launch
+            if let Err(e) = db::init(&app.handle()) {
+                storage::notify("agents", format!("Your agents could not be loaded: {}", e));
+            }
             // Load containers on startup
-            if let Ok(containers) = load_containers(&app.handle()) {
-                let mut stored_containers = CONTAINERS.lock().unwrap();
-                *stored_containers = containers.clone();
-                // Start all containers using the helper function
-                tauri::async_runtime::block_on(start_all_containers(containers));
+            match load_containers() {
+                Ok(containers) => {
+                    *CONTAINERS.lock().unwrap() = containers.clone();
+                    // Start all containers using the helper function, without holding the lock they update
+                    tauri::async_runtime::block_on(start_all_containers(containers));
+                },
+                Err(e) => storage::notify("agents", format!("Your agents could not be loaded: {}", e)),
             }
             info!("Setting up app here 3!");
             println!("Setting up app here 3!");
 
             // Add this: Load messages on startup
-            if let Ok(messages) = load_messages(&app.handle()) {
-                let mut stored_messages = MESSAGES.lock().unwrap();
-                *stored_messages = messages;
+            match load_messages() {
+                Ok(messages) => {
+                    let mut stored_messages = MESSAGES.lock().unwrap();
+                    *stored_messages = messages;
+                },
+                Err(e) => storage::notify("messages", format!("Your messages could not be loaded: {}", e)),
             }
             info!("Setting up app here 4!");
             println!("Setting up app here 4!");
@@ -453,6 +1027,21 @@ pub fn run() {
                 start_websocket_server().await;
             });
 
+            // Recreate agents whose environment lacks a websocket token or has another port
+            tauri::async_runtime::spawn(lifecycle::update_agent_environments());
+
+            // Stop agents that have been idle past their timeout
+            tauri::async_runtime::spawn(lifecycle::run_idle_watcher());
+
+            // Watch agent health and restart crashed components
+            tauri::async_runtime::spawn(supervisor::run_supervisor());
+
+            // Send scheduled prompts, waking stopped agents
+            tauri::async_runtime::spawn(scheduler::run_scheduler());
+
+            // Keep taking database backups while the app runs
+            tauri::async_runtime::spawn(db::run_backups(app.handle().clone()));
+
             splashscreen_window.close().unwrap();
             main_window.show().unwrap();
 
@@ -470,11 +1059,51 @@ pub fn run() {
             clear_all_messages,
             start_container,
             get_prompt_running,
+            get_prompt_queue,
+            cancel_queued_prompt,
+            move_queued_prompt,
             update_agent_system_prompt,
             is_setup_complete,
             delete_agent_container,
             update_agent_name,
+            get_podman_diagnosis,
+            get_agent_image_status,
+            upgrade_agent_container,
+            update_agent_resource_limits,
+            get_agent_resource_usage,
+            stop_agent_container,
+            pause_agent_container,
+            unpause_agent_container,
+            restart_agent_container,
+            set_agent_idle_timeout,
+            get_agent_schedules,
+            add_agent_schedule,
+            remove_agent_schedule,
+            set_agent_schedule_enabled,
+            get_schedule_runs,
+            get_agent_health,
+            start_agent_log_stream,
+            stop_agent_log_stream,
+            get_agent_logs,
+            create_agent_snapshot,
+            get_agent_snapshots,
+            delete_agent_snapshot,
+            restore_agent_snapshot,
+            create_agent_from_snapshot,
+            clone_agent,
+            set_agent_mounts,
+            get_shared_volumes,
+            create_shared_volume,
+            delete_shared_volume,
+            list_agent_files,
+            fetch_agent_file,
+            get_agent_artifacts,
+            delete_agent_artifact,
+            get_message_attachments,
+            get_storage_notices,
+            get_websocket_token,
+            get_websocket_port,
         ])
-        .run(tauri::generate_context!())
+        .run(context)
         .expect("error while running tauri application");
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tauri::Emitter;
+use log::{info, error};
+
+use crate::{auth, run_state, websocket, Container, CONTAINERS, get_app_handle, save_container, runtime};
+use crate::snapshot::recreate_agent;
+
+//Persisted run status of an agent's container, as last set by the app
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerStatus {
+    #[default]
+    Running,
+    Paused,
+    Stopped,
+}
+
+//How often idle agents are checked
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+//How long a prompt waits for a woken agent to reconnect
+const WAKE_TIMEOUT: Duration = Duration::from_secs(120);
+
+//Last time each agent sent or received a message
+static LAST_ACTIVITY: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn touch_agent(agent_id: &str) {
+    LAST_ACTIVITY.lock().unwrap().insert(agent_id.to_string(), Instant::now());
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct ContainerStatusEvent {
+    agent_id: String,
+    status: ContainerStatus,
+}
+
+//Records the new status, saves it and tells the frontend
+pub fn set_container_status(agent_id: &str, status: ContainerStatus) -> Result<(), String> {
+    {
+        let mut containers = CONTAINERS.lock().unwrap();
+        let container = containers.iter_mut().find(|c| c.agent_id == agent_id).ok_or("Container not found")?;
+        if container.status == status {
+            return Ok(());
+        }
+        container.status = status;
+        save_container(container)?;
+    }
+    if status == ContainerStatus::Running {
+        touch_agent(agent_id);
+    }
+    //There is no app handle in tests
+    if let Some(app_handle) = get_app_handle() {
+        let _ = app_handle.emit("container-status", ContainerStatusEvent { agent_id: agent_id.to_string(), status });
+    }
+    Ok(())
+}
+
+pub fn container_status(agent_id: &str) -> Option<ContainerStatus> {
+    CONTAINERS.lock().unwrap().iter().find(|c| c.agent_id == agent_id).map(|c| c.status)
+}
+
+//Brings a stopped or paused agent back and waits until its agent process has reconnected
+pub async fn wake_agent(agent_id: &str) -> Result<(), String> {
+    let connections = websocket::agent_connections(agent_id);
+    let container_id = CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.agent_id == agent_id)
+        .map(|c| c.id.clone())
+        .ok_or("Container not found")?;
+    info!("Waking agent {}", agent_id);
+
+    let runtime = runtime()?;
+    let paused = runtime.inspect_container(&container_id).await?
+        .map(|inspect| inspect.state.paused)
+        .unwrap_or(false);
+    if paused {
+        runtime.unpause_container(&container_id).await?;
+    } else {
+        runtime.start_container(&container_id).await?;
+    }
+    set_container_status(agent_id, ContainerStatus::Running)?;
+
+    //A paused agent's connection can outlive the pause, otherwise the agent has to connect again
+    let deadline = Instant::now() + WAKE_TIMEOUT;
+    while Instant::now() < deadline {
+        if websocket::agent_connections(agent_id) > connections || (paused && websocket::is_agent_connected(agent_id)) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    Err(format!("Agent {} did not reconnect after waking", agent_id))
+}
+
+//Stops running agents whose idle timeout has passed without any messages
+pub async fn run_idle_watcher() {
+    loop {
+        tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+
+        let candidates: Vec<(String, String, u32)> = CONTAINERS.lock().unwrap().iter()
+            .filter(|c| c.status == ContainerStatus::Running)
+            .filter_map(|c| c.idle_timeout_minutes.map(|minutes| (c.agent_id.clone(), c.id.clone(), minutes)))
+            .collect();
+
+        for (agent_id, container_id, minutes) in candidates {
+            let idle_for = LAST_ACTIVITY.lock().unwrap()
+                .entry(agent_id.clone())
+                .or_insert_with(Instant::now)
+                .elapsed();
+            if idle_for < Duration::from_secs(minutes as u64 * 60) {
+                continue;
+            }
+            if run_state::get(&agent_id).is_busy() {
+                continue;
+            }
+
+            info!("Stopping agent {} after {} idle minutes", agent_id, minutes);
+            let result = match runtime() {
+                Ok(runtime) => runtime.stop_container(&container_id).await,
+                Err(e) => Err(e),
+            };
+            match result.and_then(|_| set_container_status(&agent_id, ContainerStatus::Stopped)) {
+                Ok(()) => {},
+                Err(e) => error!("Failed to stop idle agent {}: {}", agent_id, e),
+            }
+        }
+    }
+}
+
+//An agent's environment is fixed when its container is created. Recreates the containers of agents
+//created before websocket tokens, which could never connect, and of agents created with another
+//configured port, keeping their files and run status. A port taken by something else doesn't count,
+//agents search past it like the server does.
+pub async fn update_agent_environments() {
+    let port = websocket::configured_port();
+    let outdated: Vec<Container> = CONTAINERS.lock().unwrap().iter()
+        .filter(|c| c.ws_token.is_empty() || c.ws_port != port)
+        .cloned()
+        .collect();
+
+    for mut container in outdated {
+        info!("Recreating agent {} to update its websocket token and port", container.agent_id);
+        let status = container.status;
+        if container.ws_token.is_empty() {
+            container.ws_token = auth::new_token();
+        }
+        let container = match recreate_agent(container).await {
+            Ok(container) => container,
+            Err(e) => {
+                error!("Failed to recreate agent: {}", e);
+                continue;
+            }
+        };
+
+        let runtime = match runtime() {
+            Ok(runtime) => runtime,
+            Err(_) => continue,
+        };
+        let result = match status {
+            ContainerStatus::Running => Ok(()),
+            ContainerStatus::Paused => runtime.pause_container(&container.id).await,
+            ContainerStatus::Stopped => runtime.stop_container(&container.id).await,
+        };
+        if let Err(e) = result.and_then(|_| set_container_status(&container.agent_id, status)) {
+            error!("Failed to return agent {} to {:?}: {}", container.agent_id, status, e);
+        }
+    }
+}
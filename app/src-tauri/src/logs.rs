@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use futures::stream::{BoxStream, StreamExt};
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tauri::Emitter;
+use log::{info, error};
+
+use crate::{CONTAINERS, get_app_handle, runtime};
+
+//Where a log line comes from: the container's own output or one of the supervisord programs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogSource {
+    Container,
+    Supervisord,
+    Vnc,
+    Novnc,
+    App,
+}
+
+impl LogSource {
+    //Log file inside the container, see supervisord.conf
+    fn log_file(&self) -> Option<&'static str> {
+        match self {
+            LogSource::Container => None,
+            LogSource::Supervisord => Some("/var/log/supervisor/supervisord.log"),
+            LogSource::Vnc => Some("/var/log/supervisor/vnc.log"),
+            LogSource::Novnc => Some("/var/log/supervisor/novnc.log"),
+            LogSource::App => Some("/var/log/supervisor/app.log"),
+        }
+    }
+}
+
+//Emitted as "agent-log" for every line of a running stream
+#[derive(Serialize, Clone, Debug)]
+struct LogLine {
+    stream_id: String,
+    agent_id: String,
+    source: LogSource,
+    line: String,
+}
+
+//Emitted as "agent-log-end" when a stream finishes or fails
+#[derive(Serialize, Clone, Debug)]
+struct LogStreamEnd {
+    stream_id: String,
+    agent_id: String,
+    error: Option<String>,
+}
+
+//A log being read, with the tail process to kill inside the container for supervisord logs.
+//Dropping the stream doesn't end an exec'd process, only the app's side of it.
+struct LogStream {
+    lines: BoxStream<'static, Result<String, String>>,
+    container_id: String,
+    pid: Option<String>,
+}
+
+//A running stream's task and the tail process feeding it
+struct RunningStream {
+    task: tauri::async_runtime::JoinHandle<()>,
+    container_id: String,
+    pid: Option<String>,
+}
+
+//Running log streams by stream id
+static LOG_STREAMS: Lazy<Mutex<HashMap<String, RunningStream>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+const DEFAULT_TAIL: u32 = 200;
+
+async fn open_stream(agent_id: &str, source: LogSource, follow: bool, tail: Option<u32>) -> Result<LogStream, String> {
+    let container_id = CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.agent_id == agent_id)
+        .map(|c| c.id.clone())
+        .ok_or("Container not found")?;
+    let runtime = runtime()?;
+    let tail = tail.unwrap_or(DEFAULT_TAIL);
+
+    match source.log_file() {
+        Some(file) => {
+            //The shell prints its pid and becomes tail, so the first line is the pid to kill
+            let script = format!("echo $$; exec tail -n {} {} {}", tail, if follow { "-F" } else { "" }, file);
+            let mut lines = runtime.exec_stream(&container_id, &["sh", "-c", &script]).await?;
+            let pid = match lines.next().await {
+                Some(Ok(pid)) => Some(pid.trim().to_string()),
+                Some(Err(e)) => return Err(e),
+                None => None,
+            };
+            Ok(LogStream { lines, container_id, pid })
+        },
+        None => Ok(LogStream {
+            lines: runtime.logs(&container_id, follow, Some(tail)).await?,
+            container_id,
+            pid: None,
+        }),
+    }
+}
+
+//Ends a tail process started by open_stream
+async fn kill_tail(container_id: &str, pid: &str) {
+    let result = match runtime() {
+        Ok(runtime) => runtime.exec(container_id, &["kill", pid]).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("Failed to stop log tail {} in container {}: {}", pid, container_id, e);
+    }
+}
+
+//Case-insensitive substring match, no filter matches everything
+fn matches_filter(line: &str, filter: &Option<String>) -> bool {
+    match filter {
+        Some(filter) if !filter.is_empty() => line.to_lowercase().contains(&filter.to_lowercase()),
+        _ => true,
+    }
+}
+
+//Returns the last lines of a log without following it
+pub async fn read_logs(agent_id: &str, source: LogSource, tail: Option<u32>, filter: Option<String>) -> Result<Vec<String>, String> {
+    let lines: Vec<Result<String, String>> = open_stream(agent_id, source, false, tail).await?.lines.collect().await;
+    lines.into_iter()
+        .filter(|line| line.as_ref().map(|l| matches_filter(l, &filter)).unwrap_or(true))
+        .collect()
+}
+
+//Starts streaming a log to the frontend, returning the id used to stop it
+pub async fn start_log_stream(agent_id: String, source: LogSource, follow: bool, tail: Option<u32>, filter: Option<String>) -> Result<String, String> {
+    let app_handle = get_app_handle().ok_or("Failed to get app handle")?;
+    let LogStream { mut lines, container_id, pid } = open_stream(&agent_id, source, follow, tail).await?;
+    let stream_id = uuid::Uuid::new_v4().to_string();
+    info!("Streaming {:?} logs of agent {} ({})", source, agent_id, stream_id);
+
+    //The task waits until it is registered, so a short stream can't remove itself before it is added
+    let (registered, wait_registered) = tokio::sync::oneshot::channel::<()>();
+    let id = stream_id.clone();
+    let task = tauri::async_runtime::spawn(async move {
+        if wait_registered.await.is_err() {
+            return;
+        }
+        let mut error = None;
+        while let Some(line) = lines.next().await {
+            match line {
+                Ok(line) if matches_filter(&line, &filter) => {
+                    let _ = app_handle.emit("agent-log", LogLine {
+                        stream_id: id.clone(),
+                        agent_id: agent_id.clone(),
+                        source,
+                        line,
+                    });
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        LOG_STREAMS.lock().unwrap().remove(&id);
+        let _ = app_handle.emit("agent-log-end", LogStreamEnd { stream_id: id, agent_id, error });
+    });
+    LOG_STREAMS.lock().unwrap().insert(stream_id.clone(), RunningStream { task, container_id, pid });
+    let _ = registered.send(());
+
+    Ok(stream_id)
+}
+
+pub async fn stop_log_stream(stream_id: &str) -> bool {
+    let stream = LOG_STREAMS.lock().unwrap().remove(stream_id);
+    match stream {
+        Some(stream) => {
+            stream.task.abort();
+            if let Some(pid) = &stream.pid {
+                kill_tail(&stream.container_id, pid).await;
+            }
+            true
+        },
+        None => false,
+    }
+}
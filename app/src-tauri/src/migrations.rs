@@ -0,0 +1,205 @@
+use serde_json::Value;
+
+use crate::{websocket, Container};
+
+//Current version of each stored record. Bump it and add a migration from the previous version
+//whenever a change can't be covered by a serde default.
+pub const AGENT_VERSION: u64 = 2;
+pub const MESSAGE_VERSION: u64 = 1;
+
+type Migration = fn(Value) -> Result<Value, String>;
+
+//Migrations by the version they upgrade from, so AGENT_MIGRATIONS[0] turns version 0 into version 1
+const AGENT_MIGRATIONS: &[Migration] = &[agent_v0_to_v1, agent_v1_to_v2];
+const MESSAGE_MIGRATIONS: &[Migration] = &[message_v0_to_v1];
+
+//Version 0 is every record written before the envelope: containers.json entries, which carried
+//message_ids, and the first database rows, which already didn't. Fields added along the way
+//(image, resource limits, status, idle timeout, snapshots, mounts) have serde defaults.
+fn agent_v0_to_v1(mut data: Value) -> Result<Value, String> {
+    let map = data.as_object_mut().ok_or("Agent record is not an object")?;
+    //The conversation lives in the messages table
+    map.remove("message_ids");
+    Ok(data)
+}
+
+//Version 2 authenticates agents and lets them find the server port. Agents saved before that have
+//no token, which gets them recreated with one at startup, and were built for the default port.
+fn agent_v1_to_v2(mut data: Value) -> Result<Value, String> {
+    let map = data.as_object_mut().ok_or("Agent record is not an object")?;
+    map.entry("ws_token").or_insert_with(|| Value::from(""));
+    map.entry("ws_port").or_insert_with(|| Value::from(websocket::DEFAULT_WS_PORT));
+    map.entry("schedules").or_insert_with(|| Value::Array(Vec::new()));
+    Ok(data)
+}
+
+//Messages are the JSON agents and the frontend send, stored as is, so there is nothing to change
+fn message_v0_to_v1(data: Value) -> Result<Value, String> {
+    if !data.is_object() {
+        return Err("Message record is not an object".to_string());
+    }
+    Ok(data)
+}
+
+//Stored as {"version": n, "data": record}
+fn wrap(version: u64, data: Value) -> String {
+    serde_json::json!({ "version": version, "data": data }).to_string()
+}
+
+//Splits a stored record into its version and data, unversioned records being version 0
+fn unwrap(stored: Value) -> (u64, Value) {
+    if let Value::Object(map) = &stored {
+        if map.len() == 2 && map.contains_key("data") {
+            if let Some(version) = map.get("version").and_then(|v| v.as_u64()) {
+                return (version, map.get("data").cloned().unwrap_or_default());
+            }
+        }
+    }
+    (0, stored)
+}
+
+fn migrate(stored: Value, current: u64, migrations: &[Migration]) -> Result<Value, String> {
+    let (version, mut data) = unwrap(stored);
+    if version > current {
+        return Err(format!("Saved by a newer version of the app (record version {}, this version reads up to {})", version, current));
+    }
+    for migration in &migrations[version as usize..current as usize] {
+        data = migration(data)?;
+    }
+    Ok(data)
+}
+
+pub fn encode_agent(container: &Container) -> Result<String, String> {
+    let mut data = serde_json::to_value(container).map_err(|e| format!("Failed to serialize agent: {}", e))?;
+    if let Value::Object(ref mut map) = data {
+        map.remove("message_ids");
+    }
+    Ok(wrap(AGENT_VERSION, data))
+}
+
+//Reads an agent record of any version; message_ids come back empty
+pub fn decode_agent(stored: Value) -> Result<Container, String> {
+    let data = migrate(stored, AGENT_VERSION, AGENT_MIGRATIONS)?;
+    serde_json::from_value(data).map_err(|e| format!("Invalid agent record: {}", e))
+}
+
+pub fn encode_message(json: &Value) -> String {
+    wrap(MESSAGE_VERSION, json.clone())
+}
+
+pub fn decode_message(stored: Value) -> Result<Value, String> {
+    migrate(stored, MESSAGE_VERSION, MESSAGE_MIGRATIONS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //One agent record per historical format, oldest first
+    const AGENT_FIXTURES: &[(&str, &str)] = &[
+        ("baseline containers.json", include_str!("../tests/fixtures/agent_baseline.json")),
+        ("image tag", include_str!("../tests/fixtures/agent_image.json")),
+        ("resource limits", include_str!("../tests/fixtures/agent_resource_limits.json")),
+        ("status and idle timeout", include_str!("../tests/fixtures/agent_status.json")),
+        ("snapshots and mounts", include_str!("../tests/fixtures/agent_mounts.json")),
+        ("database row", include_str!("../tests/fixtures/agent_db_row.json")),
+        ("version 1 before tokens", include_str!("../tests/fixtures/agent_v1.json")),
+        ("version 1 with tokens", include_str!("../tests/fixtures/agent_v1_ws.json")),
+        ("version 2", include_str!("../tests/fixtures/agent_v2.json")),
+    ];
+
+    const MESSAGE_FIXTURES: &[(&str, &str)] = &[
+        ("baseline messages.json", include_str!("../tests/fixtures/message_baseline.json")),
+        ("version 1", include_str!("../tests/fixtures/message_v1.json")),
+    ];
+
+    fn parse(fixture: &str) -> Value {
+        serde_json::from_str(fixture).unwrap()
+    }
+
+    #[test]
+    fn loads_every_agent_version() {
+        for (name, fixture) in AGENT_FIXTURES {
+            let container = decode_agent(parse(fixture)).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(container.agent_id, "pam-1", "{}", name);
+            assert_eq!(container.agent_name, "Pam", "{}", name);
+            assert!(container.message_ids.is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn fills_defaults_for_old_agents() {
+        let container = decode_agent(parse(AGENT_FIXTURES[0].1)).unwrap();
+        assert_eq!(container.image, "");
+        assert_eq!(container.resource_limits, crate::ResourceLimits::default());
+        assert_eq!(container.status, crate::ContainerStatus::Running);
+        assert!(container.snapshots.is_empty());
+        assert!(container.mounts.is_empty());
+    }
+
+    #[test]
+    fn keeps_fields_of_newer_agents() {
+        let container = decode_agent(parse(AGENT_FIXTURES[4].1)).unwrap();
+        assert_eq!(container.status, crate::ContainerStatus::Stopped);
+        assert_eq!(container.idle_timeout_minutes, Some(30));
+        assert_eq!(container.snapshots.len(), 1);
+        assert_eq!(container.mounts.len(), 2);
+    }
+
+    #[test]
+    fn fills_websocket_fields_of_agents_from_before_tokens() {
+        for (name, fixture) in &AGENT_FIXTURES[..7] {
+            let container = decode_agent(parse(fixture)).unwrap();
+            assert_eq!(container.ws_token, "", "{}", name);
+            assert_eq!(container.ws_port, websocket::DEFAULT_WS_PORT, "{}", name);
+            assert!(container.schedules.is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn keeps_websocket_fields_of_version_1_agents() {
+        let container = decode_agent(parse(AGENT_FIXTURES[7].1)).unwrap();
+        assert_eq!(container.ws_token, "9f86d081884c7d659a2feaa0c55ad015");
+        assert_eq!(container.ws_port, 3031);
+        assert_eq!(container.schedules.len(), 1);
+        assert_eq!(container.schedules[0].last_fired_at.as_deref(), Some("2025-01-07T08:00:00+01:00"));
+    }
+
+    #[test]
+    fn current_agent_is_stored_unchanged() {
+        let fixture = parse(AGENT_FIXTURES[8].1);
+        let container = decode_agent(fixture.clone()).unwrap();
+        assert_eq!(parse(&encode_agent(&container).unwrap()), fixture);
+    }
+
+    #[test]
+    fn agent_round_trips_at_current_version() {
+        for (name, fixture) in AGENT_FIXTURES {
+            let container = decode_agent(parse(fixture)).unwrap();
+            let stored = parse(&encode_agent(&container).unwrap());
+            assert_eq!(stored["version"], AGENT_VERSION, "{}", name);
+            assert!(stored["data"].get("message_ids").is_none(), "{}", name);
+            let reloaded = decode_agent(stored).unwrap();
+            assert_eq!(serde_json::to_value(&reloaded).unwrap(), serde_json::to_value(&container).unwrap(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn loads_every_message_version() {
+        for (name, fixture) in MESSAGE_FIXTURES {
+            let message = decode_message(parse(fixture)).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(message["agent_id"], "pam-1", "{}", name);
+            assert_eq!(message["message-type"], "message", "{}", name);
+            let stored = parse(&encode_message(&message));
+            assert_eq!(decode_message(stored).unwrap(), message, "{}", name);
+        }
+    }
+
+    #[test]
+    fn refuses_records_from_newer_versions() {
+        let stored = serde_json::json!({ "version": AGENT_VERSION + 1, "data": {} });
+        assert!(decode_agent(stored).is_err());
+        let stored = serde_json::json!({ "version": MESSAGE_VERSION + 1, "data": {} });
+        assert!(decode_message(stored).is_err());
+    }
+}
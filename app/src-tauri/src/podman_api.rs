@@ -0,0 +1,954 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use hyperlocal::{UnixClientExt, UnixConnector};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use log::{info, error};
+
+use crate::auth;
+use crate::runtime::{ContainerEvent, ContainerInspect, ContainerRuntime, ExecOutput, ResourceLimits, ResourceUsage, RunSpec, RuntimeKind};
+
+//Docker compatible API version, served by both podman (compat endpoints) and docker
+const API_VERSION: &str = "v1.41";
+
+//Podman's own endpoints (under /libpod) reject the docker API version
+const LIBPOD_API_VERSION: &str = "v4.0.0";
+
+//Error from the engine API, keeping the HTTP status so callers can tell "not found" from real failures
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    //0 when the request never got a response (socket missing, connection refused)
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    fn transport(e: impl fmt::Display) -> Self {
+        ApiError { status: 0, message: e.to_string() }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status == StatusCode::NOT_FOUND.as_u16()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.status == 0 {
+            write!(f, "API request failed: {}", self.message)
+        } else {
+            write!(f, "API error {}: {}", self.status, self.message)
+        }
+    }
+}
+
+impl From<ApiError> for String {
+    fn from(e: ApiError) -> String {
+        e.to_string()
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct CreateContainerRequest {
+    pub image: String,
+    pub env: Vec<String>,
+    pub exposed_ports: HashMap<String, HashMap<(), ()>>,
+    pub host_config: HostConfig,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct HostConfig {
+    pub network_mode: String,
+    pub port_bindings: HashMap<String, Vec<HostPortBinding>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_hosts: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub binds: Vec<String>,
+    #[serde(flatten)]
+    pub resources: Resources,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub storage_opt: HashMap<String, String>,
+}
+
+//Limits accepted by both container create (inside HostConfig) and container update
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct Resources {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nano_cpus: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_swap: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pids_limit: Option<i64>,
+}
+
+impl From<&ResourceLimits> for Resources {
+    fn from(limits: &ResourceLimits) -> Self {
+        let memory = limits.memory_mb.map(|mb| (mb * 1024 * 1024) as i64);
+        Resources {
+            nano_cpus: limits.cpus.map(|cpus| (cpus * 1e9) as i64),
+            memory,
+            memory_swap: memory,
+            pids_limit: limits.pids,
+        }
+    }
+}
+
+//Fields of the one-shot stats response used to compute usage
+#[derive(Deserialize, Debug, Default)]
+pub struct StatsResponse {
+    #[serde(default)]
+    pub cpu_stats: CpuStats,
+    #[serde(default)]
+    pub precpu_stats: CpuStats,
+    #[serde(default)]
+    pub memory_stats: MemoryStats,
+    #[serde(default)]
+    pub pids_stats: PidsStats,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CpuStats {
+    #[serde(default)]
+    pub cpu_usage: CpuUsage,
+    #[serde(default)]
+    pub system_cpu_usage: u64,
+    #[serde(default)]
+    pub online_cpus: u32,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CpuUsage {
+    #[serde(default)]
+    pub total_usage: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct MemoryStats {
+    #[serde(default)]
+    pub usage: u64,
+    #[serde(default)]
+    pub limit: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PidsStats {
+    #[serde(default)]
+    pub current: u64,
+}
+
+impl StatsResponse {
+    //Same formula as `docker stats`: share of total host cpu time, scaled by cpu count
+    pub fn cpu_percent(&self) -> f64 {
+        let cpu_delta = self.cpu_stats.cpu_usage.total_usage.saturating_sub(self.precpu_stats.cpu_usage.total_usage) as f64;
+        let system_delta = self.cpu_stats.system_cpu_usage.saturating_sub(self.precpu_stats.system_cpu_usage) as f64;
+        if cpu_delta <= 0.0 || system_delta <= 0.0 {
+            return 0.0;
+        }
+        cpu_delta / system_delta * self.cpu_stats.online_cpus.max(1) as f64 * 100.0
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct IdResponse {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ExecInspectResponse {
+    #[serde(default)]
+    exit_code: Option<i64>,
+}
+
+//Splits an attach/exec/logs stream into stdout and stderr. Without a tty every
+//frame has an 8 byte header: stream type, 3 padding bytes, big endian payload size.
+pub fn demux(bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut i = 0;
+    while i + 8 <= bytes.len() {
+        let size = u32::from_be_bytes([bytes[i + 4], bytes[i + 5], bytes[i + 6], bytes[i + 7]]) as usize;
+        let start = i + 8;
+        let end = (start + size).min(bytes.len());
+        match bytes[i] {
+            2 => stderr.extend_from_slice(&bytes[start..end]),
+            _ => stdout.extend_from_slice(&bytes[start..end]),
+        }
+        i = end;
+    }
+    (stdout, stderr)
+}
+
+//Streaming version of `demux` that yields complete lines as frames arrive
+fn demux_lines(body: Body) -> BoxStream<'static, Result<String, ApiError>> {
+    struct State {
+        body: Body,
+        raw: Vec<u8>,
+        partial: String,
+        lines: VecDeque<String>,
+        done: bool,
+    }
+    let state = State { body, raw: Vec::new(), partial: String::new(), lines: VecDeque::new(), done: false };
+
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(line) = state.lines.pop_front() {
+                return Some((Ok(line), state));
+            }
+            if state.done {
+                if state.partial.is_empty() {
+                    return None;
+                }
+                let line = std::mem::take(&mut state.partial);
+                return Some((Ok(line), state));
+            }
+            match state.body.next().await {
+                Some(Ok(chunk)) => {
+                    state.raw.extend_from_slice(&chunk);
+                    while state.raw.len() >= 8 {
+                        let size = u32::from_be_bytes([state.raw[4], state.raw[5], state.raw[6], state.raw[7]]) as usize;
+                        if state.raw.len() < 8 + size {
+                            break;
+                        }
+                        let frame: Vec<u8> = state.raw.drain(..8 + size).skip(8).collect();
+                        state.partial.push_str(&String::from_utf8_lossy(&frame));
+                        while let Some(newline) = state.partial.find('\n') {
+                            let line: String = state.partial.drain(..=newline).collect();
+                            state.lines.push_back(line.trim_end_matches(['\r', '\n']).to_string());
+                        }
+                    }
+                },
+                Some(Err(e)) => {
+                    state.done = true;
+                    return Some((Err(ApiError::transport(e)), state));
+                },
+                None => state.done = true,
+            }
+        }
+    }).boxed()
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct SizeResponse {
+    #[serde(default)]
+    size_rw: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct HostPortBinding {
+    pub host_port: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct CreateContainerResponse {
+    pub id: String,
+    #[serde(default)]
+    pub warnings: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct VersionResponse {
+    pub version: String,
+    #[serde(default)]
+    pub api_version: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct VolumeListResponse {
+    #[serde(default)]
+    volumes: Option<Vec<VolumeResponse>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct VolumeResponse {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkResponse {
+    #[serde(rename = "IPAM", default)]
+    ipam: Option<IpamResponse>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct IpamResponse {
+    #[serde(default)]
+    config: Option<Vec<IpamConfig>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct IpamConfig {
+    #[serde(default)]
+    gateway: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    #[serde(alias = "cause")]
+    message: String,
+}
+
+//Percent-encodes a query parameter value
+fn encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+//Commit request for `tag` as "repo:tag", where a colon before the last slash belongs to a registry port.
+//The secrets agents get in their environment are cleared from the image.
+fn commit_path(id: &str, tag: &str) -> String {
+    let (repo, tag) = match tag.rfind(':') {
+        Some(colon) if !tag[colon..].contains('/') => (&tag[..colon], &tag[colon + 1..]),
+        _ => (tag, "latest"),
+    };
+    let mut path = format!("/commit?container={}&repo={}&tag={}&pause=true", encode(id), encode(repo), encode(tag));
+    for name in auth::SECRET_ENV {
+        path.push_str(&format!("&changes={}", encode(&format!("ENV {}=", name))));
+    }
+    path
+}
+
+//Typed client for the podman/docker compatible REST API over a Unix socket
+pub struct ApiClient {
+    socket: PathBuf,
+    client: Client<UnixConnector>,
+}
+
+impl ApiClient {
+    pub fn new(socket: PathBuf) -> Self {
+        ApiClient { socket, client: Client::unix() }
+    }
+
+    async fn send(&self, method: Method, path: &str, content_type: &str, body: Body) -> Result<Response<Body>, ApiError> {
+        let version = if path.starts_with("/libpod/") { LIBPOD_API_VERSION } else { API_VERSION };
+        let uri: hyper::Uri = hyperlocal::Uri::new(&self.socket, &format!("/{}{}", version, path)).into();
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", content_type)
+            .body(body)
+            .map_err(ApiError::transport)?;
+
+        let response = self.client.request(request).await.map_err(ApiError::transport)?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            let bytes = hyper::body::to_bytes(response.into_body()).await.map_err(ApiError::transport)?;
+            let message = serde_json::from_slice::<ErrorResponse>(&bytes)
+                .map(|e| e.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&bytes).trim().to_string());
+            return Err(ApiError { status: status.as_u16(), message });
+        }
+        Ok(response)
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Option<serde_json::Value>) -> Result<Vec<u8>, ApiError> {
+        let body = match body {
+            Some(json) => Body::from(json.to_string()),
+            None => Body::empty(),
+        };
+        let response = self.send(method, path, "application/json", body).await?;
+        let bytes = hyper::body::to_bytes(response.into_body()).await.map_err(ApiError::transport)?;
+        Ok(bytes.to_vec())
+    }
+
+    async fn json<T: DeserializeOwned>(&self, method: Method, path: &str, body: Option<serde_json::Value>) -> Result<T, ApiError> {
+        let bytes = self.request(method, path, body).await?;
+        serde_json::from_slice(&bytes).map_err(|e| ApiError::transport(format!("Failed to parse response from {}: {}", path, e)))
+    }
+
+    pub async fn ping(&self) -> Result<(), ApiError> {
+        self.request(Method::GET, "/_ping", None).await.map(|_| ())
+    }
+
+    pub async fn version(&self) -> Result<VersionResponse, ApiError> {
+        self.json(Method::GET, "/version", None).await
+    }
+
+    pub async fn create_container(&self, name: &str, request: &CreateContainerRequest) -> Result<CreateContainerResponse, ApiError> {
+        let body = serde_json::to_value(request).map_err(ApiError::transport)?;
+        self.json(Method::POST, &format!("/containers/create?name={}", encode(name)), Some(body)).await
+    }
+
+    //Starting an already running container is a 304, which is treated as success
+    pub async fn start_container(&self, id: &str) -> Result<(), ApiError> {
+        self.request(Method::POST, &format!("/containers/{}/start", encode(id)), None).await.map(|_| ())
+    }
+
+    pub async fn stop_container(&self, id: &str, timeout_secs: u32) -> Result<(), ApiError> {
+        self.request(Method::POST, &format!("/containers/{}/stop?t={}", encode(id), timeout_secs), None).await.map(|_| ())
+    }
+
+    pub async fn pause_container(&self, id: &str) -> Result<(), ApiError> {
+        self.request(Method::POST, &format!("/containers/{}/pause", encode(id)), None).await.map(|_| ())
+    }
+
+    pub async fn unpause_container(&self, id: &str) -> Result<(), ApiError> {
+        self.request(Method::POST, &format!("/containers/{}/unpause", encode(id)), None).await.map(|_| ())
+    }
+
+    pub async fn rename_container(&self, id: &str, name: &str) -> Result<(), ApiError> {
+        self.request(Method::POST, &format!("/containers/{}/rename?name={}", encode(id), encode(name)), None).await.map(|_| ())
+    }
+
+    pub async fn restart_container(&self, id: &str, timeout_secs: u32) -> Result<(), ApiError> {
+        self.request(Method::POST, &format!("/containers/{}/restart?t={}", encode(id), timeout_secs), None).await.map(|_| ())
+    }
+
+    pub async fn inspect_container(&self, id: &str) -> Result<Option<ContainerInspect>, ApiError> {
+        match self.json(Method::GET, &format!("/containers/{}/json", encode(id)), None).await {
+            Ok(inspect) => Ok(Some(inspect)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn remove_container(&self, id: &str, force: bool) -> Result<(), ApiError> {
+        self.request(Method::DELETE, &format!("/containers/{}?force={}", encode(id), force), None).await.map(|_| ())
+    }
+
+    //Creates an exec session, runs it attached and reads its exit code
+    pub async fn exec(&self, id: &str, cmd: &[&str]) -> Result<ExecOutput, ApiError> {
+        let create = serde_json::json!({ "Cmd": cmd, "AttachStdout": true, "AttachStderr": true });
+        let exec: IdResponse = self.json(Method::POST, &format!("/containers/{}/exec", encode(id)), Some(create)).await?;
+
+        let start = serde_json::json!({ "Detach": false, "Tty": false });
+        let output = self.request(Method::POST, &format!("/exec/{}/start", exec.id), Some(start)).await?;
+        let (stdout, stderr) = demux(&output);
+
+        let inspect: ExecInspectResponse = self.json(Method::GET, &format!("/exec/{}/json", exec.id), None).await?;
+        Ok(ExecOutput {
+            exit_code: inspect.exit_code.unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&stdout).to_string(),
+            stderr: String::from_utf8_lossy(&stderr).to_string(),
+        })
+    }
+
+    //Starts an attached exec session and streams its output
+    pub async fn exec_stream(&self, id: &str, cmd: &[&str]) -> Result<BoxStream<'static, Result<String, ApiError>>, ApiError> {
+        let create = serde_json::json!({ "Cmd": cmd, "AttachStdout": true, "AttachStderr": true });
+        let exec: IdResponse = self.json(Method::POST, &format!("/containers/{}/exec", encode(id)), Some(create)).await?;
+
+        let start = serde_json::json!({ "Detach": false, "Tty": false });
+        let response = self.send(Method::POST, &format!("/exec/{}/start", exec.id), "application/json", Body::from(start.to_string())).await?;
+        Ok(demux_lines(response.into_body()))
+    }
+
+    pub async fn logs(&self, id: &str, follow: bool, tail: Option<u32>) -> Result<BoxStream<'static, Result<String, ApiError>>, ApiError> {
+        let tail = tail.map(|n| n.to_string()).unwrap_or_else(|| "all".to_string());
+        let path = format!("/containers/{}/logs?stdout=true&stderr=true&follow={}&tail={}", encode(id), follow, tail);
+        let response = self.send(Method::GET, &path, "application/json", Body::empty()).await?;
+        Ok(demux_lines(response.into_body()))
+    }
+
+    pub async fn update_container(&self, id: &str, resources: &Resources) -> Result<(), ApiError> {
+        let body = serde_json::to_value(resources).map_err(ApiError::transport)?;
+        self.request(Method::POST, &format!("/containers/{}/update", encode(id)), Some(body)).await.map(|_| ())
+    }
+
+    pub async fn stats(&self, id: &str) -> Result<StatsResponse, ApiError> {
+        self.json(Method::GET, &format!("/containers/{}/stats?stream=false", encode(id)), None).await
+    }
+
+    //Size of the container's writable layer
+    pub async fn container_size(&self, id: &str) -> Result<Option<u64>, ApiError> {
+        let size: SizeResponse = self.json(Method::GET, &format!("/containers/{}/json?size=true", encode(id)), None).await?;
+        Ok(size.size_rw)
+    }
+
+    //Image names are routed with their slashes and colon intact, so the tag is not encoded
+    pub async fn image_exists(&self, tag: &str) -> Result<bool, ApiError> {
+        match self.request(Method::GET, &format!("/images/{}/json", tag), None).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    //Creating a volume that already exists returns the existing one
+    pub async fn create_volume(&self, name: &str) -> Result<(), ApiError> {
+        self.request(Method::POST, "/volumes/create", Some(serde_json::json!({ "Name": name }))).await.map(|_| ())
+    }
+
+    pub async fn remove_volume(&self, name: &str) -> Result<(), ApiError> {
+        self.request(Method::DELETE, &format!("/volumes/{}", encode(name)), None).await.map(|_| ())
+    }
+
+    //Gateway of a network's first subnet
+    pub async fn network_gateway(&self, name: &str) -> Result<Option<String>, ApiError> {
+        let network: NetworkResponse = self.json(Method::GET, &format!("/networks/{}", encode(name)), None).await?;
+        Ok(network.ipam
+            .and_then(|ipam| ipam.config)
+            .and_then(|config| config.into_iter().find_map(|c| c.gateway)))
+    }
+
+    pub async fn list_volumes(&self) -> Result<Vec<String>, ApiError> {
+        let list: VolumeListResponse = self.json(Method::GET, "/volumes", None).await?;
+        Ok(list.volumes.unwrap_or_default().into_iter().map(|v| v.name).collect())
+    }
+
+    pub async fn remove_image(&self, tag: &str) -> Result<(), ApiError> {
+        self.request(Method::DELETE, &format!("/images/{}", tag), None).await.map(|_| ())
+    }
+
+    //Returns the id of the new image
+    pub async fn commit_container(&self, id: &str, tag: &str) -> Result<String, ApiError> {
+        let committed: IdResponse = self.json(Method::POST, &commit_path(id, tag), None).await?;
+        Ok(committed.id)
+    }
+
+    //Podman only: the checkpoint archive is the response body and is written to `export` as it arrives
+    pub async fn checkpoint_container(&self, id: &str, export: &Path) -> Result<(), ApiError> {
+        let path = format!("/libpod/containers/{}/checkpoint?export=true&leaveRunning=true&tcpEstablished=true", encode(id));
+        let response = self.send(Method::POST, &path, "application/json", Body::empty()).await?;
+
+        let mut file = tokio::fs::File::create(export).await.map_err(ApiError::transport)?;
+        let mut body = response.into_body();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(ApiError::transport)?;
+            file.write_all(&chunk).await.map_err(ApiError::transport)?;
+        }
+        file.flush().await.map_err(ApiError::transport)
+    }
+
+    //Podman only: uploads a checkpoint archive and recreates the container it was taken from
+    pub async fn restore_container(&self, import: &Path) -> Result<String, ApiError> {
+        let file = tokio::fs::File::open(import).await.map_err(ApiError::transport)?;
+        let chunks = futures::stream::unfold(file, |mut file| async move {
+            let mut buffer = vec![0u8; 64 * 1024];
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buffer.truncate(n);
+                    Some((Ok::<_, std::io::Error>(buffer), file))
+                },
+                Err(e) => Some((Err(e), file)),
+            }
+        });
+
+        let path = "/libpod/containers/import/restore?import=true&tcpClose=true";
+        let response = self.send(Method::POST, path, "application/x-tar", Body::wrap_stream(chunks)).await?;
+        let bytes = hyper::body::to_bytes(response.into_body()).await.map_err(ApiError::transport)?;
+        let restored: IdResponse = serde_json::from_slice(&bytes)
+            .map_err(|e| ApiError::transport(format!("Failed to parse restore response: {}", e)))?;
+        Ok(restored.id)
+    }
+
+    //Builds from a tar of the build context; the build log is scanned for an error line
+    pub async fn build_image(&self, tag: &str, dockerfile: &str, context_tar: Vec<u8>) -> Result<(), ApiError> {
+        let path = format!("/build?t={}&dockerfile={}&rm=true", encode(tag), encode(dockerfile));
+        let response = self.send(Method::POST, &path, "application/x-tar", Body::from(context_tar)).await?;
+        let bytes = hyper::body::to_bytes(response.into_body()).await.map_err(ApiError::transport)?;
+
+        for line in String::from_utf8_lossy(&bytes).lines() {
+            if let Ok(value) = serde_json::from_str::<serde_json::Value>(line) {
+                if let Some(error) = value.get("error").and_then(|v| v.as_str()) {
+                    return Err(ApiError { status: 500, message: error.trim().to_string() });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //Streams container events as they happen
+    pub async fn events(&self) -> Result<BoxStream<'static, Result<ContainerEvent, ApiError>>, ApiError> {
+        let filters = encode(r#"{"type":["container"]}"#);
+        let response = self.send(Method::GET, &format!("/events?filters={}", filters), "application/json", Body::empty()).await?;
+
+        //Events are newline delimited JSON, but chunks don't line up with lines
+        let stream = futures::stream::unfold((response.into_body(), String::new()), |(mut body, mut buffer)| async move {
+            loop {
+                if let Some(newline) = buffer.find('\n') {
+                    let line: String = buffer.drain(..=newline).collect();
+                    return Some((Ok(line), (body, buffer)));
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => buffer.push_str(&String::from_utf8_lossy(&chunk)),
+                    Some(Err(e)) => return Some((Err(ApiError::transport(e)), (body, buffer))),
+                    None => return None,
+                }
+            }
+        });
+
+        Ok(stream
+            .filter_map(|line| async move {
+                match line {
+                    Ok(line) => serde_json::from_str::<serde_json::Value>(line.trim()).ok()
+                        .and_then(|value| ContainerEvent::from_json(&value))
+                        .map(Ok),
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .boxed())
+    }
+}
+
+//Finds the engine's API socket: explicit env config first, then the usual per-platform locations
+pub async fn discover_socket(kind: RuntimeKind, binary: Option<&Path>) -> Option<PathBuf> {
+    let env_var = match kind {
+        RuntimeKind::Podman => "CONTAINER_HOST",
+        RuntimeKind::Docker => "DOCKER_HOST",
+    };
+    if let Ok(host) = std::env::var(env_var) {
+        if let Some(path) = host.strip_prefix("unix://") {
+            return Some(PathBuf::from(path));
+        }
+    }
+
+    let mut candidates = Vec::new();
+    match kind {
+        RuntimeKind::Podman => {
+            if let Some(socket) = podman_machine_socket(binary).await {
+                candidates.push(socket);
+            }
+            if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
+                candidates.push(PathBuf::from(runtime_dir).join("podman/podman.sock"));
+            }
+            candidates.push(PathBuf::from("/run/podman/podman.sock"));
+        },
+        RuntimeKind::Docker => {
+            if let Ok(home) = std::env::var("HOME") {
+                candidates.push(PathBuf::from(home).join(".docker/run/docker.sock"));
+            }
+            candidates.push(PathBuf::from("/var/run/docker.sock"));
+        },
+    }
+    candidates.into_iter().find(|socket| socket.exists())
+}
+
+//On macOS/Windows the API lives inside the podman machine and is forwarded to a host socket
+#[cfg(not(target_os = "linux"))]
+async fn podman_machine_socket(binary: Option<&Path>) -> Option<PathBuf> {
+    let output = tokio::process::Command::new(binary?)
+        .args(["machine", "inspect", crate::launch_podman::PODMAN_NAME, "--format", "{{.ConnectionInfo.PodmanSocket.Path}}"])
+        .output()
+        .await
+        .ok()?;
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !path.is_empty()).then(|| PathBuf::from(path))
+}
+
+#[cfg(target_os = "linux")]
+async fn podman_machine_socket(_binary: Option<&Path>) -> Option<PathBuf> {
+    None
+}
+
+//Tars the build context directory (the folder holding the Dockerfile) off the async runtime
+async fn tar_context(context: PathBuf) -> Result<Vec<u8>, String> {
+    tokio::task::spawn_blocking(move || {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.follow_symlinks(true);
+        builder.append_dir_all(".", &context)
+            .map_err(|e| format!("Failed to archive build context {}: {}", context.display(), e))?;
+        builder.into_inner().map_err(|e| format!("Failed to archive build context: {}", e))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+//ContainerRuntime backed by the REST API
+pub struct ApiRuntime {
+    kind: RuntimeKind,
+    client: ApiClient,
+}
+
+impl ApiRuntime {
+    //Connects and pings the socket so an unusable socket is detected at startup
+    pub async fn connect(kind: RuntimeKind, socket: PathBuf) -> Result<Self, ApiError> {
+        let client = ApiClient::new(socket);
+        client.ping().await?;
+        Ok(ApiRuntime { kind, client })
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for ApiRuntime {
+    fn kind(&self) -> RuntimeKind {
+        self.kind
+    }
+
+    fn host_gateway(&self) -> &'static str {
+        match self.kind {
+            RuntimeKind::Podman => "host.containers.internal",
+            RuntimeKind::Docker => "host.docker.internal",
+        }
+    }
+
+    async fn bridge_gateway(&self) -> Option<IpAddr> {
+        let network = match self.kind {
+            RuntimeKind::Podman => "podman",
+            RuntimeKind::Docker => "bridge",
+        };
+        self.client.network_gateway(network).await.ok()??.parse().ok()
+    }
+
+    async fn version(&self) -> Result<String, String> {
+        let version = self.client.version().await?;
+        Ok(format!("{} {} (API {})", self.kind.binary_name(), version.version, version.api_version))
+    }
+
+    async fn container_exists(&self, name: &str) -> Result<bool, String> {
+        Ok(self.client.inspect_container(name).await?.is_some())
+    }
+
+    async fn image_exists(&self, tag: &str) -> Result<bool, String> {
+        Ok(self.client.image_exists(tag).await?)
+    }
+
+    async fn build_image(&self, tag: &str, dockerfile: &Path) -> Result<(), String> {
+        let context = dockerfile.parent().ok_or("Dockerfile has no parent directory")?.to_path_buf();
+        let dockerfile_name = dockerfile.file_name().ok_or("Dockerfile has no file name")?.to_string_lossy().to_string();
+        info!("Building {} through the API", tag);
+        let context_tar = tar_context(context).await?;
+        self.client.build_image(tag, &dockerfile_name, context_tar).await
+            .map_err(|e| format!("Build failed: {}", e))
+    }
+
+    async fn create_volume(&self, name: &str) -> Result<(), String> {
+        Ok(self.client.create_volume(name).await?)
+    }
+
+    async fn remove_volume(&self, name: &str) -> Result<(), String> {
+        match self.client.remove_volume(name).await {
+            Err(e) if e.is_not_found() => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    async fn list_volumes(&self) -> Result<Vec<String>, String> {
+        Ok(self.client.list_volumes().await?)
+    }
+
+    async fn remove_image(&self, tag: &str) -> Result<(), String> {
+        match self.client.remove_image(tag).await {
+            Err(e) if e.is_not_found() => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    async fn commit_container(&self, id: &str, tag: &str) -> Result<(), String> {
+        let image_id = self.client.commit_container(id, tag).await?;
+        info!("Committed container {} as {} ({})", id, tag, image_id);
+        Ok(())
+    }
+
+    async fn checkpoint_container(&self, id: &str, export: &Path) -> Result<(), String> {
+        if self.kind != RuntimeKind::Podman {
+            return Err("Checkpoints are only supported with podman".to_string());
+        }
+        Ok(self.client.checkpoint_container(id, export).await?)
+    }
+
+    async fn restore_container(&self, import: &Path) -> Result<String, String> {
+        if self.kind != RuntimeKind::Podman {
+            return Err("Checkpoints are only supported with podman".to_string());
+        }
+        Ok(self.client.restore_container(import).await?)
+    }
+
+    async fn run_container(&self, spec: &RunSpec) -> Result<String, String> {
+        let mut request = CreateContainerRequest {
+            image: spec.image.clone(),
+            env: spec.env.iter().map(|(key, value)| format!("{}={}", key, value)).collect(),
+            ..Default::default()
+        };
+        request.host_config.network_mode = "bridge".to_string();
+        request.host_config.binds = spec.mounts.iter().map(|m| m.bind()).collect();
+        request.host_config.resources = Resources::from(&spec.limits);
+        if let Some(storage_gb) = spec.limits.storage_gb {
+            request.host_config.storage_opt.insert("size".to_string(), format!("{}G", storage_gb));
+        }
+        if self.kind == RuntimeKind::Docker {
+            request.host_config.extra_hosts.push("host.docker.internal:host-gateway".to_string());
+        }
+        for (host, container) in &spec.ports {
+            let port = format!("{}/tcp", container);
+            request.exposed_ports.insert(port.clone(), HashMap::new());
+            request.host_config.port_bindings.insert(port, vec![HostPortBinding { host_port: host.to_string() }]);
+        }
+
+        let created = self.client.create_container(&spec.name, &request).await?;
+        for warning in created.warnings.iter().flatten() {
+            info!("Warning creating {}: {}", spec.name, warning);
+        }
+        if let Err(e) = self.client.start_container(&created.id).await {
+            error!("Failed to start {}: {}", spec.name, e);
+            let _ = self.client.remove_container(&created.id, true).await;
+            return Err(e.into());
+        }
+        Ok(created.id)
+    }
+
+    async fn start_container(&self, id: &str) -> Result<(), String> {
+        Ok(self.client.start_container(id).await?)
+    }
+
+    async fn stop_container(&self, id: &str) -> Result<(), String> {
+        Ok(self.client.stop_container(id, 10).await?)
+    }
+
+    async fn pause_container(&self, id: &str) -> Result<(), String> {
+        Ok(self.client.pause_container(id).await?)
+    }
+
+    async fn unpause_container(&self, id: &str) -> Result<(), String> {
+        Ok(self.client.unpause_container(id).await?)
+    }
+
+    async fn restart_container(&self, id: &str) -> Result<(), String> {
+        Ok(self.client.restart_container(id, 10).await?)
+    }
+
+    async fn rename_container(&self, id: &str, name: &str) -> Result<(), String> {
+        Ok(self.client.rename_container(id, name).await?)
+    }
+
+    async fn inspect_container(&self, id: &str) -> Result<Option<ContainerInspect>, String> {
+        Ok(self.client.inspect_container(id).await?)
+    }
+
+    async fn remove_container(&self, id: &str) -> Result<(), String> {
+        match self.client.remove_container(id, true).await {
+            Err(e) if e.is_not_found() => Ok(()),
+            result => result.map_err(|e| format!("Failed to delete container: {}", e)),
+        }
+    }
+
+    async fn exec(&self, id: &str, cmd: &[&str]) -> Result<ExecOutput, String> {
+        Ok(self.client.exec(id, cmd).await?)
+    }
+
+    async fn exec_stream(&self, id: &str, cmd: &[&str]) -> Result<BoxStream<'static, Result<String, String>>, String> {
+        Ok(self.client.exec_stream(id, cmd).await?.map(|line| line.map_err(String::from)).boxed())
+    }
+
+    async fn logs(&self, id: &str, follow: bool, tail: Option<u32>) -> Result<BoxStream<'static, Result<String, String>>, String> {
+        Ok(self.client.logs(id, follow, tail).await?.map(|line| line.map_err(String::from)).boxed())
+    }
+
+    async fn update_limits(&self, id: &str, limits: &ResourceLimits) -> Result<(), String> {
+        Ok(self.client.update_container(id, &Resources::from(limits)).await?)
+    }
+
+    async fn usage(&self, id: &str) -> Result<ResourceUsage, String> {
+        let stats = self.client.stats(id).await?;
+        let disk_bytes = self.client.container_size(id).await.ok().flatten();
+        Ok(ResourceUsage {
+            cpu_percent: stats.cpu_percent(),
+            memory_bytes: stats.memory_stats.usage,
+            memory_limit_bytes: stats.memory_stats.limit,
+            pids: stats.pids_stats.current,
+            disk_bytes,
+        })
+    }
+
+    async fn events(&self) -> Result<BoxStream<'static, Result<ContainerEvent, String>>, String> {
+        Ok(self.client.events().await?.map(|event| event.map_err(String::from)).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //One multiplexed stream frame
+    fn frame(stream: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![stream, 0, 0, 0];
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn demux_splits_stdout_and_stderr() {
+        let mut bytes = frame(1, b"out 1\n");
+        bytes.extend(frame(2, b"err\n"));
+        bytes.extend(frame(1, b"out 2\n"));
+        //Frame cut off by the end of the response, keeps what arrived
+        bytes.extend(&frame(2, b"partial error")[..12]);
+
+        let (stdout, stderr) = demux(&bytes);
+        assert_eq!(stdout, b"out 1\nout 2\n");
+        assert_eq!(stderr, b"err\npart");
+    }
+
+    #[test]
+    fn demux_ignores_a_truncated_header() {
+        let mut bytes = frame(1, b"done");
+        bytes.extend_from_slice(&[2, 0, 0]);
+        assert_eq!(demux(&bytes), (b"done".to_vec(), Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn demux_lines_joins_frames_split_across_chunks() {
+        let mut bytes = frame(1, b"first li");
+        bytes.extend(frame(2, b"ne\r\nsecond\nthi"));
+        bytes.extend(frame(1, b"rd"));
+        //Chunk boundaries fall inside a header and inside a payload
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![
+            Ok(bytes[..5].to_vec()),
+            Ok(bytes[5..20].to_vec()),
+            Ok(bytes[20..].to_vec()),
+        ];
+
+        let lines: Vec<String> = demux_lines(Body::wrap_stream(futures::stream::iter(chunks)))
+            .map(|line| line.unwrap())
+            .collect()
+            .await;
+        assert_eq!(lines, vec!["first line", "second", "third"]);
+    }
+
+    #[test]
+    fn encode_escapes_everything_but_unreserved_characters() {
+        assert_eq!(encode("radah-agent_1.0~x"), "radah-agent_1.0~x");
+        assert_eq!(encode("a b/c:d&e=f"), "a%20b%2Fc%3Ad%26e%3Df");
+        assert_eq!(encode(r#"{"type":["container"]}"#), "%7B%22type%22%3A%5B%22container%22%5D%7D");
+        assert_eq!(encode("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn commit_path_splits_the_tag() {
+        assert_eq!(
+            commit_path("abc123", "radah-snapshot-pam-1:before-upgrade"),
+            "/commit?container=abc123&repo=radah-snapshot-pam-1&tag=before-upgrade&pause=true\
+             &changes=ENV%20ANTHROPIC_API_KEY%3D&changes=ENV%20RADAH_WS_TOKEN%3D",
+        );
+        assert!(commit_path("abc123", "radah-clone-pam-2")
+            .starts_with("/commit?container=abc123&repo=radah-clone-pam-2&tag=latest&pause=true&"));
+        //The colon belongs to the registry port, not the tag
+        assert!(commit_path("abc123", "localhost:5000/radah-agent")
+            .starts_with("/commit?container=abc123&repo=localhost%3A5000%2Fradah-agent&tag=latest&pause=true&"));
+    }
+
+    #[test]
+    fn parses_the_image_id_from_a_commit_response() {
+        let docker: IdResponse = serde_json::from_slice(br#"{"Id":"sha256:4f2c9a"}"#).unwrap();
+        assert_eq!(docker.id, "sha256:4f2c9a");
+        let podman: IdResponse = serde_json::from_slice(br#"{"Id":"4f2c9a"}"#).unwrap();
+        assert_eq!(podman.id, "4f2c9a");
+    }
+}
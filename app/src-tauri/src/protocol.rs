@@ -0,0 +1,280 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::AgentFile;
+use crate::run_state::AgentRunState;
+
+//Version of the messages below, sent as "protocol_version" with every message.
+//Bump it for changes older peers can't read; messages without it are version 1.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionType {
+    Agent,
+    Client,
+}
+
+//First message on every connection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Init {
+    #[serde(rename = "connection-type")]
+    pub connection_type: ConnectionType,
+    //Agents identify themselves with their agent id, sent as container_id by app.py
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_running: Option<AgentRunState>,
+    //Agents a frontend client wants updates for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscribe: Vec<String>,
+    //Agents get theirs from their environment, the frontend from the get_websocket_token command
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+    //Session an agent is resuming after a reconnect and the last seq it got from the app, see delivery.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seq: Option<u64>,
+    //Agents that read binary frames get messages in those instead of JSON text chunks
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub binary_frames: bool,
+}
+
+//Output of an agent's run, stored as part of its conversation
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AgentMessage {
+    //message_id and agent_id are set by the app, not the agent
+    #[serde(default)]
+    pub message_id: String,
+    #[serde(default)]
+    pub agent_id: String,
+    //Entry of the model conversation, replayed to the agent as history
+    #[serde(rename = "agent-message", default, skip_serializing_if = "Option::is_none")]
+    pub agent_message: Option<Value>,
+    //Content block or tool result shown in the UI
+    #[serde(rename = "agent-output", default, skip_serializing_if = "Option::is_none")]
+    pub agent_output: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub show_ui: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub error: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub end_message: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_running: Option<AgentRunState>,
+}
+
+//File uploaded with a prompt, data is a base64 data: URL
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadedFile {
+    pub name: String,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Prompt {
+    #[serde(default)]
+    pub message_id: String,
+    pub agent_id: String,
+    pub text: String,
+    #[serde(default)]
+    pub show_ui: bool,
+    //Sent to the agent but stored separately as attachments
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<UploadedFile>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Stop {
+    #[serde(default)]
+    pub message_id: String,
+    pub agent_id: String,
+    #[serde(default)]
+    pub show_ui: bool,
+}
+
+//A prompt waiting for its agent to finish the one before it, as shown in the UI
+#[derive(Serialize, Clone, Debug)]
+pub struct QueuedPrompt {
+    pub id: String,
+    pub text: String,
+    pub file_names: Vec<String>,
+    pub queued_at: String,
+}
+
+//A conversation entry, as stored and as returned by get_agent_messages
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "message-type", rename_all = "kebab-case")]
+pub enum ChatMessage {
+    Message(AgentMessage),
+    Prompt(Prompt),
+    Stop(Stop),
+}
+
+impl ChatMessage {
+    pub fn agent_id(&self) -> &str {
+        match self {
+            ChatMessage::Message(m) => &m.agent_id,
+            ChatMessage::Prompt(m) => &m.agent_id,
+            ChatMessage::Stop(m) => &m.agent_id,
+        }
+    }
+
+    pub fn set_ids(&mut self, message_id: &str, agent_id: &str) {
+        let (id, agent) = match self {
+            ChatMessage::Message(m) => (&mut m.message_id, &mut m.agent_id),
+            ChatMessage::Prompt(m) => (&mut m.message_id, &mut m.agent_id),
+            ChatMessage::Stop(m) => (&mut m.message_id, &mut m.agent_id),
+        };
+        *id = message_id.to_string();
+        *agent = agent_id.to_string();
+    }
+}
+
+//Anything the frontend or an agent sends to the app
+#[derive(Deserialize, Debug)]
+#[serde(tag = "message-type", rename_all = "kebab-case")]
+pub enum Inbound {
+    Init(Init),
+    //From agents
+    Message(AgentMessage),
+    FileList { request_id: String, files: Vec<AgentFile> },
+    FileChunk { request_id: String, chunk: usize, total_chunks: usize, data: String },
+    FileError { request_id: String, error: String },
+    //The agent handled the app's messages up to seq
+    Ack { seq: u64 },
+    //From the frontend
+    Prompt(Prompt),
+    Stop(Stop),
+    //Start or stop receiving an agent's messages and status
+    Subscribe { agent_ids: Vec<String> },
+    Unsubscribe { agent_ids: Vec<String> },
+}
+
+impl Inbound {
+    //Who may send the message, None for init which every connection starts with
+    pub fn sender(&self) -> Option<ConnectionType> {
+        match self {
+            Inbound::Init(_) => None,
+            Inbound::Message(_) | Inbound::FileList { .. } | Inbound::FileChunk { .. } | Inbound::FileError { .. } | Inbound::Ack { .. } => Some(ConnectionType::Agent),
+            Inbound::Prompt(_) | Inbound::Stop(_) | Inbound::Subscribe { .. } | Inbound::Unsubscribe { .. } => Some(ConnectionType::Client),
+        }
+    }
+}
+
+//Prompt as forwarded to the agent, with the context it needs to run
+#[derive(Serialize, Clone, Debug)]
+pub struct AgentPrompt {
+    #[serde(flatten)]
+    pub prompt: Prompt,
+    #[serde(rename = "recent-messages")]
+    pub recent_messages: Vec<Value>,
+    pub additional_system_prompt: String,
+}
+
+//Requests to an agent, sent in chunks
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "message-type", rename_all = "kebab-case")]
+pub enum ToAgent {
+    Prompt(AgentPrompt),
+    Stop(Stop),
+    ListFiles { request_id: String, path: String },
+    FetchFile { request_id: String, path: String },
+}
+
+//One piece of a message chunked as text, for agents that don't read binary frames
+#[derive(Serialize, Clone, Debug)]
+pub struct Chunk<'a> {
+    pub message_id: &'a str,
+    pub chunk: usize,
+    pub total_chunks: usize,
+    pub data: &'a str,
+}
+
+//Messages from the app itself
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "message-type", rename_all = "kebab-case")]
+pub enum ServerMessage {
+    //An agent connected, disconnected or changed run state
+    Status { agent_id: String, prompt_running: AgentRunState },
+    //The agent's queued prompts changed, next first
+    Queue { agent_id: String, prompts: Vec<QueuedPrompt> },
+    //Reply to a message that couldn't be handled
+    Error {
+        error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        agent_id: Option<String>,
+    },
+    //Reply to an agent's init with the session to use and the last seq the app got from the agent
+    Session { session_id: String, last_seq: u64 },
+    //The app handled the agent's messages up to seq
+    Ack { seq: u64 },
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Outbound {
+    Chat(ChatMessage),
+    Server(ServerMessage),
+}
+
+impl Outbound {
+    //Agent the message is about, None for messages meant for every client
+    pub fn agent_id(&self) -> Option<&str> {
+        match self {
+            Outbound::Chat(message) => Some(message.agent_id()),
+            Outbound::Server(ServerMessage::Status { agent_id, .. }) => Some(agent_id),
+            Outbound::Server(ServerMessage::Queue { agent_id, .. }) => Some(agent_id),
+            Outbound::Server(ServerMessage::Error { agent_id, .. }) => agent_id.as_deref(),
+            Outbound::Server(ServerMessage::Session { .. }) | Outbound::Server(ServerMessage::Ack { .. }) => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T: Serialize> {
+    protocol_version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(flatten)]
+    message: &'a T,
+}
+
+//JSON text of a message with the protocol version added
+pub fn encode<T: Serialize>(message: &T) -> String {
+    encode_sequenced(message, None)
+}
+
+//Same as encode, numbered for an agent with a session
+pub fn encode_sequenced<T: Serialize>(message: &T, seq: Option<u64>) -> String {
+    serde_json::to_string(&Envelope { protocol_version: PROTOCOL_VERSION, seq, message }).unwrap_or_default()
+}
+
+//The message and its seq, which agents with a session put on everything but init and acks
+pub fn decode(text: &str) -> Result<(Option<u64>, Inbound), String> {
+    let json: Value = serde_json::from_str(text).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let version = match json.get("protocol_version") {
+        Some(version) => version.as_u64().ok_or("Invalid protocol_version")?,
+        None => 1,
+    };
+    if version > PROTOCOL_VERSION {
+        return Err(format!("Unsupported protocol version {}, the app speaks version {}", version, PROTOCOL_VERSION));
+    }
+    let seq = json.get("seq").and_then(|seq| seq.as_u64());
+    let message = serde_json::from_value(json).map_err(|e| format!("Invalid message: {}", e))?;
+    Ok((seq, message))
+}
+
+pub fn error(error: impl Into<String>, agent_id: Option<&str>) -> Outbound {
+    Outbound::Server(ServerMessage::Error {
+        error: error.into(),
+        agent_id: agent_id.map(str::to_string),
+    })
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use log::error;
+
+use crate::db;
+
+//What an agent is doing, sent to the frontend and by app.py as "prompt_running"
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentRunState {
+    //Connected and waiting for a prompt ("stopped" from agents on older images)
+    #[default]
+    #[serde(alias = "stopped")]
+    Idle,
+    //A prompt was accepted but hasn't reached the agent yet, e.g. while the agent is woken
+    #[serde(alias = "loading")]
+    Queued,
+    Running,
+    //Asked to stop, the agent hasn't finished yet
+    Stopping,
+    //Not connected to the app
+    #[serde(alias = "na")]
+    Disconnected,
+    //The last prompt failed, a new one can be sent
+    Errored,
+}
+
+impl AgentRunState {
+    //A prompt is on its way or being worked on
+    pub fn is_busy(self) -> bool {
+        matches!(self, AgentRunState::Queued | AgentRunState::Running | AgentRunState::Stopping)
+    }
+
+    //Transitions requested by the frontend or reported by the agent. Connecting and disconnecting
+    //aren't requests and go through `set` instead.
+    fn can_become(self, next: AgentRunState) -> bool {
+        use AgentRunState::*;
+        match (self, next) {
+            (from, to) if from == to => true,
+            (_, Disconnected) => true,
+            (Idle | Errored | Disconnected, Queued) => true,
+            (Idle | Queued | Errored, Running) => true,
+            (Running, Stopping) => true,
+            (Queued | Running | Stopping | Errored, Idle) => true,
+            (Queued | Running | Stopping, Errored) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for AgentRunState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = serde_json::to_value(self).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+//State of each agent this launch, agents that haven't connected are missing
+static STATES: Lazy<Mutex<HashMap<String, AgentRunState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn get(agent_id: &str) -> AgentRunState {
+    STATES.lock().unwrap().get(agent_id).copied().unwrap_or(AgentRunState::Disconnected)
+}
+
+//Moves an agent to `next`, refusing transitions that would leave the app and the agent disagreeing
+//(e.g. a second prompt while one is running). Returns the previous state.
+pub fn transition(agent_id: &str, next: AgentRunState) -> Result<AgentRunState, String> {
+    let mut states = STATES.lock().unwrap();
+    let previous = states.get(agent_id).copied().unwrap_or(AgentRunState::Disconnected);
+    check(agent_id, previous, next)?;
+    states.insert(agent_id.to_string(), next);
+    drop(states);
+    save(agent_id, next);
+    Ok(previous)
+}
+
+//Whether `transition` to `next` would currently be allowed, for requests that must reach the agent first
+pub fn can_transition(agent_id: &str, next: AgentRunState) -> Result<(), String> {
+    check(agent_id, get(agent_id), next)
+}
+
+fn check(agent_id: &str, previous: AgentRunState, next: AgentRunState) -> Result<(), String> {
+    if !previous.can_become(next) {
+        return Err(format!("Agent {} is {} and can't become {}", agent_id, previous, next));
+    }
+    Ok(())
+}
+
+//Sets the state an agent reported when it connected, or Disconnected when it went away
+pub fn set(agent_id: &str, state: AgentRunState) {
+    STATES.lock().unwrap().insert(agent_id.to_string(), state);
+    save(agent_id, state);
+}
+
+//State the agent was last seen in, kept across disconnects and restarts of the app
+pub fn last_known(agent_id: &str) -> AgentRunState {
+    match db::load_run_state(agent_id) {
+        Ok(state) => state.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to load run state of agent {}: {}", agent_id, e);
+            AgentRunState::default()
+        }
+    }
+}
+
+//Disconnected isn't saved, it says nothing about what the agent was doing
+fn save(agent_id: &str, state: AgentRunState) {
+    if state == AgentRunState::Disconnected {
+        return;
+    }
+    if let Err(e) = db::save_run_state(agent_id, state) {
+        error!("Failed to save run state of agent {}: {}", agent_id, e);
+    }
+}
+
+pub fn forget(agent_id: &str) {
+    STATES.lock().unwrap().remove(agent_id);
+}
@@ -0,0 +1,963 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use log::{info, error};
+
+use crate::auth;
+use crate::podman_api::{self, ApiRuntime};
+
+//Which container engine the app is talking to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeKind {
+    Podman,
+    Docker,
+}
+
+impl RuntimeKind {
+    pub fn binary_name(&self) -> &'static str {
+        match self {
+            RuntimeKind::Podman => "podman",
+            RuntimeKind::Docker => "docker",
+        }
+    }
+}
+
+//Everything needed to launch an agent container
+#[derive(Clone, Debug, Default)]
+pub struct RunSpec {
+    pub name: String,
+    pub image: String,
+    pub env: Vec<(String, String)>,
+    //(host port, container port)
+    pub ports: Vec<(u16, u16)>,
+    pub limits: ResourceLimits,
+    pub mounts: Vec<MountSpec>,
+}
+
+//A named volume or host directory mounted into a container
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountSpec {
+    //Volume name, or an absolute host path for bind mounts
+    pub source: String,
+    pub target: String,
+    pub read_only: bool,
+}
+
+impl MountSpec {
+    //"source:target[:ro]", understood by `-v` and by the API's Binds
+    pub fn bind(&self) -> String {
+        if self.read_only {
+            format!("{}:{}:ro", self.source, self.target)
+        } else {
+            format!("{}:{}", self.source, self.target)
+        }
+    }
+}
+
+//Per-agent resource caps, None means no limit
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    pub cpus: Option<f64>,
+    pub memory_mb: Option<u64>,
+    pub pids: Option<i64>,
+    //Writable layer size, only enforced on storage drivers that support quotas
+    pub storage_gb: Option<u64>,
+}
+
+impl ResourceLimits {
+    //Limits for new agents when none are given, sized so 6-10 agents fit on one workstation
+    pub fn agent_default() -> Self {
+        ResourceLimits {
+            cpus: Some(2.0),
+            memory_mb: Some(4096),
+            pids: Some(2048),
+            storage_gb: None,
+        }
+    }
+
+    //Flags shared by `run` and `update`; storage is left out since it can only be set at creation
+    fn cli_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(cpus) = self.cpus {
+            args.push(format!("--cpus={}", cpus));
+        }
+        if let Some(memory_mb) = self.memory_mb {
+            //Swap equal to memory keeps the cap from being bypassed through swap
+            args.push(format!("--memory={}m", memory_mb));
+            args.push(format!("--memory-swap={}m", memory_mb));
+        }
+        if let Some(pids) = self.pids {
+            args.push(format!("--pids-limit={}", pids));
+        }
+        args
+    }
+}
+
+//Observed usage of a running container
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ResourceUsage {
+    pub cpu_percent: f64,
+    pub memory_bytes: u64,
+    pub memory_limit_bytes: u64,
+    pub pids: u64,
+    //Size of the writable layer, when the engine reports it
+    pub disk_bytes: Option<u64>,
+}
+
+//Subset of `inspect` output we use, shared by the CLI and the REST API
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerInspect {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub state: ContainerStateInfo,
+    #[serde(default)]
+    pub network_settings: Option<NetworkSettings>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerStateInfo {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub running: bool,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub exit_code: i64,
+    #[serde(default)]
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct NetworkSettings {
+    //"5900/tcp" -> host bindings
+    #[serde(default)]
+    pub ports: HashMap<String, Option<Vec<PortBinding>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PortBinding {
+    #[serde(default)]
+    pub host_ip: String,
+    #[serde(default)]
+    pub host_port: String,
+}
+
+impl ContainerInspect {
+    //Host port published for a container tcp port
+    pub fn host_port(&self, container_port: u16) -> Option<u16> {
+        self.network_settings.as_ref()?
+            .ports.get(&format!("{}/tcp", container_port))?
+            .as_ref()?
+            .iter()
+            .find_map(|binding| binding.host_port.parse().ok())
+    }
+}
+
+//Result of running a command inside a container
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+//Container lifecycle event (start, die, oom, ...) reported by the engine
+#[derive(Serialize, Clone, Debug)]
+pub struct ContainerEvent {
+    pub id: String,
+    pub name: String,
+    pub action: String,
+    pub time: i64,
+}
+
+impl ContainerEvent {
+    //Accepts both the docker/compat event format and podman's native `events --format json`
+    pub fn from_json(value: &serde_json::Value) -> Option<ContainerEvent> {
+        if let Some(kind) = value.get("Type").and_then(|v| v.as_str()) {
+            if kind != "container" {
+                return None;
+            }
+        }
+        let actor = value.get("Actor");
+        let id = actor.and_then(|a| a.get("ID"))
+            .or_else(|| value.get("ID"))
+            .or_else(|| value.get("id"))
+            .and_then(|v| v.as_str())?;
+        let name = actor.and_then(|a| a["Attributes"].get("name"))
+            .or_else(|| value.get("Name"))
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let action = value.get("Action")
+            .or_else(|| value.get("Status"))
+            .or_else(|| value.get("status"))
+            .and_then(|v| v.as_str())?;
+        let time = value.get("time").and_then(|v| v.as_i64()).unwrap_or_default();
+
+        Some(ContainerEvent {
+            id: id.to_string(),
+            name: name.to_string(),
+            action: action.to_string(),
+            time,
+        })
+    }
+}
+
+//Operations the agent lifecycle needs from a container engine.
+//Every command goes through this so podman, docker (or a fake in tests) are interchangeable.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    fn kind(&self) -> RuntimeKind;
+
+    //Hostname a container uses to reach the websocket server on the host
+    fn host_gateway(&self) -> &'static str;
+
+    //Host side address of the default bridge network, which the websocket server listens on next to
+    //loopback. None when the engine runs in a VM or a rootless namespace, where loopback is forwarded.
+    async fn bridge_gateway(&self) -> Option<IpAddr>;
+
+    async fn version(&self) -> Result<String, String>;
+
+    async fn container_exists(&self, name: &str) -> Result<bool, String>;
+
+    async fn image_exists(&self, tag: &str) -> Result<bool, String>;
+
+    async fn build_image(&self, tag: &str, dockerfile: &Path) -> Result<(), String>;
+
+    //Creates a named volume unless it already exists
+    async fn create_volume(&self, name: &str) -> Result<(), String>;
+
+    //Removes a named volume, succeeding if it is already gone. Fails while a container uses it.
+    async fn remove_volume(&self, name: &str) -> Result<(), String>;
+
+    async fn list_volumes(&self) -> Result<Vec<String>, String>;
+
+    //Removes an image, succeeding if it is already gone. Fails while a container still uses it.
+    async fn remove_image(&self, tag: &str) -> Result<(), String>;
+
+    //Saves the container's filesystem as a new image, pausing it while the layer is written.
+    //The image doesn't keep the values of auth::SECRET_ENV.
+    async fn commit_container(&self, id: &str, tag: &str) -> Result<(), String>;
+
+    //Exports a CRIU checkpoint (memory and filesystem) of a running container and leaves it running.
+    //Only podman can do this, and only with CRIU installed and a rootful engine.
+    async fn checkpoint_container(&self, id: &str, export: &Path) -> Result<(), String>;
+
+    //Recreates a container from an exported checkpoint with its original name and ports, returning its id
+    async fn restore_container(&self, import: &Path) -> Result<String, String>;
+
+    //Creates and starts a detached container, returning its id
+    async fn run_container(&self, spec: &RunSpec) -> Result<String, String>;
+
+    async fn start_container(&self, id: &str) -> Result<(), String>;
+
+    async fn stop_container(&self, id: &str) -> Result<(), String>;
+
+    async fn pause_container(&self, id: &str) -> Result<(), String>;
+
+    async fn unpause_container(&self, id: &str) -> Result<(), String>;
+
+    async fn restart_container(&self, id: &str) -> Result<(), String>;
+
+    async fn rename_container(&self, id: &str, name: &str) -> Result<(), String>;
+
+    //Returns None when the container does not exist
+    async fn inspect_container(&self, id: &str) -> Result<Option<ContainerInspect>, String>;
+
+    //Force removes a container, succeeding if it is already gone
+    async fn remove_container(&self, id: &str) -> Result<(), String>;
+
+    //Runs a command inside a running container and waits for it to finish
+    async fn exec(&self, id: &str, cmd: &[&str]) -> Result<ExecOutput, String>;
+
+    //Streams the output of a command run inside the container, line by line
+    async fn exec_stream(&self, id: &str, cmd: &[&str]) -> Result<BoxStream<'static, Result<String, String>>, String>;
+
+    //Container stdout/stderr, optionally following new output
+    async fn logs(&self, id: &str, follow: bool, tail: Option<u32>) -> Result<BoxStream<'static, Result<String, String>>, String>;
+
+    //Applies cpu, memory and pids limits to a running container
+    async fn update_limits(&self, id: &str, limits: &ResourceLimits) -> Result<(), String>;
+
+    async fn usage(&self, id: &str) -> Result<ResourceUsage, String>;
+
+    //Live stream of container events until the stream is dropped
+    async fn events(&self) -> Result<BoxStream<'static, Result<ContainerEvent, String>>, String>;
+}
+
+//Selected runtime, set once at startup
+static RUNTIME: Lazy<Mutex<Option<Arc<dyn ContainerRuntime>>>> = Lazy::new(|| Mutex::new(None));
+
+//Directories GUI apps on macOS don't get on their PATH
+const EXTRA_SEARCH_DIRS: &[&str] = &["/opt/homebrew/bin", "/usr/local/bin", "/usr/bin", "/opt/podman/bin"];
+
+//Looks up a binary via its RADAH_<NAME>_PATH override, then PATH, then well known install dirs
+pub fn find_binary(name: &str) -> Option<PathBuf> {
+    if let Ok(configured) = env::var(format!("RADAH_{}_PATH", name.to_uppercase())) {
+        let path = PathBuf::from(configured);
+        if path.is_file() {
+            return Some(path);
+        }
+        error!("Configured {} path {} does not exist", name, path.display());
+    }
+
+    let path_dirs = env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    path_dirs.into_iter()
+        .chain(EXTRA_SEARCH_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+//Runtime to use: RADAH_CONTAINER_RUNTIME if set, otherwise podman unless only docker is installed
+pub fn preferred_kind() -> RuntimeKind {
+    match env::var("RADAH_CONTAINER_RUNTIME").map(|v| v.to_lowercase()).as_deref() {
+        Ok("docker") => RuntimeKind::Docker,
+        Ok("podman") => RuntimeKind::Podman,
+        _ => {
+            if find_binary("podman").is_none() && find_binary("docker").is_some() {
+                RuntimeKind::Docker
+            } else {
+                RuntimeKind::Podman
+            }
+        }
+    }
+}
+
+//Picks the runtime for this session and stores it globally.
+//The REST API socket is preferred, the CLI is the fallback when no socket is reachable.
+pub async fn init_runtime() -> Result<Arc<dyn ContainerRuntime>, String> {
+    let kind = preferred_kind();
+    let binary = find_binary(kind.binary_name());
+
+    let api_runtime = match podman_api::discover_socket(kind, binary.as_deref()).await {
+        Some(socket) => match ApiRuntime::connect(kind, socket.clone()).await {
+            Ok(api_runtime) => {
+                info!("Using {} API at {}", kind.binary_name(), socket.display());
+                Some(api_runtime)
+            },
+            Err(e) => {
+                info!("{} API at {} is not usable ({}), falling back to the CLI", kind.binary_name(), socket.display(), e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let runtime: Arc<dyn ContainerRuntime> = match (api_runtime, binary) {
+        (Some(api_runtime), _) => Arc::new(api_runtime),
+        (None, Some(binary)) => {
+            info!("Using {} at {}", kind.binary_name(), binary.display());
+            Arc::new(CliRuntime::new(kind, binary))
+        },
+        (None, None) => return Err(format!("Could not find {} on PATH", kind.binary_name())),
+    };
+    set_runtime(runtime.clone());
+    Ok(runtime)
+}
+
+pub fn set_runtime(runtime: Arc<dyn ContainerRuntime>) {
+    *RUNTIME.lock().unwrap() = Some(runtime);
+}
+
+pub fn runtime() -> Result<Arc<dyn ContainerRuntime>, String> {
+    RUNTIME.lock().unwrap().clone().ok_or_else(|| "Container runtime is not initialized".to_string())
+}
+
+//Drives the podman or docker CLI, which accept the same arguments for everything we use
+pub struct CliRuntime {
+    kind: RuntimeKind,
+    binary: PathBuf,
+}
+
+impl CliRuntime {
+    pub fn new(kind: RuntimeKind, binary: PathBuf) -> Self {
+        CliRuntime { kind, binary }
+    }
+
+    async fn output(&self, args: &[&str], current_dir: Option<&Path>) -> Result<Output, String> {
+        let mut command = tokio::process::Command::new(&self.binary);
+        command.args(args);
+        if let Some(dir) = current_dir {
+            command.current_dir(dir);
+        }
+        command.output()
+            .await
+            .map_err(|e| format!("Failed to run {} {}: {}", self.binary.display(), args.join(" "), e))
+    }
+
+    //Runs a command and returns trimmed stdout, or stderr as the error
+    async fn run(&self, args: &[&str], current_dir: Option<&Path>) -> Result<String, String> {
+        let output = self.output(args, current_dir).await?;
+        if !output.status.success() {
+            let error_message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            error!("{} {} failed: {}", self.binary.display(), args.first().unwrap_or(&""), error_message);
+            return Err(error_message);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    //Spawns a long running command and streams its output line by line; the process is killed when the stream is dropped
+    fn spawn_lines(&self, args: &[&str], include_stderr: bool) -> Result<BoxStream<'static, Result<String, String>>, String> {
+        let mut child = tokio::process::Command::new(&self.binary)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(if include_stderr { Stdio::piped() } else { Stdio::null() })
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Failed to run {} {}: {}", self.binary.display(), args.join(" "), e))?;
+        let stdout = child.stdout.take().ok_or("Failed to capture stdout")?;
+        let lines = match child.stderr.take() {
+            Some(stderr) => futures::stream::select(read_lines(stdout), read_lines(stderr)).boxed(),
+            None => read_lines(stdout),
+        };
+
+        //The stream owns the child so dropping it kills the process
+        Ok(lines.map(move |line| {
+            let _child = &child;
+            line
+        }).boxed())
+    }
+}
+
+fn read_lines<R: tokio::io::AsyncRead + Unpin + Send + 'static>(reader: R) -> BoxStream<'static, Result<String, String>> {
+    futures::stream::unfold(Some(BufReader::new(reader).lines()), |state| async move {
+        let mut lines = state?;
+        match lines.next_line().await {
+            Ok(Some(line)) => Some((Ok(line), Some(lines))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e.to_string()), None)),
+        }
+    }).boxed()
+}
+
+//Parses sizes as printed by `stats`, e.g. "23.5MiB" or "1.2GB"
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.trim().parse().ok()?;
+    let multiplier = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "kb" => 1e3,
+        "kib" => 1024.0,
+        "mb" => 1e6,
+        "mib" => 1024.0 * 1024.0,
+        "gb" => 1e9,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        "tb" => 1e12,
+        "tib" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((number * multiplier) as u64)
+}
+
+#[async_trait]
+impl ContainerRuntime for CliRuntime {
+    fn kind(&self) -> RuntimeKind {
+        self.kind
+    }
+
+    fn host_gateway(&self) -> &'static str {
+        match self.kind {
+            RuntimeKind::Podman => "host.containers.internal",
+            RuntimeKind::Docker => "host.docker.internal",
+        }
+    }
+
+    async fn bridge_gateway(&self) -> Option<IpAddr> {
+        let (network, format) = match self.kind {
+            RuntimeKind::Podman => ("podman", "{{(index .Subnets 0).Gateway}}"),
+            RuntimeKind::Docker => ("bridge", "{{(index .IPAM.Config 0).Gateway}}"),
+        };
+        self.run(&["network", "inspect", network, "--format", format], None).await.ok()?
+            .trim().parse().ok()
+    }
+
+    async fn version(&self) -> Result<String, String> {
+        self.run(&["--version"], None).await
+    }
+
+    async fn container_exists(&self, name: &str) -> Result<bool, String> {
+        Ok(self.output(&["container", "inspect", name], None).await?.status.success())
+    }
+
+    async fn image_exists(&self, tag: &str) -> Result<bool, String> {
+        Ok(self.output(&["image", "inspect", tag], None).await?.status.success())
+    }
+
+    async fn build_image(&self, tag: &str, dockerfile: &Path) -> Result<(), String> {
+        let context = dockerfile.parent().ok_or("Dockerfile has no parent directory")?;
+        self.run(&["build", "-t", tag, "-f", &dockerfile.to_string_lossy(), "."], Some(context))
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Build failed: {}", e))
+    }
+
+    async fn create_volume(&self, name: &str) -> Result<(), String> {
+        if self.output(&["volume", "inspect", name], None).await?.status.success() {
+            return Ok(());
+        }
+        self.run(&["volume", "create", name], None).await.map(|_| ())
+    }
+
+    async fn remove_volume(&self, name: &str) -> Result<(), String> {
+        if !self.output(&["volume", "inspect", name], None).await?.status.success() {
+            return Ok(());
+        }
+        self.run(&["volume", "rm", name], None).await.map(|_| ())
+    }
+
+    async fn list_volumes(&self) -> Result<Vec<String>, String> {
+        let names = self.run(&["volume", "ls", "--format", "{{.Name}}"], None).await?;
+        Ok(names.lines().map(str::to_string).collect())
+    }
+
+    async fn remove_image(&self, tag: &str) -> Result<(), String> {
+        if !self.image_exists(tag).await? {
+            return Ok(());
+        }
+        self.run(&["rmi", tag], None).await.map(|_| ())
+    }
+
+    async fn commit_container(&self, id: &str, tag: &str) -> Result<(), String> {
+        let mut args = vec!["commit".to_string(), "--pause=true".to_string()];
+        for name in auth::SECRET_ENV {
+            args.push("--change".to_string());
+            args.push(format!("ENV {}=", name));
+        }
+        args.push(id.to_string());
+        args.push(tag.to_string());
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run(&args, None).await.map(|_| ())
+    }
+
+    async fn checkpoint_container(&self, id: &str, export: &Path) -> Result<(), String> {
+        if self.kind != RuntimeKind::Podman {
+            return Err("Checkpoints are only supported with podman".to_string());
+        }
+        //The agent keeps a websocket open to the host, CRIU refuses to dump established connections otherwise
+        let export = export.to_string_lossy();
+        self.run(&["container", "checkpoint", "--leave-running", "--tcp-established", "--export", &export, id], None)
+            .await
+            .map(|_| ())
+    }
+
+    async fn restore_container(&self, import: &Path) -> Result<String, String> {
+        if self.kind != RuntimeKind::Podman {
+            return Err("Checkpoints are only supported with podman".to_string());
+        }
+        //Connections saved in the checkpoint are dead by now, close them so the agent reconnects
+        let import = import.to_string_lossy();
+        self.run(&["container", "restore", "--tcp-close", "--import", &import], None).await
+    }
+
+    async fn run_container(&self, spec: &RunSpec) -> Result<String, String> {
+        let mut args: Vec<String> = vec!["run".into(), "-d".into(), "--network".into(), "bridge".into()];
+        if self.kind == RuntimeKind::Docker {
+            //Docker Desktop resolves host.docker.internal itself, plain docker on Linux needs the mapping
+            args.push("--add-host".into());
+            args.push("host.docker.internal:host-gateway".into());
+        }
+        for (key, value) in &spec.env {
+            args.push("-e".into());
+            args.push(format!("{}={}", key, value));
+        }
+        for (host, container) in &spec.ports {
+            args.push("-p".into());
+            args.push(format!("{}:{}", host, container));
+        }
+        for mount in &spec.mounts {
+            args.push("-v".into());
+            args.push(mount.bind());
+        }
+        args.extend(spec.limits.cli_args());
+        if let Some(storage_gb) = spec.limits.storage_gb {
+            args.push("--storage-opt".into());
+            args.push(format!("size={}G", storage_gb));
+        }
+        args.push("--name".into());
+        args.push(spec.name.clone());
+        args.push(spec.image.clone());
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run(&args, None).await
+    }
+
+    async fn start_container(&self, id: &str) -> Result<(), String> {
+        self.run(&["start", id], None).await.map(|_| ())
+    }
+
+    async fn stop_container(&self, id: &str) -> Result<(), String> {
+        self.run(&["stop", id], None).await.map(|_| ())
+    }
+
+    async fn pause_container(&self, id: &str) -> Result<(), String> {
+        self.run(&["pause", id], None).await.map(|_| ())
+    }
+
+    async fn unpause_container(&self, id: &str) -> Result<(), String> {
+        self.run(&["unpause", id], None).await.map(|_| ())
+    }
+
+    async fn restart_container(&self, id: &str) -> Result<(), String> {
+        self.run(&["restart", id], None).await.map(|_| ())
+    }
+
+    async fn rename_container(&self, id: &str, name: &str) -> Result<(), String> {
+        self.run(&["rename", id, name], None).await.map(|_| ())
+    }
+
+    async fn inspect_container(&self, id: &str) -> Result<Option<ContainerInspect>, String> {
+        let output = self.output(&["container", "inspect", id], None).await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let mut inspected: Vec<ContainerInspect> = serde_json::from_slice(&output.stdout)
+            .map_err(|e| format!("Failed to parse inspect output: {}", e))?;
+        Ok(inspected.pop())
+    }
+
+    async fn remove_container(&self, id: &str) -> Result<(), String> {
+        if !self.container_exists(id).await? {
+            return Ok(());
+        }
+        self.run(&["rm", "-f", id], None)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to delete container: {}", e))
+    }
+
+    async fn exec(&self, id: &str, cmd: &[&str]) -> Result<ExecOutput, String> {
+        let mut args = vec!["exec", id];
+        args.extend_from_slice(cmd);
+        let output = self.output(&args, None).await?;
+        Ok(ExecOutput {
+            exit_code: output.status.code().unwrap_or(-1) as i64,
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+
+    async fn exec_stream(&self, id: &str, cmd: &[&str]) -> Result<BoxStream<'static, Result<String, String>>, String> {
+        let mut args = vec!["exec", id];
+        args.extend_from_slice(cmd);
+        self.spawn_lines(&args, true)
+    }
+
+    async fn logs(&self, id: &str, follow: bool, tail: Option<u32>) -> Result<BoxStream<'static, Result<String, String>>, String> {
+        let tail = tail.map(|n| n.to_string()).unwrap_or_else(|| "all".to_string());
+        let mut args = vec!["logs", "--tail", &tail];
+        if follow {
+            args.push("--follow");
+        }
+        args.push(id);
+        self.spawn_lines(&args, true)
+    }
+
+    async fn update_limits(&self, id: &str, limits: &ResourceLimits) -> Result<(), String> {
+        let mut args: Vec<String> = vec!["update".into()];
+        args.extend(limits.cli_args());
+        args.push(id.to_string());
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run(&args, None).await.map(|_| ())
+    }
+
+    async fn usage(&self, id: &str) -> Result<ResourceUsage, String> {
+        let stats = self.run(&["stats", "--no-stream", "--format", "{{.CPUPerc}}\t{{.MemUsage}}\t{{.PIDs}}", id], None).await?;
+        let mut fields = stats.lines().next().unwrap_or_default().split('\t');
+        let cpu_percent = fields.next().unwrap_or_default().trim().trim_end_matches('%').parse().unwrap_or_default();
+        let mut memory = fields.next().unwrap_or_default().split('/');
+        let memory_bytes = memory.next().and_then(parse_size).unwrap_or_default();
+        let memory_limit_bytes = memory.next().and_then(parse_size).unwrap_or_default();
+        let pids = fields.next().unwrap_or_default().trim().parse().unwrap_or_default();
+
+        let disk_bytes = self.run(&["container", "inspect", "--size", "--format", "{{.SizeRw}}", id], None).await
+            .ok()
+            .and_then(|size| size.parse().ok());
+
+        Ok(ResourceUsage { cpu_percent, memory_bytes, memory_limit_bytes, pids, disk_bytes })
+    }
+
+    async fn events(&self) -> Result<BoxStream<'static, Result<ContainerEvent, String>>, String> {
+        let format = match self.kind {
+            RuntimeKind::Podman => "json",
+            RuntimeKind::Docker => "{{json .}}",
+        };
+        Ok(self.spawn_lines(&["events", "--format", format, "--filter", "type=container"], false)?
+            .filter_map(|line| async move {
+                match line {
+                    Ok(line) => serde_json::from_str::<serde_json::Value>(&line).ok()
+                        .and_then(|value| ContainerEvent::from_json(&value))
+                        .map(Ok),
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .boxed())
+    }
+}
+
+//In-memory engine for tests. Containers run as soon as they are created and every exec succeeds.
+#[cfg(test)]
+pub mod fake {
+    use std::collections::{BTreeSet, HashMap};
+    use std::net::IpAddr;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use futures::stream::{BoxStream, StreamExt};
+
+    use super::*;
+
+    struct FakeContainer {
+        name: String,
+        env: Vec<(String, String)>,
+        running: bool,
+        paused: bool,
+    }
+
+    #[derive(Default)]
+    pub struct FakeRuntime {
+        containers: Mutex<HashMap<String, FakeContainer>>,
+        volumes: Mutex<BTreeSet<String>>,
+        images: Mutex<BTreeSet<String>>,
+        next_id: Mutex<u64>,
+    }
+
+    impl FakeRuntime {
+        pub fn volumes(&self) -> Vec<String> {
+            self.volumes.lock().unwrap().iter().cloned().collect()
+        }
+
+        //Environment a container was run with
+        pub fn env(&self, id: &str) -> Vec<(String, String)> {
+            self.containers.lock().unwrap().get(id).map(|c| c.env.clone()).unwrap_or_default()
+        }
+
+        //A container that was run before and is stopped now, returns its id
+        pub fn add_stopped(&self, name: &str) -> String {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            let id = format!("fake{:08}", *next_id);
+            self.containers.lock().unwrap().insert(id.clone(), FakeContainer { name: name.to_string(), env: Vec::new(), running: false, paused: false });
+            id
+        }
+
+        pub fn is_running(&self, id: &str) -> bool {
+            self.containers.lock().unwrap().get(id).map(|c| c.running && !c.paused).unwrap_or(false)
+        }
+
+        //Containers are found by id or name, like the engines do
+        fn find_id(&self, id_or_name: &str) -> Option<String> {
+            self.containers.lock().unwrap().iter()
+                .find(|(id, c)| id.as_str() == id_or_name || c.name == id_or_name)
+                .map(|(id, _)| id.clone())
+        }
+
+        fn update(&self, id: &str, update: impl FnOnce(&mut FakeContainer)) -> Result<(), String> {
+            let id = self.find_id(id).ok_or_else(|| format!("No such container: {}", id))?;
+            update(self.containers.lock().unwrap().get_mut(&id).unwrap());
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl ContainerRuntime for FakeRuntime {
+        fn kind(&self) -> RuntimeKind {
+            RuntimeKind::Podman
+        }
+
+        fn host_gateway(&self) -> &'static str {
+            "host.containers.internal"
+        }
+
+        async fn bridge_gateway(&self) -> Option<IpAddr> {
+            None
+        }
+
+        async fn version(&self) -> Result<String, String> {
+            Ok("fake".to_string())
+        }
+
+        async fn container_exists(&self, name: &str) -> Result<bool, String> {
+            Ok(self.find_id(name).is_some())
+        }
+
+        async fn image_exists(&self, tag: &str) -> Result<bool, String> {
+            Ok(self.images.lock().unwrap().contains(tag))
+        }
+
+        async fn build_image(&self, tag: &str, _dockerfile: &Path) -> Result<(), String> {
+            self.images.lock().unwrap().insert(tag.to_string());
+            Ok(())
+        }
+
+        async fn create_volume(&self, name: &str) -> Result<(), String> {
+            self.volumes.lock().unwrap().insert(name.to_string());
+            Ok(())
+        }
+
+        async fn remove_volume(&self, name: &str) -> Result<(), String> {
+            self.volumes.lock().unwrap().remove(name);
+            Ok(())
+        }
+
+        async fn list_volumes(&self) -> Result<Vec<String>, String> {
+            Ok(self.volumes())
+        }
+
+        async fn remove_image(&self, tag: &str) -> Result<(), String> {
+            self.images.lock().unwrap().remove(tag);
+            Ok(())
+        }
+
+        async fn commit_container(&self, id: &str, tag: &str) -> Result<(), String> {
+            self.find_id(id).ok_or_else(|| format!("No such container: {}", id))?;
+            self.images.lock().unwrap().insert(tag.to_string());
+            Ok(())
+        }
+
+        async fn checkpoint_container(&self, _id: &str, _export: &Path) -> Result<(), String> {
+            Err("Checkpoints are not supported by the fake runtime".to_string())
+        }
+
+        async fn restore_container(&self, _import: &Path) -> Result<String, String> {
+            Err("Checkpoints are not supported by the fake runtime".to_string())
+        }
+
+        async fn run_container(&self, spec: &RunSpec) -> Result<String, String> {
+            if self.find_id(&spec.name).is_some() {
+                return Err(format!("The container name \"{}\" is already in use", spec.name));
+            }
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            let id = format!("fake{:08}", *next_id);
+            self.containers.lock().unwrap().insert(id.clone(), FakeContainer { name: spec.name.clone(), env: spec.env.clone(), running: true, paused: false });
+            Ok(id)
+        }
+
+        async fn start_container(&self, id: &str) -> Result<(), String> {
+            self.update(id, |c| c.running = true)
+        }
+
+        async fn stop_container(&self, id: &str) -> Result<(), String> {
+            self.update(id, |c| { c.running = false; c.paused = false; })
+        }
+
+        async fn pause_container(&self, id: &str) -> Result<(), String> {
+            self.update(id, |c| c.paused = true)
+        }
+
+        async fn unpause_container(&self, id: &str) -> Result<(), String> {
+            self.update(id, |c| c.paused = false)
+        }
+
+        async fn restart_container(&self, id: &str) -> Result<(), String> {
+            self.update(id, |c| { c.running = true; c.paused = false; })
+        }
+
+        async fn rename_container(&self, id: &str, name: &str) -> Result<(), String> {
+            if self.find_id(name).is_some() {
+                return Err(format!("The container name \"{}\" is already in use", name));
+            }
+            self.update(id, |c| c.name = name.to_string())
+        }
+
+        async fn inspect_container(&self, id: &str) -> Result<Option<ContainerInspect>, String> {
+            let Some(id) = self.find_id(id) else { return Ok(None) };
+            let containers = self.containers.lock().unwrap();
+            let container = &containers[&id];
+            let status = if container.paused { "paused" } else if container.running { "running" } else { "exited" };
+            Ok(Some(ContainerInspect {
+                id: id.clone(),
+                name: container.name.clone(),
+                state: ContainerStateInfo {
+                    status: status.to_string(),
+                    running: container.running,
+                    paused: container.paused,
+                    exit_code: 0,
+                    error: String::new(),
+                },
+                network_settings: None,
+            }))
+        }
+
+        async fn remove_container(&self, id: &str) -> Result<(), String> {
+            if let Some(id) = self.find_id(id) {
+                self.containers.lock().unwrap().remove(&id);
+            }
+            Ok(())
+        }
+
+        async fn exec(&self, id: &str, _cmd: &[&str]) -> Result<ExecOutput, String> {
+            self.find_id(id).ok_or_else(|| format!("No such container: {}", id))?;
+            Ok(ExecOutput::default())
+        }
+
+        async fn exec_stream(&self, id: &str, cmd: &[&str]) -> Result<BoxStream<'static, Result<String, String>>, String> {
+            self.exec(id, cmd).await?;
+            Ok(futures::stream::empty().boxed())
+        }
+
+        async fn logs(&self, _id: &str, _follow: bool, _tail: Option<u32>) -> Result<BoxStream<'static, Result<String, String>>, String> {
+            Ok(futures::stream::empty().boxed())
+        }
+
+        async fn update_limits(&self, id: &str, _limits: &ResourceLimits) -> Result<(), String> {
+            self.update(id, |_| {})
+        }
+
+        async fn usage(&self, _id: &str) -> Result<ResourceUsage, String> {
+            Ok(ResourceUsage::default())
+        }
+
+        async fn events(&self) -> Result<BoxStream<'static, Result<ContainerEvent, String>>, String> {
+            Ok(futures::stream::pending().boxed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fake::FakeRuntime;
+
+    #[tokio::test]
+    async fn runs_stops_and_removes_an_agent_container() {
+        let runtime = FakeRuntime::default();
+        let container = crate::test_agent("pam-1");
+        let spec = crate::agent_run_spec(&runtime, &container, 5900, "test-key".to_string());
+
+        let id = runtime.run_container(&spec).await.unwrap();
+        assert!(runtime.is_running(&id));
+        assert!(runtime.env(&id).contains(&("ANTHROPIC_API_KEY".to_string(), "test-key".to_string())));
+
+        runtime.stop_container(&id).await.unwrap();
+        assert!(!runtime.is_running(&id));
+        runtime.start_container(&id).await.unwrap();
+        assert!(runtime.is_running(&id));
+
+        //Names are unique, so a leftover container has to be removed before the agent is run again
+        assert!(runtime.run_container(&spec).await.is_err());
+        runtime.remove_container("agent-pam-1").await.unwrap();
+        assert!(!runtime.container_exists(&id).await.unwrap());
+        let id = runtime.run_container(&spec).await.unwrap();
+        assert!(runtime.is_running(&id));
+    }
+}
@@ -0,0 +1,291 @@
+use std::fmt;
+use std::time::Duration;
+use chrono::{DateTime, Local};
+use croner::Cron;
+use serde::{Serialize, Deserialize};
+use log::{info, error};
+
+use crate::{db, websocket, save_container, CONTAINERS};
+use crate::protocol::Prompt;
+
+//Prompts sent to an agent on a schedule, e.g. "every morning at 8, summarize these dashboards".
+//Schedules are stored with their agent (Container::schedules) and each run is recorded in the
+//schedule_runs table. A due schedule queues its prompt like one from the frontend, which wakes a
+//stopped agent when the prompt's turn comes.
+
+//How often schedules are checked, cron expressions are to the minute
+const CHECK_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Schedule {
+    pub id: String,
+    //Five field cron expression (minute hour day-of-month month day-of-week) in local time
+    pub cron: String,
+    pub prompt: String,
+    pub enabled: bool,
+    pub created_at: String,
+    //Occurrences are counted from here, so ones missed while the app was closed fire once at startup
+    #[serde(default)]
+    pub last_fired_at: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunOutcome {
+    //Queued or running
+    Pending,
+    Succeeded,
+    //Stopped from the frontend
+    Stopped,
+    //Removed from the queue before it ran
+    Cancelled,
+    Failed,
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = serde_json::to_value(self).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+//One firing of a schedule. Its id is also the id of the prompt in the agent's queue.
+#[derive(Serialize, Clone, Debug)]
+pub struct ScheduleRun {
+    pub id: String,
+    pub schedule_id: String,
+    pub agent_id: String,
+    pub prompt: String,
+    pub scheduled_for: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub outcome: RunOutcome,
+    pub error: Option<String>,
+}
+
+fn parse_cron(cron: &str) -> Result<Cron, String> {
+    Cron::new(cron).parse().map_err(|e| format!("Invalid schedule \"{}\": {}", cron, e))
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Local>, String> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Local))
+        .map_err(|e| format!("Invalid time {}: {}", time, e))
+}
+
+//First occurrence of the schedule after it last fired, or after it was created
+fn next_run(schedule: &Schedule) -> Result<DateTime<Local>, String> {
+    let after = parse_time(schedule.last_fired_at.as_deref().unwrap_or(&schedule.created_at))?;
+    parse_cron(&schedule.cron)?
+        .find_next_occurrence(&after, false)
+        .map_err(|e| format!("Schedule \"{}\" never runs: {}", schedule.cron, e))
+}
+
+fn update_schedules<T>(agent_id: &str, update: impl FnOnce(&mut Vec<Schedule>) -> Result<T, String>) -> Result<T, String> {
+    let mut containers = CONTAINERS.lock().unwrap();
+    let container = containers.iter_mut().find(|c| c.agent_id == agent_id).ok_or("Container not found")?;
+    let result = update(&mut container.schedules)?;
+    save_container(container)?;
+    Ok(result)
+}
+
+pub fn get_schedules(agent_id: &str) -> Result<Vec<Schedule>, String> {
+    CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.agent_id == agent_id)
+        .map(|c| c.schedules.clone())
+        .ok_or("Container not found".to_string())
+}
+
+pub fn add_schedule(agent_id: &str, cron: &str, prompt: &str) -> Result<Schedule, String> {
+    parse_cron(cron)?;
+    if prompt.trim().is_empty() {
+        return Err("A schedule needs a prompt".to_string());
+    }
+    let schedule = Schedule {
+        id: uuid::Uuid::new_v4().to_string(),
+        cron: cron.trim().to_string(),
+        prompt: prompt.to_string(),
+        enabled: true,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        last_fired_at: None,
+    };
+    update_schedules(agent_id, |schedules| {
+        schedules.push(schedule.clone());
+        Ok(())
+    })?;
+    Ok(schedule)
+}
+
+pub fn remove_schedule(agent_id: &str, schedule_id: &str) -> Result<(), String> {
+    update_schedules(agent_id, |schedules| {
+        let index = schedules.iter().position(|s| s.id == schedule_id).ok_or("Schedule not found")?;
+        schedules.remove(index);
+        Ok(())
+    })
+}
+
+pub fn set_schedule_enabled(agent_id: &str, schedule_id: &str, enabled: bool) -> Result<Schedule, String> {
+    update_schedules(agent_id, |schedules| {
+        let schedule = schedules.iter_mut().find(|s| s.id == schedule_id).ok_or("Schedule not found")?;
+        //Occurrences while it was disabled don't fire when it is enabled again
+        if enabled && !schedule.enabled {
+            schedule.last_fired_at = Some(chrono::Utc::now().to_rfc3339());
+        }
+        schedule.enabled = enabled;
+        Ok(schedule.clone())
+    })
+}
+
+pub fn get_schedule_runs(agent_id: &str, limit: usize) -> Result<Vec<ScheduleRun>, String> {
+    db::load_schedule_runs(agent_id, limit)
+}
+
+//Called when a queued prompt ends, whether or not it came from a schedule
+pub fn prompt_finished(prompt_id: &str, outcome: RunOutcome, error: Option<&str>) {
+    if let Err(e) = db::finish_schedule_run(prompt_id, outcome, error) {
+        error!("Failed to record outcome of prompt {}: {}", prompt_id, e);
+    }
+}
+
+//Fires due schedules until the app exits
+pub async fn run_scheduler() {
+    //Queues aren't kept across restarts, so runs still pending were lost with the last launch
+    if let Err(e) = db::abandon_schedule_runs("The app closed before the run finished") {
+        error!("Failed to update unfinished schedule runs: {}", e);
+    }
+    loop {
+        fire_due_schedules().await;
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+async fn fire_due_schedules() {
+    let now = Local::now();
+    let due: Vec<(String, Schedule, DateTime<Local>)> = CONTAINERS.lock().unwrap().iter()
+        .flat_map(|c| c.schedules.iter().filter(|s| s.enabled).map(move |s| (c.agent_id.clone(), s.clone())))
+        .filter_map(|(agent_id, schedule)| match next_run(&schedule) {
+            Ok(next) if next <= now => Some((agent_id, schedule, next)),
+            Ok(_) => None,
+            Err(e) => {
+                error!("Skipping schedule {} of agent {}: {}", schedule.id, agent_id, e);
+                None
+            }
+        })
+        .collect();
+    if due.is_empty() {
+        return;
+    }
+
+    //Marked as fired before the prompts go out so a failing save can't fire them again every check
+    let fired_at = chrono::Utc::now().to_rfc3339();
+    for (agent_id, schedule, _) in &due {
+        let result = update_schedules(agent_id, |schedules| {
+            if let Some(stored) = schedules.iter_mut().find(|s| s.id == schedule.id) {
+                stored.last_fired_at = Some(fired_at.clone());
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Failed to save schedule {} of agent {}: {}", schedule.id, agent_id, e);
+        }
+    }
+
+    for (agent_id, schedule, scheduled_for) in due {
+        fire(agent_id, schedule, scheduled_for).await;
+    }
+}
+
+async fn fire(agent_id: String, schedule: Schedule, scheduled_for: DateTime<Local>) {
+    info!("Running schedule {} of agent {}", schedule.id, agent_id);
+    let run = ScheduleRun {
+        id: uuid::Uuid::new_v4().to_string(),
+        schedule_id: schedule.id.clone(),
+        agent_id: agent_id.clone(),
+        prompt: schedule.prompt.clone(),
+        scheduled_for: scheduled_for.to_rfc3339(),
+        started_at: chrono::Utc::now().to_rfc3339(),
+        finished_at: None,
+        outcome: RunOutcome::Pending,
+        error: None,
+    };
+    //Recorded first, the prompt can end before submit_prompt returns
+    if let Err(e) = db::insert_schedule_run(&run) {
+        error!("Failed to record run of schedule {}: {}", schedule.id, e);
+    }
+
+    let prompt = Prompt {
+        message_id: String::new(),
+        agent_id,
+        text: schedule.prompt,
+        show_ui: true,
+        files: Vec::new(),
+    };
+    if let Err(e) = websocket::submit_prompt(run.id.clone(), prompt).await {
+        error!("Failed to queue prompt of schedule {}: {}", schedule.id, e);
+        prompt_finished(&run.id, RunOutcome::Failed, Some(&e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    fn schedule(cron: &str, created_at: DateTime<Local>) -> Schedule {
+        Schedule {
+            id: "schedule-1".to_string(),
+            cron: cron.to_string(),
+            prompt: "Summarize the dashboards".to_string(),
+            enabled: true,
+            created_at: created_at.to_rfc3339(),
+            last_fired_at: None,
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_cron_expressions() {
+        for cron in ["", "every morning", "* * *", "61 * * * *", "0 25 * * *", "0 8 * 13 *"] {
+            assert!(parse_cron(cron).is_err(), "accepted {:?}", cron);
+        }
+        assert!(parse_cron("0 8 * * 1-5").is_ok());
+        assert!(add_schedule("no-such-agent", "every morning", "Hello").unwrap_err().contains("Invalid schedule"));
+    }
+
+    #[test]
+    fn missed_occurrences_fire_once() {
+        let now = Local::now();
+        //Closed for three days, so three 8 o'clocks were missed
+        let mut schedule = schedule("0 8 * * *", now - ChronoDuration::days(3));
+        let next = next_run(&schedule).unwrap();
+        assert!(next <= now - ChronoDuration::days(2));
+
+        //Firing marks it, after which the next run is the next 8 o'clock to come
+        schedule.last_fired_at = Some(now.to_rfc3339());
+        let next = next_run(&schedule).unwrap();
+        assert!(next > now && next <= now + ChronoDuration::days(1));
+    }
+
+    #[test]
+    fn enabling_a_schedule_again_skips_what_it_missed() {
+        let _globals = crate::TEST_GLOBALS.blocking_lock();
+        db::open_in_memory();
+        let now = Local::now();
+        let mut container = crate::test_agent("schedule-agent");
+        let mut disabled = schedule("*/5 * * * *", now - ChronoDuration::days(1));
+        disabled.enabled = false;
+        container.schedules.push(disabled);
+        CONTAINERS.lock().unwrap().push(container);
+
+        let enabled = set_schedule_enabled("schedule-agent", "schedule-1", true).unwrap();
+        assert!(enabled.enabled);
+        assert!(next_run(&enabled).unwrap() > now);
+        assert!(get_schedules("schedule-agent").unwrap()[0].last_fired_at.is_some());
+
+        //Enabling one that is already enabled doesn't push it back
+        let last_fired_at = enabled.last_fired_at.clone();
+        let again = set_schedule_enabled("schedule-agent", "schedule-1", true).unwrap();
+        assert_eq!(again.last_fired_at, last_fired_at);
+
+        CONTAINERS.lock().unwrap().retain(|c| c.agent_id != "schedule-agent");
+    }
+}
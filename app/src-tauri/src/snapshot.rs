@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+use tauri::Manager;
+use log::{info, error};
+
+use crate::{auth, websocket, Container, ContainerStatus, RuntimeKind, CONTAINERS, MESSAGES, run_agent_container, replace_agent_container, replace_agent_container_with, runtime};
+use crate::db;
+use crate::helpers::{save_container, get_available_ports, copy_messages};
+
+//Snapshot images are tagged with the snapshot id
+const SNAPSHOT_IMAGE_REPO: &str = "localhost/radah-snapshot";
+
+//Images carrying an agent's filesystem into a new container (clones, changed mounts).
+//Each is owned by a single agent and removed once that agent stops using it.
+const CLONE_IMAGE_REPO: &str = "localhost/radah-clone";
+
+//Saved state of an agent: its filesystem as an image, a CRIU checkpoint when the engine supports it,
+//and the conversation up to that point
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    //Exported checkpoint archive, None when the engine could not checkpoint the container
+    #[serde(default)]
+    pub checkpoint: Option<String>,
+    pub message_ids: Vec<String>,
+    pub created_at: String,
+}
+
+fn snapshots_dir(app_handle: &tauri::AppHandle) -> Result<PathBuf, String> {
+    let dir = app_handle.path().app_data_dir()
+        .map_err(|e| format!("Failed to get app data dir: {}", e))?
+        .join("snapshots");
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create snapshots directory: {}", e))?;
+    Ok(dir)
+}
+
+fn find_agent(agent_id: &str) -> Result<Container, String> {
+    CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.agent_id == agent_id)
+        .cloned()
+        .ok_or_else(|| "Container not found".to_string())
+}
+
+fn find_snapshot(container: &Container, snapshot_id: &str) -> Result<Snapshot, String> {
+    container.snapshots.iter()
+        .find(|s| s.id == snapshot_id)
+        .cloned()
+        .ok_or_else(|| "Snapshot not found".to_string())
+}
+
+//Messages an agent still needs, either in its conversation or in one of its snapshots
+fn referenced_messages(container: &Container) -> HashSet<String> {
+    container.message_ids.iter()
+        .chain(container.snapshots.iter().flat_map(|s| s.message_ids.iter()))
+        .cloned()
+        .collect()
+}
+
+//Removes messages that `before` referenced and `after` no longer does
+fn prune_messages(before: &Container, after: &Container) -> Result<(), String> {
+    let kept = referenced_messages(after);
+    let removed: Vec<String> = referenced_messages(before).difference(&kept).cloned().collect();
+    let mut messages = MESSAGES.lock().unwrap();
+    for message_id in &removed {
+        messages.remove(message_id);
+    }
+    db::delete_messages(&removed)
+}
+
+fn store_agent(container: &Container) -> Result<(), String> {
+    let mut containers = CONTAINERS.lock().unwrap();
+    let stored = containers.iter_mut().find(|c| c.agent_id == container.agent_id).ok_or("Container not found")?;
+    *stored = container.clone();
+    save_container(container)
+}
+
+//New agent with the source agent's type, prompt and settings, running `image`
+fn derived_agent(source: &Container, agent_id: String, agent_name: String, number: i32, image: String) -> Container {
+    Container {
+        id: String::new(),
+        vnc_port: 0,
+        number,
+        agent_type: source.agent_type.clone(),
+        agent_name,
+        message_ids: Vec::new(),
+        agent_id,
+        system_prompt: source.system_prompt.clone(),
+        image,
+        resource_limits: source.resource_limits.clone(),
+        status: ContainerStatus::Running,
+        idle_timeout_minutes: source.idle_timeout_minutes,
+        snapshots: Vec::new(),
+        //Shared volumes and host directories carry over, agent volumes start out empty
+        mounts: source.mounts.clone(),
+        ws_token: auth::new_token(),
+        ws_port: websocket::configured_port(),
+        //Copies don't run the source agent's schedules
+        schedules: Vec::new(),
+    }
+}
+
+//Runs a new agent's container from `container.image` and saves it, copying `history` into its conversation
+pub async fn launch_agent(mut container: Container, history: &[String]) -> Result<Container, String> {
+    let runtime = runtime()?;
+    let ports = get_available_ports()?;
+    container.vnc_port = ports[1];
+
+    runtime.remove_container(&format!("agent-{}", container.agent_id)).await?;
+    container.id = run_agent_container(runtime.as_ref(), &container, ports[0]).await?;
+
+    container.message_ids = copy_messages(history, &container.agent_id)?;
+    save_container(&container)?;
+    CONTAINERS.lock().unwrap().push(container.clone());
+    Ok(container)
+}
+
+//Commits the agent's container into an image and, on podman with CRIU, also exports a checkpoint of its memory
+pub async fn create_snapshot(app_handle: &tauri::AppHandle, agent_id: &str, name: String) -> Result<Snapshot, String> {
+    let runtime = runtime()?;
+    let container = find_agent(agent_id)?;
+    let snapshot_id = uuid::Uuid::new_v4().to_string();
+    let image = format!("{}:{}", SNAPSHOT_IMAGE_REPO, snapshot_id);
+
+    runtime.commit_container(&container.id, &image).await
+        .map_err(|e| format!("Failed to snapshot agent: {}", e))?;
+
+    let mut checkpoint = None;
+    if runtime.kind() == RuntimeKind::Podman && container.status == ContainerStatus::Running {
+        let path = snapshots_dir(app_handle)?.join(format!("{}.tar.gz", snapshot_id));
+        match runtime.checkpoint_container(&container.id, &path).await {
+            Ok(()) => checkpoint = Some(path.to_string_lossy().to_string()),
+            Err(e) => {
+                info!("Checkpoint of agent {} unavailable, keeping the filesystem snapshot only: {}", agent_id, e);
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    let snapshot = Snapshot {
+        id: snapshot_id,
+        name,
+        image,
+        checkpoint,
+        message_ids: container.message_ids.clone(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    info!("Created snapshot {} of agent {}", snapshot.id, agent_id);
+
+    let mut containers = CONTAINERS.lock().unwrap();
+    let stored = containers.iter_mut().find(|c| c.agent_id == agent_id).ok_or("Container not found")?;
+    stored.snapshots.push(snapshot.clone());
+    save_container(stored)?;
+    Ok(snapshot)
+}
+
+//Removes a snapshot's image and checkpoint; fails while another agent still runs from the image
+pub async fn delete_snapshot(agent_id: &str, snapshot_id: &str) -> Result<(), String> {
+    let container = find_agent(agent_id)?;
+    let snapshot = find_snapshot(&container, snapshot_id)?;
+
+    runtime()?.remove_image(&snapshot.image).await
+        .map_err(|e| format!("Failed to delete snapshot image: {}", e))?;
+    if let Some(checkpoint) = &snapshot.checkpoint {
+        let _ = fs::remove_file(checkpoint);
+    }
+
+    let mut updated = container.clone();
+    updated.snapshots.retain(|s| s.id != snapshot_id);
+    store_agent(&updated)?;
+    prune_messages(&container, &updated)
+}
+
+//Rolls an agent back to a snapshot: restores the checkpoint when there is one, otherwise recreates
+//the container from the snapshot image. Messages after the snapshot are dropped from the conversation.
+pub async fn restore_snapshot(agent_id: &str, snapshot_id: &str) -> Result<Container, String> {
+    let runtime = runtime()?;
+    let container = find_agent(agent_id)?;
+    let snapshot = find_snapshot(&container, snapshot_id)?;
+
+    let container_name = format!("agent-{}", agent_id);
+    let vnc_host_port = match runtime.inspect_container(&container_name).await?.and_then(|i| i.host_port(5900)) {
+        Some(port) => port,
+        None => get_available_ports()?[0],
+    };
+    let checkpoint = snapshot.checkpoint.as_ref().map(PathBuf::from).filter(|path| path.exists());
+
+    let mut from_image = container.clone();
+    from_image.image = snapshot.image.clone();
+    from_image.ws_port = websocket::configured_port();
+
+    //The current container is only removed once the restored one runs
+    let (id, from_checkpoint) = replace_agent_container_with(runtime.as_ref(), agent_id, || async {
+        if let Some(path) = &checkpoint {
+            match runtime.restore_container(path).await {
+                Ok(id) => return Ok((id, true)),
+                Err(e) => {
+                    error!("Failed to restore checkpoint of agent {}, starting from the snapshot image: {}", agent_id, e);
+                    let _ = runtime.remove_container(&container_name).await;
+                }
+            }
+        }
+        run_agent_container(runtime.as_ref(), &from_image, vnc_host_port).await.map(|id| (id, false))
+    }).await?;
+
+    let mut updated = if from_checkpoint { container.clone() } else { from_image };
+    updated.id = id;
+    updated.message_ids = snapshot.message_ids.clone();
+    updated.status = ContainerStatus::Running;
+    info!("Restored agent {} to snapshot {}", agent_id, snapshot_id);
+
+    store_agent(&updated)?;
+    db::set_conversation(agent_id, &updated.message_ids)?;
+    prune_messages(&container, &updated)?;
+    if updated.image != container.image {
+        release_image(&container.image).await;
+    }
+    Ok(updated)
+}
+
+//Starts a new agent from another agent's snapshot, with a copy of the conversation up to the snapshot
+pub async fn create_agent_from_snapshot(
+    source_agent_id: &str,
+    snapshot_id: &str,
+    agent_id: String,
+    agent_name: String,
+    number: i32,
+) -> Result<Container, String> {
+    let source = find_agent(source_agent_id)?;
+    let snapshot = find_snapshot(&source, snapshot_id)?;
+
+    let container = derived_agent(&source, agent_id, agent_name, number, snapshot.image.clone());
+    launch_agent(container, &snapshot.message_ids).await
+}
+
+fn owned_image_tag(agent_id: &str) -> String {
+    format!("{}:{}-{}", CLONE_IMAGE_REPO, agent_id, &uuid::Uuid::new_v4().simple().to_string()[..8])
+}
+
+//Replaces an agent's container with one built from `container`'s settings (e.g. new mounts),
+//carrying the current filesystem over through a committed image
+pub async fn recreate_agent(mut container: Container) -> Result<Container, String> {
+    let runtime = runtime()?;
+    let container_name = format!("agent-{}", container.agent_id);
+    let inspect = runtime.inspect_container(&container_name).await?.ok_or("Agent container not found")?;
+    let vnc_host_port = match inspect.host_port(5900) {
+        Some(port) => port,
+        None => get_available_ports()?[0],
+    };
+
+    let previous_image = container.image.clone();
+    let image = owned_image_tag(&container.agent_id);
+    runtime.commit_container(&inspect.id, &image).await
+        .map_err(|e| format!("Failed to save agent state: {}", e))?;
+
+    container.image = image.clone();
+    container.ws_port = websocket::configured_port();
+    container.id = match replace_agent_container(runtime.as_ref(), &container, vnc_host_port).await {
+        Ok(id) => id,
+        Err(e) => {
+            let _ = runtime.remove_image(&image).await;
+            return Err(e);
+        }
+    };
+    container.status = ContainerStatus::Running;
+    store_agent(&container)?;
+    release_image(&previous_image).await;
+    Ok(container)
+}
+
+//Copies an agent's current filesystem, prompt and settings, and optionally its conversation, into a new agent
+pub async fn clone_agent(
+    source_agent_id: &str,
+    agent_id: String,
+    agent_name: String,
+    number: i32,
+    include_messages: bool,
+) -> Result<Container, String> {
+    let runtime = runtime()?;
+    let source = find_agent(source_agent_id)?;
+    let image = owned_image_tag(&agent_id);
+
+    runtime.commit_container(&source.id, &image).await
+        .map_err(|e| format!("Failed to copy agent: {}", e))?;
+    info!("Cloning agent {} into {}", source_agent_id, agent_id);
+
+    let history = if include_messages { source.message_ids.clone() } else { Vec::new() };
+    let container = derived_agent(&source, agent_id, agent_name, number, image.clone());
+    match launch_agent(container, &history).await {
+        Ok(container) => Ok(container),
+        Err(e) => {
+            let _ = runtime.remove_image(&image).await;
+            Err(e)
+        }
+    }
+}
+
+//Removes an image an agent stopped using if the agent owned it, which is only the case for clones
+pub async fn release_image(image: &str) {
+    if !image.starts_with(CLONE_IMAGE_REPO) {
+        return;
+    }
+    if let Ok(runtime) = runtime() {
+        if let Err(e) = runtime.remove_image(image).await {
+            error!("Failed to remove image {}: {}", image, e);
+        }
+    }
+}
+
+//Cleans up the images and checkpoints of a deleted agent. Images still used by agents created from them are kept.
+pub async fn remove_agent_snapshots(snapshots: &[Snapshot]) {
+    let runtime = match runtime() {
+        Ok(runtime) => runtime,
+        Err(_) => return,
+    };
+    for snapshot in snapshots {
+        if let Err(e) = runtime.remove_image(&snapshot.image).await {
+            error!("Keeping snapshot image {}: {}", snapshot.image, e);
+        }
+        if let Some(checkpoint) = &snapshot.checkpoint {
+            let _ = fs::remove_file(checkpoint);
+        }
+    }
+}
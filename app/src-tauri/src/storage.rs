@@ -0,0 +1,164 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use serde::{Serialize, de::DeserializeOwned};
+use tauri::Emitter;
+use log::{info, error};
+
+use crate::get_app_handle;
+
+//Previous versions kept next to each store as <file>.1 (newest) to <file>.N
+pub const BACKUP_COUNT: usize = 3;
+
+//Something the user should know about their stored data, e.g. a store restored from a backup
+#[derive(Serialize, Clone, Debug)]
+pub struct StorageNotice {
+    pub store: String,
+    pub message: String,
+    pub at: String,
+}
+
+//Notices since launch, kept so the frontend can fetch the ones emitted before it was listening
+static NOTICES: Lazy<Mutex<Vec<StorageNotice>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+//Records a notice and emits it as "storage-notice"
+pub fn notify(store: &str, message: String) {
+    error!("{}: {}", store, message);
+    let notice = StorageNotice {
+        store: store.to_string(),
+        message,
+        at: chrono::Utc::now().to_rfc3339(),
+    };
+    NOTICES.lock().unwrap().push(notice.clone());
+    if let Some(app_handle) = get_app_handle() {
+        let _ = app_handle.emit("storage-notice", notice);
+    }
+}
+
+pub fn get_notices() -> Vec<StorageNotice> {
+    NOTICES.lock().unwrap().clone()
+}
+
+//`path` with `suffix` appended to the file name, e.g. artifacts.json.1
+pub fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+pub fn backup_path(path: &Path, index: usize) -> PathBuf {
+    sibling(path, &format!(".{}", index))
+}
+
+//Flushes a rename to disk, not supported on Windows
+fn sync_dir(dir: &Path) {
+    #[cfg(unix)]
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+}
+
+//Replaces `path` so it holds either the old or the new contents even if the app dies half way
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), String> {
+    use std::io::Write;
+
+    let dir = path.parent().ok_or("Invalid file path")?;
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+    let temp_path = sibling(path, ".tmp");
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(format!("Failed to write {}: {}", path.display(), e));
+    }
+    sync_dir(dir);
+    Ok(())
+}
+
+//Shifts <file>.1..N up by one, dropping the oldest, so <file>.1 is free for a new backup
+pub fn rotate_backups(path: &Path) -> Result<(), String> {
+    let _ = fs::remove_file(backup_path(path, BACKUP_COUNT));
+    for index in (1..BACKUP_COUNT).rev() {
+        let from = backup_path(path, index);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, index + 1))
+                .map_err(|e| format!("Failed to rotate backup {}: {}", from.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+//When a backup was written, for telling the user what a restore rolled back to
+pub fn saved_at(path: &Path) -> String {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+        .map(|time| chrono::DateTime::<chrono::Local>::from(time).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| "an unknown time".to_string())
+}
+
+//Moves an unreadable file out of the way, keeping it for the user to inspect
+pub fn set_aside(path: &Path) -> Result<PathBuf, String> {
+    let corrupt_path = sibling(path, &format!(".corrupt-{}", chrono::Utc::now().format("%Y%m%d%H%M%S")));
+    fs::rename(path, &corrupt_path)
+        .map_err(|e| format!("Failed to move {} aside: {}", path.display(), e))?;
+    Ok(corrupt_path)
+}
+
+//Writes `value` as JSON, keeping the previous file as the newest backup
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(value)
+        .map_err(|e| format!("Failed to serialize {}: {}", path.display(), e))?;
+    if path.exists() {
+        rotate_backups(path)?;
+        fs::copy(path, backup_path(path, 1))
+            .map_err(|e| format!("Failed to back up {}: {}", path.display(), e))?;
+    }
+    write_atomic(path, contents.as_bytes())
+}
+
+//Reads a JSON store, None if there is none. A file that doesn't parse is set aside and replaced
+//with its newest readable backup; if no backup parses either the store starts over empty.
+//Either way the user gets a notice.
+pub fn read_json<T: DeserializeOwned>(path: &Path, store: &str) -> Result<Option<T>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let parse_error = match serde_json::from_str(&contents) {
+        Ok(value) => return Ok(Some(value)),
+        Err(e) => e,
+    };
+    error!("Failed to parse {}: {}", path.display(), parse_error);
+
+    let corrupt_path = set_aside(path)?;
+    for index in 1..=BACKUP_COUNT {
+        let backup = backup_path(path, index);
+        let contents = match fs::read_to_string(&backup) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        if let Ok(value) = serde_json::from_str(&contents) {
+            write_atomic(path, contents.as_bytes())?;
+            let saved_at = saved_at(&backup);
+            info!("Restored {} from {}", path.display(), backup.display());
+            notify(store, format!(
+                "Your {} could not be read and were restored from a backup saved at {}. Changes made after that are lost. The unreadable file was kept at {}.",
+                store, saved_at, corrupt_path.display()
+            ));
+            return Ok(Some(value));
+        }
+    }
+
+    notify(store, format!(
+        "Your {} could not be read and no usable backup was found, so they start out empty. The unreadable file was kept at {}.",
+        store, corrupt_path.display()
+    ));
+    Ok(None)
+}
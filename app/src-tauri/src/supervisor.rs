@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tauri::Emitter;
+use tokio::net::TcpStream;
+use log::{info, error};
+
+use crate::{websocket, Container, ContainerStatus, ContainerRuntime, CONTAINERS, get_app_handle, runtime};
+
+//How often every running agent is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const PORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+//When a failing component gets restarted and when we stop trying
+pub struct RestartPolicy {
+    //Consecutive failed checks before acting, gives components time to come up after a start
+    pub failures_before_restart: u32,
+    //Restarts allowed per component within `window` before it is marked failed
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+pub const RESTART_POLICY: RestartPolicy = RestartPolicy {
+    failures_before_restart: 2,
+    max_restarts: 3,
+    window: Duration::from_secs(15 * 60),
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Component {
+    Container,
+    Vnc,
+    Novnc,
+    Agent,
+}
+
+impl Component {
+    //supervisord program running the component inside the container
+    fn program(&self) -> Option<&'static str> {
+        match self {
+            Component::Container => None,
+            Component::Vnc => Some("vnc"),
+            Component::Novnc => Some("novnc"),
+            Component::Agent => Some("app"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ComponentHealth {
+    pub component: Component,
+    pub healthy: bool,
+    pub detail: String,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    Unhealthy,
+    Recovering,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthReport {
+    pub agent_id: String,
+    pub status: HealthStatus,
+    pub components: Vec<ComponentHealth>,
+    pub checked_at: String,
+}
+
+//Pushed to the frontend as "agent-health"
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthEvent {
+    //Sent whenever an agent's overall status changes
+    Report(HealthReport),
+    Restarting { agent_id: String, component: Component, attempt: usize },
+    GaveUp { agent_id: String, component: Component, restarts: usize },
+}
+
+#[derive(Default)]
+struct Supervision {
+    failures: HashMap<Component, u32>,
+    restarts: HashMap<Component, Vec<Instant>>,
+    last_report: Option<HealthReport>,
+}
+
+static SUPERVISION: Lazy<Mutex<HashMap<String, Supervision>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn get_agent_health(agent_id: &str) -> Option<HealthReport> {
+    SUPERVISION.lock().unwrap().get(agent_id).and_then(|s| s.last_report.clone())
+}
+
+fn emit(event: HealthEvent) {
+    if let Some(app_handle) = get_app_handle() {
+        let _ = app_handle.emit("agent-health", event);
+    }
+}
+
+async fn port_open(port: u16) -> bool {
+    matches!(tokio::time::timeout(PORT_TIMEOUT, TcpStream::connect(("127.0.0.1", port))).await, Ok(Ok(_)))
+}
+
+//Program name -> state (RUNNING, FATAL, BACKOFF, ...) from `supervisorctl status`
+async fn program_states(runtime: &dyn ContainerRuntime, container_id: &str) -> HashMap<String, String> {
+    match runtime.exec(container_id, &["supervisorctl", "status"]).await {
+        Ok(output) => output.stdout.lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                Some((parts.next()?.to_string(), parts.next()?.to_string()))
+            })
+            .collect(),
+        Err(e) => {
+            error!("Failed to read supervisor status for {}: {}", container_id, e);
+            HashMap::new()
+        }
+    }
+}
+
+async fn check_components(runtime: &dyn ContainerRuntime, container: &Container) -> Vec<ComponentHealth> {
+    let health = |component, healthy, detail: String| ComponentHealth { component, healthy, detail };
+
+    let inspect = match runtime.inspect_container(&container.id).await {
+        Ok(Some(inspect)) => inspect,
+        Ok(None) => return vec![health(Component::Container, false, "container no longer exists".to_string())],
+        Err(e) => return vec![health(Component::Container, false, e)],
+    };
+    if !inspect.state.running || inspect.state.paused {
+        let detail = format!("container is {} (exit code {})", inspect.state.status, inspect.state.exit_code);
+        return vec![health(Component::Container, false, detail)];
+    }
+
+    let mut components = vec![health(Component::Container, true, "running".to_string())];
+    let programs = program_states(runtime, &container.id).await;
+    let program_running = |component: Component| {
+        let state = component.program().and_then(|p| programs.get(p)).map(String::as_str).unwrap_or("UNKNOWN");
+        (state == "RUNNING", state.to_string())
+    };
+
+    let vnc_port = inspect.host_port(5900);
+    let (running, state) = program_running(Component::Vnc);
+    let vnc_open = match vnc_port {
+        Some(port) => port_open(port).await,
+        None => false,
+    };
+    components.push(health(Component::Vnc, running && vnc_open, format!("{}, port {:?} {}", state, vnc_port, if vnc_open { "open" } else { "closed" })));
+
+    let (running, state) = program_running(Component::Novnc);
+    let novnc_open = port_open(container.vnc_port).await;
+    components.push(health(Component::Novnc, running && novnc_open, format!("{}, port {} {}", state, container.vnc_port, if novnc_open { "open" } else { "closed" })));
+
+    let (running, state) = program_running(Component::Agent);
+    let connected = websocket::is_agent_connected(&container.agent_id);
+    components.push(health(Component::Agent, running && connected, format!("{}, websocket {}", state, if connected { "connected" } else { "disconnected" })));
+
+    components
+}
+
+async fn restart_component(runtime: &dyn ContainerRuntime, container: &Container, component: Component) -> Result<(), String> {
+    match component.program() {
+        Some(program) => {
+            let output = runtime.exec(&container.id, &["supervisorctl", "restart", program]).await?;
+            if output.exit_code != 0 {
+                return Err(format!("supervisorctl restart {} failed: {}{}", program, output.stdout, output.stderr));
+            }
+            Ok(())
+        },
+        None => {
+            let paused = runtime.inspect_container(&container.id).await?.map(|i| i.state.paused).unwrap_or(false);
+            if paused {
+                runtime.unpause_container(&container.id).await
+            } else {
+                runtime.start_container(&container.id).await
+            }
+        }
+    }
+}
+
+//Checks one agent, applies the restart policy and reports status changes
+async fn supervise_agent(runtime: &dyn ContainerRuntime, container: &Container) {
+    let components = check_components(runtime, container).await;
+
+    //Decide what to restart while holding the lock, restart after releasing it
+    let mut to_restart = Vec::new();
+    let mut gave_up = false;
+    {
+        let mut supervision = SUPERVISION.lock().unwrap();
+        let state = supervision.entry(container.agent_id.clone()).or_default();
+        for component in &components {
+            if component.healthy {
+                state.failures.remove(&component.component);
+                continue;
+            }
+            let failures = state.failures.entry(component.component).or_insert(0);
+            *failures += 1;
+            if *failures < RESTART_POLICY.failures_before_restart {
+                continue;
+            }
+
+            let restarts = state.restarts.entry(component.component).or_default();
+            restarts.retain(|at| at.elapsed() < RESTART_POLICY.window);
+            if restarts.len() >= RESTART_POLICY.max_restarts {
+                gave_up = true;
+                if *failures == RESTART_POLICY.failures_before_restart {
+                    emit(HealthEvent::GaveUp { agent_id: container.agent_id.clone(), component: component.component, restarts: restarts.len() });
+                }
+                continue;
+            }
+            restarts.push(Instant::now());
+            *failures = 0;
+            to_restart.push((component.component, restarts.len()));
+        }
+    }
+
+    for (component, attempt) in &to_restart {
+        info!("Restarting {:?} of agent {} (attempt {})", component, container.agent_id, attempt);
+        emit(HealthEvent::Restarting { agent_id: container.agent_id.clone(), component: *component, attempt: *attempt });
+        if let Err(e) = restart_component(runtime, container, *component).await {
+            error!("Failed to restart {:?} of agent {}: {}", component, container.agent_id, e);
+        }
+    }
+
+    let status = if gave_up {
+        HealthStatus::Failed
+    } else if !to_restart.is_empty() {
+        HealthStatus::Recovering
+    } else if components.iter().all(|c| c.healthy) {
+        HealthStatus::Healthy
+    } else {
+        HealthStatus::Unhealthy
+    };
+    let report = HealthReport {
+        agent_id: container.agent_id.clone(),
+        status,
+        components,
+        checked_at: chrono::Utc::now().to_rfc3339(),
+    };
+
+    let changed = {
+        let mut supervision = SUPERVISION.lock().unwrap();
+        let state = supervision.entry(container.agent_id.clone()).or_default();
+        let changed = state.last_report.as_ref().map(|r| r.status) != Some(status);
+        state.last_report = Some(report.clone());
+        changed
+    };
+    if changed {
+        emit(HealthEvent::Report(report));
+    }
+}
+
+//Periodically inspects every agent the app expects to be running
+pub async fn run_supervisor() {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        let runtime = match runtime() {
+            Ok(runtime) => runtime,
+            Err(_) => continue,
+        };
+        let containers: Vec<Container> = CONTAINERS.lock().unwrap().iter()
+            .filter(|c| c.status == ContainerStatus::Running)
+            .cloned()
+            .collect();
+
+        //Forget agents that were deleted or stopped on purpose
+        SUPERVISION.lock().unwrap().retain(|agent_id, _| containers.iter().any(|c| &c.agent_id == agent_id));
+
+        for container in &containers {
+            supervise_agent(runtime.as_ref(), container).await;
+        }
+    }
+}
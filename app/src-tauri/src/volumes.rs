@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use log::{info, error};
+
+use crate::{Container, ContainerRuntime, MountSpec, CONTAINERS, runtime};
+
+//Engine volume names, prefixed so the app only lists and deletes its own
+const AGENT_VOLUME_PREFIX: &str = "radah-agent-";
+const SHARED_VOLUME_PREFIX: &str = "radah-shared-";
+
+//Where a mount's files live
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MountSource {
+    //Volume owned by one agent and deleted with it
+    AgentVolume { name: String },
+    //Named volume any agent can mount
+    SharedVolume { name: String },
+    //Directory on the host, created if missing
+    HostPath { path: String },
+}
+
+//A volume or host directory mounted into an agent's container
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Mount {
+    pub source: MountSource,
+    //Absolute path inside the container
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl Mount {
+    //Engine volume backing the mount, None for host directories
+    fn volume_name(&self, agent_id: &str) -> Option<String> {
+        match &self.source {
+            MountSource::AgentVolume { name } => Some(format!("{}{}-{}", AGENT_VOLUME_PREFIX, agent_id, name)),
+            MountSource::SharedVolume { name } => Some(format!("{}{}", SHARED_VOLUME_PREFIX, name)),
+            MountSource::HostPath { .. } => None,
+        }
+    }
+
+    pub fn spec(&self, agent_id: &str) -> MountSpec {
+        let source = match &self.source {
+            MountSource::HostPath { path } => path.clone(),
+            _ => self.volume_name(agent_id).unwrap_or_default(),
+        };
+        MountSpec { source, target: self.target.clone(), read_only: self.read_only }
+    }
+}
+
+//Volume names end up in engine names, so keep them to what both engines accept
+fn valid_volume_name(name: &str) -> bool {
+    name.chars().next().map(|c| c.is_ascii_alphanumeric()).unwrap_or(false)
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+//Checks mount paths and names without touching the host or the engine
+pub fn validate_mounts(mounts: &[Mount]) -> Result<(), String> {
+    let mut targets = Vec::new();
+    for mount in mounts {
+        if !mount.target.starts_with('/') || mount.target == "/" || mount.target.contains(':') {
+            return Err(format!("Invalid mount target: {}", mount.target));
+        }
+        if targets.contains(&mount.target.trim_end_matches('/')) {
+            return Err(format!("{} is mounted more than once", mount.target));
+        }
+        targets.push(mount.target.trim_end_matches('/'));
+
+        match &mount.source {
+            MountSource::AgentVolume { name } | MountSource::SharedVolume { name } => {
+                if !valid_volume_name(name) {
+                    return Err(format!("Invalid volume name: {}", name));
+                }
+            },
+            MountSource::HostPath { path } => {
+                if !Path::new(path).is_absolute() || path.contains(':') {
+                    return Err(format!("Invalid host directory: {}", path));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//Creates the volumes and missing host directories an agent's mounts need before its container is run
+pub async fn create_agent_volumes(runtime: &dyn ContainerRuntime, container: &Container) -> Result<(), String> {
+    for mount in &container.mounts {
+        if let MountSource::HostPath { path } = &mount.source {
+            fs::create_dir_all(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        }
+    }
+    for name in container.mounts.iter().filter_map(|m| m.volume_name(&container.agent_id)) {
+        runtime.create_volume(&name).await?;
+    }
+    Ok(())
+}
+
+//New volumes are mounted as root, hand them to the desktop user so the agent can write to them.
+//Host directories are left alone so rootless podman doesn't remap their owner on the host.
+pub async fn chown_agent_volumes(runtime: &dyn ContainerRuntime, container: &Container) {
+    for mount in container.mounts.iter().filter(|m| !m.read_only && m.volume_name(&container.agent_id).is_some()) {
+        match runtime.exec(&container.id, &["chown", "vncuser:vncuser", &mount.target]).await {
+            Ok(output) if output.exit_code != 0 => error!("Failed to chown {}: {}", mount.target, output.stderr.trim()),
+            Err(e) => error!("Failed to chown {}: {}", mount.target, e),
+            Ok(_) => {},
+        }
+    }
+}
+
+//Deletes the agent-owned volumes in `mounts`; call after the container using them is gone
+pub async fn remove_agent_volumes(agent_id: &str, mounts: &[Mount]) {
+    let runtime = match runtime() {
+        Ok(runtime) => runtime,
+        Err(_) => return,
+    };
+    for mount in mounts.iter().filter(|m| matches!(m.source, MountSource::AgentVolume { .. })) {
+        if let Some(name) = mount.volume_name(agent_id) {
+            info!("Removing volume {}", name);
+            if let Err(e) = runtime.remove_volume(&name).await {
+                error!("Failed to remove volume {}: {}", name, e);
+            }
+        }
+    }
+}
+
+pub async fn list_shared_volumes() -> Result<Vec<String>, String> {
+    Ok(runtime()?.list_volumes().await?
+        .into_iter()
+        .filter_map(|name| name.strip_prefix(SHARED_VOLUME_PREFIX).map(str::to_string))
+        .collect())
+}
+
+pub async fn create_shared_volume(name: &str) -> Result<(), String> {
+    if !valid_volume_name(name) {
+        return Err(format!("Invalid volume name: {}", name));
+    }
+    runtime()?.create_volume(&format!("{}{}", SHARED_VOLUME_PREFIX, name)).await
+}
+
+//Refuses while any agent still mounts the volume
+pub async fn delete_shared_volume(name: &str) -> Result<(), String> {
+    let shared = MountSource::SharedVolume { name: name.to_string() };
+    let user = CONTAINERS.lock().unwrap().iter()
+        .find(|c| c.mounts.iter().any(|m| m.source == shared))
+        .map(|c| c.agent_name.clone());
+    if let Some(agent_name) = user {
+        return Err(format!("Volume {} is mounted by {}", name, agent_name));
+    }
+    runtime()?.remove_volume(&format!("{}{}", SHARED_VOLUME_PREFIX, name)).await
+}
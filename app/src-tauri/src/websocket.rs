@@ -1,28 +1,74 @@
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use warp::Filter;
 use futures::{StreamExt, SinkExt};
 use tokio::sync::Mutex as AsyncMutex;
-use serde_json;
 use uuid;
 use once_cell::sync::Lazy;
 
 
-use crate::{MESSAGES, CONTAINERS, save_messages, get_app_handle};
+use crate::{auth, db, delivery, frames, run_state, scheduler, MESSAGES, CONTAINERS, runtime};
+use log::{info, error};
 
-//import save_containers and get_recent_agent_messages from helpers
-use crate::helpers::{save_containers, get_recent_agent_messages};
+//import get_recent_agent_messages from helpers
+use crate::helpers::get_recent_agent_messages;
+use crate::lifecycle::{touch_agent, container_status, wake_agent, ContainerStatus};
+use crate::artifacts::{handle_file_list, handle_file_chunk, handle_file_error};
+use crate::run_state::AgentRunState;
+use crate::scheduler::RunOutcome;
+use crate::protocol::{self, AgentMessage, AgentPrompt, ChatMessage, Chunk, ConnectionType, Inbound, Init, Outbound, Prompt, QueuedPrompt, ServerMessage, ToAgent};
 
 
 
-//Websocket server
-static CLIENT_CONNECTION: Lazy<Arc<AsyncMutex<Option<Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>>>>> = 
-    Lazy::new(|| Arc::new(AsyncMutex::new(None)));
+//Port used unless RADAH_WS_PORT says otherwise, and the one agents created before it was configurable connect to
+pub const DEFAULT_WS_PORT: u16 = 3030;
 
+//Port to listen on, also how agents get the port from their environment
+pub const WS_PORT_ENV: &str = "RADAH_WS_PORT";
 
+//How far past the configured port to look for a free one, also passed to agents so they search the same ports
+pub const PORT_SEARCH_RANGE: u16 = 100;
+pub const PORT_RANGE_ENV: &str = "RADAH_WS_PORT_RANGE";
+
+//Read once per launch. Agents are given this port, not the one the server ends up on.
+static CONFIGURED_PORT: Lazy<u16> = Lazy::new(read_configured_port);
+
+//Chosen once per launch, so the frontend always gets the port the server listens on
+static WS_PORT: Lazy<u16> = Lazy::new(choose_port);
+
+//Loopback listener bound while choosing the port and served from once the server starts,
+//so nothing else can take the port in between
+static LOOPBACK_LISTENER: Lazy<Mutex<Option<std::net::TcpListener>>> = Lazy::new(|| Mutex::new(None));
+
+//How often connections are pinged, and how long one may go without sending anything, pongs included,
+//before it counts as dead. Paused or frozen containers keep their TCP connection open but stop answering.
+//Overridable in seconds with RADAH_WS_PING_INTERVAL and RADAH_WS_PING_TIMEOUT.
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(45);
+static PING_INTERVAL: Lazy<Duration> = Lazy::new(|| duration_from_env("RADAH_WS_PING_INTERVAL", DEFAULT_PING_INTERVAL));
+static PING_TIMEOUT: Lazy<Duration> = Lazy::new(|| duration_from_env("RADAH_WS_PING_TIMEOUT", DEFAULT_PING_TIMEOUT));
+
+//How long closing a dead connection may take, its peer won't be reading
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+//Frontend window and the agents it is subscribed to
+struct ClientInfo {
+    tx: Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
+    agent_ids: HashSet<String>,
+}
+
+//Connected frontend clients by connection id
+static CLIENTS: Lazy<Arc<AsyncMutex<std::collections::HashMap<String, ClientInfo>>>> =
+    Lazy::new(|| Arc::new(AsyncMutex::new(std::collections::HashMap::new())));
+
+
+// Latest connection of an agent, kept after it closes so messages sent meanwhile are buffered for replay.
+// Whether the agent is connected is tracked in AGENT_PRESENCE.
 #[derive(Debug)]
 pub struct ConnectionInfo {
     pub tx: Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
-    pub prompt_running: String, //"running", "stopped", "loading", "na"
 }
 
 
@@ -35,229 +81,951 @@ pub static AGENT_CONNECTIONS: Lazy<Arc<AsyncMutex<std::collections::HashMap<Stri
 static ID_BY_CONNECTION: Lazy<Arc<AsyncMutex<std::collections::HashMap<String, String>>>> =
     Lazy::new(|| Arc::new(AsyncMutex::new(std::collections::HashMap::new())));
 
+// A prompt from the frontend waiting for its agent, stored in the conversation once it is sent
+struct PendingPrompt {
+    id: String,
+    prompt: Prompt,
+    queued_at: String,
+}
 
+// Prompts waiting for their agent to finish the one it is running, next first
+type PromptQueues = std::collections::HashMap<String, VecDeque<PendingPrompt>>;
+static PROMPT_QUEUES: Lazy<Arc<AsyncMutex<PromptQueues>>> =
+    Lazy::new(|| Arc::new(AsyncMutex::new(std::collections::HashMap::new())));
+
+// Id of the queued prompt each agent was last given, until it finishes
+static RUNNING_PROMPTS: Lazy<Mutex<std::collections::HashMap<String, String>>> =
+    Lazy::new(|| Mutex::new(std::collections::HashMap::new()));
+
+// Whether each agent has an open connection and how many times it finished init this launch.
+// Kept apart from the run state, which is Queued while a woken agent is still starting.
+#[derive(Default)]
+struct AgentPresence {
+    connected: bool,
+    connections: u64,
+}
+
+static AGENT_PRESENCE: Lazy<Mutex<std::collections::HashMap<String, AgentPresence>>> =
+    Lazy::new(|| Mutex::new(std::collections::HashMap::new()));
 
 
-pub async fn start_websocket_server() {
-    let app_handle = get_app_handle().expect("Failed to get app handle");
-    let app_handle = warp::any().map(move || app_handle.clone());
 
-    // WebSocket route
-    let ws_route = warp::path("ws")
-        .and(warp::ws())
-        .and(app_handle.clone())
-        .map(|ws: warp::ws::Ws, handle| {
-            ws.on_upgrade(move |socket| handle_websocket(socket, handle))
-        });
-    println!("Server starting on http://0.0.0.0:3030");
-    println!("WebSocket endpoint: ws://0.0.0.0:3030/ws");
 
-    warp::serve(ws_route).run(([0, 0, 0, 0], 3030)).await;
+pub fn ws_port() -> u16 {
+    *WS_PORT
 }
 
-async fn handle_websocket(websocket: warp::ws::WebSocket, app_handle: tauri::AppHandle) {
-    let (ws_tx, mut rx) = websocket.split();
-    let tx = Arc::new(AsyncMutex::new(ws_tx));
-    let conn_id = uuid::Uuid::new_v4().to_string();
+pub fn configured_port() -> u16 {
+    *CONFIGURED_PORT
+}
 
-    while let Some(Ok(message)) = rx.next().await {
-        if let Ok(text) = message.to_str() {
-            let truncated_text = text.chars().take(250).collect::<String>();
-            println!("[INFO] Received message: {}", truncated_text);
-            if let Ok(json) = serde_json::from_str::<serde_json::Value>(text) {
-                if let Some("init") = json.get("message-type").and_then(|v| v.as_str()) {
-                    handle_init_message(&conn_id, &tx, &json).await;
-                } else if let Some("message") = json.get("message-type").and_then(|v| v.as_str()) {
-                    handle_agent_message(&conn_id, &tx, json, app_handle.clone()).await;
-                } else if let Some("prompt") = json.get("message-type").and_then(|v| v.as_str()) {
-                    handle_client_message(&conn_id, &tx, json, app_handle.clone(), true).await;
-                } else if let Some("stop") = json.get("message-type").and_then(|v| v.as_str()) {
-                    handle_client_message(&conn_id, &tx, json, app_handle.clone(), false).await;
-                }
+fn read_configured_port() -> u16 {
+    match std::env::var(WS_PORT_ENV) {
+        Ok(port) => match port.trim().parse::<u16>() {
+            Ok(port) if port != 0 => port,
+            _ => {
+                error!("Ignoring invalid {}: {}", WS_PORT_ENV, port);
+                DEFAULT_WS_PORT
+            }
+        },
+        Err(_) => DEFAULT_WS_PORT,
+    }
+}
+
+// The configured port or, when something else holds it (e.g. another instance of the app), the next free one.
+// Agents search the same range at connect time, so a fallback port doesn't mean recreating them.
+fn choose_port() -> u16 {
+    let configured = configured_port();
+    let bound = (configured..=configured.saturating_add(PORT_SEARCH_RANGE))
+        .find_map(|port| std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port)).ok().map(|listener| (port, listener)));
+    match bound {
+        Some((port, listener)) => {
+            if port != configured {
+                error!("WebSocket port {} is in use, listening on {} instead", configured, port);
             }
+            *LOOPBACK_LISTENER.lock().unwrap() = Some(listener);
+            port
+        },
+        None => {
+            error!("No free WebSocket port between {} and {}", configured, configured.saturating_add(PORT_SEARCH_RANGE));
+            configured
         }
     }
+}
 
-    handle_disconnection(&conn_id).await;
+// Lets the frontend connect to the websocket server and nothing else on localhost. The port is
+// only known at launch, so the policy's default port is replaced before the app starts.
+pub fn restrict_csp(config: &mut tauri::Config) {
+    if let Some(tauri::utils::config::Csp::Policy(policy)) = &mut config.app.security.csp {
+        *policy = policy.replace(&format!("ws://localhost:{}", DEFAULT_WS_PORT), &format!("ws://localhost:{}", ws_port()));
+    }
 }
 
-async fn handle_disconnection(conn_id: &str) {
-    let mut agent_conns = AGENT_CONNECTIONS.lock().await;
-    let id_conns = ID_BY_CONNECTION.lock().await;
+fn duration_from_env(name: &str, default: Duration) -> Duration {
+    match std::env::var(name) {
+        Ok(seconds) => match seconds.trim().parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+            _ => {
+                error!("Ignoring invalid {}: {}", name, seconds);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
 
-    if let Some(agent_id) = id_conns.get(conn_id) {
-        let tx = if let Some(conn_info) = agent_conns.get(agent_id) {
-            conn_info.tx.clone()
-        } else {
-            return;
-        };
+// WebSocket route, shared by agents and the frontend
+fn ws_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("ws")
+        .and(warp::ws())
+        .map(|ws: warp::ws::Ws| ws.on_upgrade(handle_websocket))
+}
+
+pub async fn start_websocket_server() {
+    let ws_route = ws_route();
 
-        agent_conns.insert(agent_id.to_string(), ConnectionInfo {
-            tx,
-            prompt_running: "na".to_string(),
+    // Containers reach the host through loopback forwarding (Docker Desktop, podman machine, rootless
+    // podman) or the bridge gateway (rootful engines on Linux), so there is no need to listen anywhere else
+    let port = ws_port();
+    let mut loopback = LOOPBACK_LISTENER.lock().unwrap().take();
+    let mut servers = Vec::new();
+    for address in listen_addresses().await {
+        let listener = match loopback.take() {
+            Some(listener) if address == IpAddr::V4(Ipv4Addr::LOCALHOST) => Ok(listener),
+            other => {
+                loopback = other;
+                std::net::TcpListener::bind((address, port))
+            }
+        };
+        let listener = listener.and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            tokio::net::TcpListener::from_std(listener)
         });
+        match listener {
+            Ok(listener) => {
+                info!("WebSocket endpoint: ws://{}:{}/ws", address, port);
+                servers.push(warp::serve(ws_route.clone()).run_incoming(accept_connections(listener)));
+            },
+            Err(e) => error!("Failed to listen on {}:{}: {}", address, port, e),
+        }
+    }
+    futures::future::join_all(servers).await;
+}
 
-        if let Some(client_conn) = CLIENT_CONNECTION.lock().await.as_ref() {
-            let message = serde_json::json!({
-                "agent_id": agent_id,
-                "prompt_running": "na"
-            });
-            let json_string = serde_json::to_string(&message).unwrap();
-            if let Err(e) = client_conn.lock().await.send(warp::ws::Message::text(json_string)).await {
-                eprintln!("Error sending disconnect message to client: {}", e);
+// Connections on a listener as warp serves them. A failed accept (e.g. out of file descriptors)
+// is skipped instead of ending the server.
+fn accept_connections(listener: tokio::net::TcpListener) -> impl futures::Stream<Item = std::io::Result<tokio::net::TcpStream>> {
+    futures::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => {
+                    error!("Failed to accept websocket connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    })
+}
+
+// Loopback plus the engine's bridge gateway on Linux, or the addresses in RADAH_WS_BIND
+async fn listen_addresses() -> Vec<IpAddr> {
+    if let Ok(configured) = std::env::var("RADAH_WS_BIND") {
+        return configured.split(',')
+            .filter_map(|address| match address.trim().parse() {
+                Ok(address) => Some(address),
+                Err(_) => {
+                    error!("Ignoring invalid RADAH_WS_BIND address: {}", address);
+                    None
+                }
+            })
+            .collect();
+    }
+
+    let mut addresses = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+    if cfg!(target_os = "linux") {
+        if let Ok(runtime) = runtime() {
+            if let Some(gateway) = runtime.bridge_gateway().await {
+                addresses.push(gateway);
             }
         }
     }
+    addresses
 }
 
-async fn handle_init_message(
-    conn_id: &str,
-    tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
-    json: &serde_json::Value,
-) {
-    if let Some(conn_type) = json.get("connection-type").and_then(|v| v.as_str()) {
-        match conn_type {
-            "agent" => {
-                if let Some(agent_id) = json.get("container_id").and_then(|v| v.as_str()) {
-                    let mut agent_conns = AGENT_CONNECTIONS.lock().await;
-                    let mut id_conns = ID_BY_CONNECTION.lock().await;
-
-                    let prompt_running = json.get("prompt_running").and_then(|v| v.as_str()).unwrap_or("stopped").to_string();
-                    
-                    agent_conns.insert(agent_id.to_string(), ConnectionInfo {
-                        tx: tx.clone(),
-                        prompt_running: prompt_running,
-                    });
-                    
-                    id_conns.insert(conn_id.to_string(), agent_id.to_string());
-                    if let Some(client_conn) = CLIENT_CONNECTION.lock().await.as_ref() {
-                        let json_string = serde_json::to_string(&json).unwrap();
-                        client_conn.lock().await.send(warp::ws::Message::text(json_string)).await.unwrap();
+async fn handle_websocket(websocket: warp::ws::WebSocket) {
+    let (ws_tx, mut rx) = websocket.split();
+    let tx = Arc::new(AsyncMutex::new(ws_tx));
+    let conn_id = uuid::Uuid::new_v4().to_string();
+    // Set by a successful init, which decides what else the connection may send
+    let mut role: Option<ConnectionType> = None;
+    let mut heartbeat = tokio::time::interval(*PING_INTERVAL);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_seen = Instant::now();
+    let mut frames = frames::Reassembler::default();
+
+    loop {
+        let message = tokio::select! {
+            message = rx.next() => match message {
+                Some(Ok(message)) => message,
+                _ => break,
+            },
+            _ = heartbeat.tick() => {
+                // A silent connection is treated like a closed one, so its agent shows as disconnected
+                if last_seen.elapsed() > *PING_TIMEOUT {
+                    error!("No heartbeat on {:?} connection {} for {:?}, closing it", role, conn_id, *PING_TIMEOUT);
+                    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async { tx.lock().await.close().await }).await;
+                    break;
+                }
+                if let Err(e) = tx.lock().await.send(warp::ws::Message::ping(Vec::new())).await {
+                    error!("Error pinging connection {}: {}", conn_id, e);
+                    break;
+                }
+                continue;
+            },
+        };
+        last_seen = Instant::now();
+
+        // Big messages from agents come in binary frames, handled like text once they are all in
+        let text = if message.is_binary() && role == Some(ConnectionType::Agent) {
+            match frames.push(message.as_bytes()) {
+                Ok(Some(data)) => match String::from_utf8(data) {
+                    Ok(text) => text,
+                    Err(_) => {
+                        let _ = send(&tx, &protocol::error("Message is not valid UTF-8", None)).await;
+                        continue;
                     }
+                },
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Dropped binary frame on connection {}: {}", conn_id, e);
+                    let _ = send(&tx, &protocol::error(e, None)).await;
+                    continue;
                 }
+            }
+        } else {
+            match message.to_str() {
+                Ok(text) => text.to_string(),
+                Err(_) => continue,
+            }
+        };
+        let text = text.as_str();
+        let (seq, inbound) = match protocol::decode(text) {
+            Ok((_, inbound)) if inbound.sender().is_some() && inbound.sender() != role => {
+                (None, Err(format!("{:?} messages are not allowed on this connection", inbound.sender().unwrap())))
             },
-            "client" => {
-                let mut client_conn = CLIENT_CONNECTION.lock().await;
-                *client_conn = Some(tx.clone());
+            Ok((seq, inbound)) => (seq, Ok(inbound)),
+            Err(e) => (None, Err(e)),
+        };
+        // Connections that fail to authenticate are closed
+        if let Ok(Inbound::Init(init)) = inbound {
+            let connection_type = init.connection_type;
+            match handle_init_message(&conn_id, &tx, init).await {
+                Ok(()) => role = Some(connection_type),
+                Err(e) => {
+                    error!("Rejected {:?} init on connection {}: {}", connection_type, conn_id, e);
+                    let _ = send(&tx, &protocol::error(e, None)).await;
+                    break;
+                }
+            }
+            continue;
+        }
+
+        // A numbered agent message is handled once, replays of it after a reconnect are only acknowledged again
+        let sequenced = match seq {
+            Some(seq) => agent_of(&conn_id).await.map(|agent_id| (seq, delivery::session(&agent_id))),
+            None => None,
+        };
+        if let Some((seq, session)) = &sequenced {
+            if !session.lock().await.is_new(*seq) {
+                let _ = send(&tx, &Outbound::Server(ServerMessage::Ack { seq: *seq })).await;
+                continue;
+            }
+        }
+
+        let result = match inbound {
+            Ok(Inbound::Init(_)) => Ok(()),
+            Ok(Inbound::Message(message)) => handle_agent_message(&conn_id, message).await,
+            Ok(Inbound::Prompt(prompt)) => handle_client_message(ChatMessage::Prompt(prompt)).await,
+            Ok(Inbound::Stop(stop)) => handle_client_message(ChatMessage::Stop(stop)).await,
+            Ok(Inbound::Subscribe { agent_ids }) => update_subscriptions(&conn_id, agent_ids, true).await,
+            Ok(Inbound::Unsubscribe { agent_ids }) => update_subscriptions(&conn_id, agent_ids, false).await,
+            Ok(Inbound::FileList { request_id, files }) => {
+                handle_file_list(&request_id, files);
+                Ok(())
             },
-            _ => println!("Unknown connection type: {}", conn_type)
+            Ok(Inbound::FileChunk { request_id, chunk, total_chunks, data }) => {
+                handle_file_chunk(&request_id, chunk, total_chunks, data);
+                Ok(())
+            },
+            Ok(Inbound::FileError { request_id, error }) => {
+                handle_file_error(&request_id, error);
+                Ok(())
+            },
+            Ok(Inbound::Ack { seq }) => match agent_of(&conn_id).await {
+                Some(agent_id) => {
+                    delivery::session(&agent_id).lock().await.ack(seq);
+                    Ok(())
+                },
+                None => Err("Connection has not sent an agent init message".to_string()),
+            },
+            Err(e) => Err(e),
+        };
+
+        // Acknowledged even if it was rejected, sending it again wouldn't change that
+        if let Some((seq, session)) = sequenced {
+            session.lock().await.received = seq;
+            if let Err(e) = send(&tx, &Outbound::Server(ServerMessage::Ack { seq })).await {
+                error!("Error acknowledging message {} on connection {}: {}", seq, conn_id, e);
+            }
+        }
+
+        // Tell the sender instead of dropping the message
+        if let Err(e) = result {
+            error!("Rejected message on connection {}: {}", conn_id, e);
+            if let Err(e) = send(&tx, &protocol::error(e, None)).await {
+                error!("Error sending error reply: {}", e);
+            }
+        }
+    }
+
+    handle_disconnection(&conn_id, &tx).await;
+}
+
+async fn send(
+    tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
+    message: &Outbound,
+) -> Result<(), String> {
+    tx.lock().await.send(warp::ws::Message::text(protocol::encode(message))).await
+        .map_err(|e| e.to_string())
+}
+
+// Sends a message to every client subscribed to its agent, or to all clients if it isn't about one
+async fn send_to_clients(message: Outbound) {
+    let subscribers: Vec<_> = CLIENTS.lock().await.iter()
+        .filter(|(_, client)| message.agent_id().map(|id| client.agent_ids.contains(id)).unwrap_or(true))
+        .map(|(conn_id, client)| (conn_id.clone(), client.tx.clone()))
+        .collect();
+    for (conn_id, tx) in subscribers {
+        if let Err(e) = send(&tx, &message).await {
+            error!("Error sending message to client {}: {}", conn_id, e);
+        }
+    }
+}
+
+// Changes the agents a client receives updates for
+async fn update_subscriptions(conn_id: &str, agent_ids: Vec<String>, subscribe: bool) -> Result<(), String> {
+    let mut clients = CLIENTS.lock().await;
+    let client = clients.get_mut(conn_id).ok_or("Connection has not sent a client init message")?;
+    for agent_id in agent_ids {
+        if subscribe {
+            client.agent_ids.insert(agent_id);
+        } else {
+            client.agent_ids.remove(&agent_id);
         }
     }
+    Ok(())
 }
 
-async fn handle_agent_message(
+async fn handle_disconnection(
     conn_id: &str,
-    _tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
-    mut json_message: serde_json::Value,
-    app_handle: tauri::AppHandle,
+    conn_tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
 ) {
-    if let Some(agent_id) = ID_BY_CONNECTION.lock().await.get(conn_id).cloned() {
-        let message_id = uuid::Uuid::new_v4().to_string();
-
-        if let serde_json::Value::Object(ref mut map) = json_message {
-            map.insert("agent_id".to_string(), serde_json::Value::String(agent_id.clone()));
-            map.insert("message_id".to_string(), serde_json::Value::String(message_id.clone()));
-            if let Some(prompt_running) = map.get("prompt_running").and_then(|v| v.as_str()) {
-                if let Some(agent_conn) = AGENT_CONNECTIONS.lock().await.get_mut(&agent_id) {
-                    agent_conn.prompt_running = prompt_running.to_string();
-                }
+    CLIENTS.lock().await.remove(conn_id);
+
+    // The connections lock keeps a reconnect from slipping in until this one is marked gone,
+    // but isn't held while clients are told
+    let agent_id = {
+        let agent_conns = AGENT_CONNECTIONS.lock().await;
+        let agent_id = ID_BY_CONNECTION.lock().await.remove(conn_id);
+        // A connection that went silent may close after its agent already reconnected
+        let agent_id = match agent_id {
+            Some(agent_id) if agent_conns.get(&agent_id).is_some_and(|conn_info| Arc::ptr_eq(&conn_info.tx, conn_tx)) => agent_id,
+            _ => return,
+        };
+        if let Some(presence) = AGENT_PRESENCE.lock().unwrap().get_mut(&agent_id) {
+            presence.connected = false;
+        }
+        run_state::set(&agent_id, AgentRunState::Disconnected);
+        agent_id
+    };
+    send_status(&agent_id, AgentRunState::Disconnected).await;
+}
+
+pub fn is_agent_connected(agent_id: &str) -> bool {
+    AGENT_PRESENCE.lock().unwrap().get(agent_id).map(|p| p.connected).unwrap_or(false)
+}
+
+// Counts agent inits, so waiting for a reconnect can tell a new connection from one that was already open
+pub fn agent_connections(agent_id: &str) -> u64 {
+    AGENT_PRESENCE.lock().unwrap().get(agent_id).map(|p| p.connections).unwrap_or(0)
+}
+
+async fn send_status(agent_id: &str, state: AgentRunState) {
+    send_to_clients(Outbound::Server(ServerMessage::Status {
+        agent_id: agent_id.to_string(),
+        prompt_running: state,
+    })).await;
+}
+
+// Moves an agent to another run state and tells its clients, failing if the transition isn't allowed
+async fn change_run_state(agent_id: &str, state: AgentRunState) -> Result<(), String> {
+    if run_state::transition(agent_id, state)? != state {
+        send_status(agent_id, state).await;
+    }
+    Ok(())
+}
+
+async fn handle_init_message(
+    conn_id: &str,
+    tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
+    init: Init,
+) -> Result<(), String> {
+    match init.connection_type {
+        ConnectionType::Agent => {
+            let agent_id = init.container_id.ok_or("Agent init is missing container_id")?;
+            if !auth::verify_agent(&agent_id, init.token.as_deref()) {
+                return Err(format!("Invalid token for agent {}", agent_id));
+            }
+            {
+                let mut agent_conns = AGENT_CONNECTIONS.lock().await;
+                let mut id_conns = ID_BY_CONNECTION.lock().await;
+
+                agent_conns.insert(agent_id.clone(), ConnectionInfo { tx: tx.clone() });
+                id_conns.insert(conn_id.to_string(), agent_id.clone());
             }
+
+            let session = delivery::session(&agent_id);
+            let mut session = session.lock().await;
+            let resumed = init.session_id.as_deref() == Some(session.id.as_str());
+
+            // What the agent says it is doing wins, except that a prompt taken off the queue for it is still coming,
+            // and an agent that restarted in the middle of a prompt lost it. Decided under the queue lock, see deliver_prompt.
+            let reported = init.prompt_running.unwrap_or_default();
+            let queues = PROMPT_QUEUES.lock().await;
+            let outstanding = RUNNING_PROMPTS.lock().unwrap().contains_key(&agent_id);
+            let state = match (reported, run_state::get(&agent_id), run_state::last_known(&agent_id)) {
+                (AgentRunState::Idle, AgentRunState::Queued, _) if outstanding => AgentRunState::Queued,
+                (AgentRunState::Idle, _, AgentRunState::Running | AgentRunState::Stopping) if !resumed => AgentRunState::Errored,
+                _ => reported,
+            };
+            run_state::set(&agent_id, state);
+            drop(queues);
+            send_status(&agent_id, state).await;
+            match state {
+                AgentRunState::Errored => finish_running_prompt(&agent_id, RunOutcome::Failed, Some("The agent restarted during the prompt")),
+                AgentRunState::Idle => finish_running_prompt(&agent_id, RunOutcome::Succeeded, None),
+                _ => {},
+            }
+
+            // Tell the agent where to pick up, then replay what it missed while disconnected
+            let missed = session.resume(&agent_id, init.session_id.as_deref(), init.last_seq);
+            session.binary_frames = init.binary_frames;
+            send(tx, &Outbound::Server(ServerMessage::Session {
+                session_id: session.id.clone(),
+                last_seq: session.received,
+            })).await?;
+            if !missed.is_empty() {
+                info!("Replaying {} messages to agent {}", missed.len(), agent_id);
+            }
+            for (_, encoded) in missed {
+                send_chunked(tx, &encoded, session.binary_frames).await?;
+            }
+            drop(session);
+            {
+                let mut presence = AGENT_PRESENCE.lock().unwrap();
+                let presence = presence.entry(agent_id.clone()).or_default();
+                presence.connected = true;
+                presence.connections += 1;
+            }
+
+            // Prompts queued while it was away
+            dispatch_next(&agent_id).await;
+        },
+        ConnectionType::Client => {
+            if !auth::verify_client(init.token.as_deref()) {
+                return Err("Invalid client token".to_string());
+            }
+            CLIENTS.lock().await.insert(conn_id.to_string(), ClientInfo {
+                tx: tx.clone(),
+                agent_ids: init.subscribe.into_iter().collect(),
+            });
+        },
+    }
+    Ok(())
+}
+
+// Agent a connection belongs to, None for clients and connections that haven't sent init
+async fn agent_of(conn_id: &str) -> Option<String> {
+    ID_BY_CONNECTION.lock().await.get(conn_id).cloned()
+}
+
+async fn handle_agent_message(conn_id: &str, message: AgentMessage) -> Result<(), String> {
+    let agent_id = agent_of(conn_id).await
+        .ok_or("Connection has not sent an agent init message")?;
+    touch_agent(&agent_id);
+
+    // The message is kept even if the state it reports makes no sense
+    let reported = message.prompt_running;
+    let previous = run_state::get(&agent_id);
+    if let Some(state) = reported {
+        if let Err(e) = change_run_state(&agent_id, state).await {
+            error!("Ignoring run state reported by agent: {}", e);
         }
-        process_message(message_id, json_message, &agent_id, &app_handle).await;
     }
+    let message_id = uuid::Uuid::new_v4().to_string();
+    process_message(message_id, ChatMessage::Message(message), &agent_id).await;
+
+    // The agent finished, so the next prompt can go
+    match reported {
+        Some(AgentRunState::Idle) => {
+            let outcome = if previous == AgentRunState::Stopping { RunOutcome::Stopped } else { RunOutcome::Succeeded };
+            finish_running_prompt(&agent_id, outcome, None);
+            dispatch_next(&agent_id).await;
+        },
+        Some(AgentRunState::Errored) => {
+            finish_running_prompt(&agent_id, RunOutcome::Failed, Some("The agent reported an error"));
+            dispatch_next(&agent_id).await;
+        },
+        _ => {},
+    }
+    Ok(())
 }
 
-async fn handle_client_message(
-    _conn_id: &str,
-    _tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
-    mut json: serde_json::Value,
-    app_handle: tauri::AppHandle,
-    is_prompt: bool,
-) {
-    let agent_id = json.get("agent_id")
-        .and_then(|v| v.as_str())
-        .map(String::from);
-
-    if let Some(agent_id_value) = agent_id {
-        let mut agent_conns = AGENT_CONNECTIONS.lock().await;
-        if let Some(conn_info) = agent_conns.get_mut(&agent_id_value) {
-            conn_info.prompt_running = "running".to_string();
-            
-            let mut json_with_history = json.clone();
-            if is_prompt {
-                if let serde_json::Value::Object(ref mut map) = json_with_history {
-                    let recent_messages = get_recent_agent_messages(agent_id_value.clone(), 5);
-                    map.insert("recent-messages".to_string(), serde_json::Value::Array(recent_messages));
-
-                    // Get system prompt from CONTAINERS
-                    let containers = CONTAINERS.lock().unwrap();
-                    if let Some(container) = containers.iter().find(|c| c.agent_id == agent_id_value) {
-                        map.insert(
-                            "additional_system_prompt".to_string(), 
-                            serde_json::Value::String(container.system_prompt.clone())
-                        );
-                        println!("System prompt: {}", container.system_prompt);
-                    }
-                }
+// Handles prompts and stops from the frontend. Prompts go through the agent's queue.
+async fn handle_client_message(message: ChatMessage) -> Result<(), String> {
+    let agent_id = message.agent_id().to_string();
+    match message {
+        ChatMessage::Prompt(prompt) => submit_prompt(uuid::Uuid::new_v4().to_string(), prompt).await,
+        ChatMessage::Stop(stop) => {
+            check_agent(&agent_id)?;
+            // Only a running prompt can be stopped, queued ones are cancelled.
+            // The agent stays Running unless the stop actually reached it.
+            run_state::can_transition(&agent_id, AgentRunState::Stopping)?;
+            if !deliver_client_message(ChatMessage::Stop(stop), agent_id.clone()).await {
+                return Err(format!("Could not send the stop to agent {}", agent_id));
             }
+            change_run_state(&agent_id, AgentRunState::Stopping).await
+        },
+        ChatMessage::Message(_) => Err("Only agents can send messages".to_string()),
+    }
+}
+
+fn check_agent(agent_id: &str) -> Result<(), String> {
+    if container_status(agent_id).is_none() {
+        return Err(format!("Unknown agent {}", agent_id));
+    }
+    touch_agent(agent_id);
+    Ok(())
+}
+
+// Queues a prompt for its agent as `prompt_id`, the way prompts from the frontend are.
+// The agent is woken if it is asleep when the prompt's turn comes.
+pub async fn submit_prompt(prompt_id: String, prompt: Prompt) -> Result<(), String> {
+    check_agent(&prompt.agent_id)?;
+    enqueue_prompt(prompt_id, prompt).await
+}
+
+// Reports how the prompt the agent was running ended, see scheduler.rs
+fn finish_running_prompt(agent_id: &str, outcome: RunOutcome, error: Option<&str>) {
+    if let Some(prompt_id) = RUNNING_PROMPTS.lock().unwrap().remove(agent_id) {
+        scheduler::prompt_finished(&prompt_id, outcome, error);
+    }
+}
 
-            let message_id = uuid::Uuid::new_v4().to_string();
-            if let serde_json::Value::Object(ref mut map) = json_with_history {
-                map.insert("message_id".to_string(), serde_json::Value::String(message_id.clone()));
+fn is_asleep(agent_id: &str) -> bool {
+    matches!(container_status(agent_id), Some(ContainerStatus::Stopped) | Some(ContainerStatus::Paused))
+}
+
+async fn enqueue_prompt(prompt_id: String, prompt: Prompt) -> Result<(), String> {
+    let agent_id = prompt.agent_id.clone();
+    PROMPT_QUEUES.lock().await.entry(agent_id.clone()).or_default().push_back(PendingPrompt {
+        id: prompt_id,
+        prompt,
+        queued_at: chrono::Utc::now().to_rfc3339(),
+    });
+    send_queue(&agent_id).await;
+    dispatch_next(&agent_id).await;
+    Ok(())
+}
+
+fn queue_info(queue: Option<&VecDeque<PendingPrompt>>) -> Vec<QueuedPrompt> {
+    queue.into_iter().flatten()
+        .map(|pending| QueuedPrompt {
+            id: pending.id.clone(),
+            text: pending.prompt.text.clone(),
+            file_names: pending.prompt.files.iter().map(|f| f.name.clone()).collect(),
+            queued_at: pending.queued_at.clone(),
+        })
+        .collect()
+}
+
+pub async fn prompt_queue(agent_id: &str) -> Vec<QueuedPrompt> {
+    queue_info(PROMPT_QUEUES.lock().await.get(agent_id))
+}
+
+async fn send_queue(agent_id: &str) {
+    let prompts = prompt_queue(agent_id).await;
+    send_to_clients(Outbound::Server(ServerMessage::Queue { agent_id: agent_id.to_string(), prompts })).await;
+}
+
+// Removes a prompt that hasn't been sent yet
+pub async fn cancel_queued_prompt(agent_id: &str, prompt_id: &str) -> Result<Vec<QueuedPrompt>, String> {
+    {
+        let mut queues = PROMPT_QUEUES.lock().await;
+        let queue = queues.get_mut(agent_id).ok_or("Prompt not found")?;
+        let index = queue.iter().position(|p| p.id == prompt_id).ok_or("Prompt not found")?;
+        queue.remove(index);
+    }
+    scheduler::prompt_finished(prompt_id, RunOutcome::Cancelled, None);
+    send_queue(agent_id).await;
+    Ok(prompt_queue(agent_id).await)
+}
+
+// Moves a queued prompt to `position`, 0 being next
+pub async fn move_queued_prompt(agent_id: &str, prompt_id: &str, position: usize) -> Result<Vec<QueuedPrompt>, String> {
+    {
+        let mut queues = PROMPT_QUEUES.lock().await;
+        let queue = queues.get_mut(agent_id).ok_or("Prompt not found")?;
+        let index = queue.iter().position(|p| p.id == prompt_id).ok_or("Prompt not found")?;
+        let pending = queue.remove(index).unwrap();
+        queue.insert(position.min(queue.len()), pending);
+    }
+    send_queue(agent_id).await;
+    Ok(prompt_queue(agent_id).await)
+}
+
+pub async fn clear_prompt_queue(agent_id: &str) {
+    PROMPT_QUEUES.lock().await.remove(agent_id);
+    RUNNING_PROMPTS.lock().unwrap().remove(agent_id);
+}
+
+// Sends the agent its next queued prompt if it can take one: it isn't busy, and it is connected or can be woken.
+// Agents that are neither get theirs when they connect.
+async fn dispatch_next(agent_id: &str) {
+    let next = {
+        let mut queues = PROMPT_QUEUES.lock().await;
+        let queue = match queues.get_mut(agent_id) {
+            Some(queue) if !queue.is_empty() => queue,
+            _ => return,
+        };
+        let state = run_state::get(agent_id);
+        if state.is_busy() || (!is_agent_connected(agent_id) && !is_asleep(agent_id)) {
+            return;
+        }
+        // Marking the agent busy before letting go of the queue keeps two prompts from going out at once
+        if let Err(e) = run_state::transition(agent_id, AgentRunState::Queued) {
+            error!("{}", e);
+            return;
+        }
+        queue.pop_front()
+    };
+    let Some(next) = next else { return };
+    RUNNING_PROMPTS.lock().unwrap().insert(agent_id.to_string(), next.id.clone());
+    send_status(agent_id, AgentRunState::Queued).await;
+    send_queue(agent_id).await;
+    start_prompt(next).await;
+}
+
+// Sends a prompt taken off the queue. A stopped or paused agent is woken first, without holding up the caller.
+async fn start_prompt(pending: PendingPrompt) {
+    let agent_id = pending.prompt.agent_id.clone();
+    if is_asleep(&agent_id) {
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = wake_agent(&agent_id).await {
+                error!("Failed to wake agent {}: {}", agent_id, e);
+                let _ = change_run_state(&agent_id, AgentRunState::Errored).await;
+                finish_running_prompt(&agent_id, RunOutcome::Failed, Some(&format!("Failed to wake agent: {}", e)));
+                send_to_clients(protocol::error(format!("Failed to wake agent: {}", e), Some(&agent_id))).await;
+                return;
             }
+            deliver_prompt(pending).await;
+        });
+        return;
+    }
+    deliver_prompt(pending).await;
+}
 
-            // Send complete message (including files) to agent
-            let json_string = serde_json::to_string(&json_with_history).unwrap();
-            let chunk_size = 1024;
-            let total_chunks = (json_string.len() + chunk_size - 1) / chunk_size;
-
-            for (i, chunk) in json_string.as_bytes().chunks(chunk_size).enumerate() {
-                let chunk_message = serde_json::json!({
-                    "message_id": message_id,
-                    "chunk": i,
-                    "total_chunks": total_chunks,
-                    "data": String::from_utf8_lossy(chunk),
-                });
-
-                if let Err(e) = conn_info.tx.lock().await.send(warp::ws::Message::text(serde_json::to_string(&chunk_message).unwrap())).await {
-                    eprintln!("Error forwarding chunk to agent {}: {}", agent_id_value, e);
-                }
+// Sends a prompt to its agent, or puts it back at the front of the queue if it doesn't go out.
+// An agent that reconnected meanwhile gets it on the new connection, otherwise when it next connects.
+async fn deliver_prompt(pending: PendingPrompt) {
+    let agent_id = pending.prompt.agent_id.clone();
+    loop {
+        let connections = agent_connections(&agent_id);
+        if deliver_client_message(ChatMessage::Prompt(pending.prompt.clone()), agent_id.clone()).await {
+            return;
+        }
+
+        // Agent init decides whether the agent is still Queued under the same lock, so the prompt
+        // is either sent again here or dispatched by the init
+        let mut queues = PROMPT_QUEUES.lock().await;
+        if agent_connections(&agent_id) != connections {
+            continue;
+        }
+        info!("Prompt {} didn't reach agent {}, putting it back in the queue", pending.id, agent_id);
+        let state = if is_agent_connected(&agent_id) { AgentRunState::Idle } else { AgentRunState::Disconnected };
+        RUNNING_PROMPTS.lock().unwrap().remove(&agent_id);
+        queues.entry(agent_id.clone()).or_default().push_front(pending);
+        run_state::set(&agent_id, state);
+        drop(queues);
+
+        send_status(&agent_id, state).await;
+        send_queue(&agent_id).await;
+        return;
+    }
+}
+
+// Sends a prompt or stop to the agent, returning whether it went out. Messages are only stored once they do.
+async fn deliver_client_message(mut message: ChatMessage, agent_id: String) -> bool {
+    let message_id = uuid::Uuid::new_v4().to_string();
+    message.set_ids(&message_id, &agent_id);
+
+    let mut delivered = false;
+    // Not holding the connections lock while sending, so one slow agent doesn't hold up the others
+    let tx = AGENT_CONNECTIONS.lock().await.get(&agent_id).map(|conn| conn.tx.clone());
+    if let Some(tx) = tx {
+        let request = match &message {
+            ChatMessage::Prompt(prompt) => {
+                let recent_messages = get_recent_agent_messages(agent_id.clone(), 5);
+                // Get system prompt from CONTAINERS
+                let system_prompt = CONTAINERS.lock().unwrap().iter()
+                    .find(|c| c.agent_id == agent_id)
+                    .map(|c| c.system_prompt.clone())
+                    .unwrap_or_default();
+                Some(ToAgent::Prompt(AgentPrompt {
+                    prompt: prompt.clone(),
+                    recent_messages,
+                    additional_system_prompt: system_prompt,
+                }))
+            },
+            ChatMessage::Stop(stop) => Some(ToAgent::Stop(stop.clone())),
+            ChatMessage::Message(_) => None,
+        };
+
+        // Send complete message (including files) to agent
+        if let Some(request) = request {
+            match send_reliably(&agent_id, &tx, &request).await {
+                Ok(()) => delivered = true,
+                Err(e) => error!("Error forwarding chunk to agent {}: {}", agent_id, e),
             }
         }
-        
-        let message_id = uuid::Uuid::new_v4().to_string();
-
-        // Create a version of the message for storage without the files field
-        let storage_json = if let serde_json::Value::Object(mut map) = json {
-            map.remove("files"); // Remove files field before storage
-            map.insert("message_id".to_string(), serde_json::Value::String(message_id.clone()));
-            serde_json::Value::Object(map)
-        } else {
-            json
+    }
+
+    if !delivered {
+        return false;
+    }
+    if matches!(message, ChatMessage::Prompt(_)) {
+        if let Err(e) = change_run_state(&agent_id, AgentRunState::Running).await {
+            error!("{}", e);
+        }
+    }
+
+    // Files are stored as attachments rather than in the message
+    let files = match &mut message {
+        ChatMessage::Prompt(prompt) => std::mem::take(&mut prompt.files),
+        _ => Vec::new(),
+    };
+
+    process_message(message_id.clone(), message, &agent_id).await;
+    if !files.is_empty() {
+        if let Err(e) = db::save_attachments(&message_id, &files) {
+            error!("Error saving attachments for agent {}: {}", agent_id, e);
+        }
+    }
+    delivered
+}
+
+// Sends a message to an agent in binary frames (see frames.rs), or to agents that don't read those in
+// 1024 byte JSON text chunks, which app.py reassembles by message_id
+async fn send_chunked(
+    tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
+    json_string: &str,
+    binary_frames: bool,
+) -> Result<(), String> {
+    if binary_frames {
+        for frame in frames::split(json_string.as_bytes()) {
+            tx.lock().await.send(warp::ws::Message::binary(frame)).await
+                .map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+
+    let message_id = uuid::Uuid::new_v4().to_string();
+    let chunks = text_chunks(json_string, 1024);
+    let total_chunks = chunks.len();
+    for (i, data) in chunks.into_iter().enumerate() {
+        let chunk_message = Chunk {
+            message_id: &message_id,
+            chunk: i,
+            total_chunks,
+            data,
         };
+        tx.lock().await.send(warp::ws::Message::text(protocol::encode(&chunk_message))).await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Pieces of at most `size` bytes, cut between characters so none is split across two chunks
+fn text_chunks(text: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = (start + size).min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&text[start..end]);
+        start = end;
+    }
+    chunks
+}
 
-        process_message(message_id, storage_json, &agent_id_value, &app_handle).await;
+// Numbers a message in the agent's session and sends it, holding the session so messages go out in order
+async fn send_reliably(
+    agent_id: &str,
+    tx: &Arc<AsyncMutex<futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>,
+    message: &ToAgent,
+) -> Result<(), String> {
+    let session = delivery::session(agent_id);
+    let mut session = session.lock().await;
+    let (seq, encoded) = session.track(message);
+    let result = send_chunked(tx, &encoded, session.binary_frames).await;
+    // Prompts that fail to send go back on the queue rather than being replayed (see deliver_prompt),
+    // and failed stops are reported to the client rather than stopping a later prompt
+    if let (Err(_), Some(seq), ToAgent::Prompt(_) | ToAgent::Stop(_)) = (&result, seq, message) {
+        session.untrack(seq);
     }
-} 
+    result
+}
+
+// Sends a request to a connected agent without touching its prompt state
+pub async fn send_to_agent(agent_id: &str, message: ToAgent) -> Result<(), String> {
+    let tx = AGENT_CONNECTIONS.lock().await
+        .get(agent_id)
+        .filter(|_| is_agent_connected(agent_id))
+        .map(|conn| conn.tx.clone())
+        .ok_or("Agent is not connected")?;
+    send_reliably(agent_id, &tx, &message).await
+}
 
 async fn process_message(
     message_id: String,
-    json_message: serde_json::Value,
+    mut message: ChatMessage,
     agent_id: &str,
-    app_handle: &tauri::AppHandle,
 ) {
-    if let Some(client_conn) = CLIENT_CONNECTION.lock().await.as_ref() {
-        let json_string = serde_json::to_string(&json_message).unwrap();
-        client_conn.lock().await.send(warp::ws::Message::text(json_string)).await.unwrap();
-    }
-
-    let mut messages = MESSAGES.lock().unwrap();
-    messages.insert(message_id.clone(), json_message);
-    save_messages(&app_handle, &messages).unwrap();
+    message.set_ids(&message_id, agent_id);
+    let json_message = match serde_json::to_value(&message) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Error serializing message for agent {}: {}", agent_id, e);
+            return;
+        }
+    };
+    send_to_clients(Outbound::Chat(message)).await;
 
+    // Appends just this message to the database instead of rewriting every message
     let mut containers = CONTAINERS.lock().unwrap();
     if let Some(container) = containers.iter_mut().find(|c| c.agent_id == agent_id) {
-        container.message_ids.push(message_id);
-        save_containers(&app_handle, &containers).unwrap();
+        if let Err(e) = db::append_message(agent_id, &message_id, &json_message) {
+            error!("Error saving message for agent {}: {}", agent_id, e);
+        }
+        container.message_ids.push(message_id.clone());
+    }
+    MESSAGES.lock().unwrap().insert(message_id, json_message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::fake::FakeRuntime;
+
+    #[test]
+    fn text_chunks_never_split_a_character() {
+        // "é" is two bytes, so a 2 byte cut after "a" would fall inside it
+        let text = "aé".repeat(10);
+        let chunks = text_chunks(&text, 2);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 2));
+        assert_eq!(chunks.concat(), text);
+        assert_eq!(&chunks[..2], &["a", "é"]);
+        assert!(text_chunks("", 4).is_empty());
+    }
+
+    // Next text message on the test connection, pings aside
+    async fn recv_json(agent: &mut warp::test::WsClient) -> serde_json::Value {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), agent.recv()).await
+                .expect("Timed out waiting for the app")
+                .expect("Connection closed");
+            if let Ok(text) = message.to_str() {
+                return serde_json::from_str(text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn prompt_to_a_stopped_agent_gets_delivered_after_wake() {
+        let _globals = crate::TEST_GLOBALS.lock().await;
+        db::open_in_memory();
+        let runtime = Arc::new(FakeRuntime::default());
+        crate::set_runtime(runtime.clone());
+
+        let mut container = crate::test_agent("wake-1");
+        container.ws_token = "wake-token".to_string();
+        container.id = runtime.add_stopped("agent-wake-1");
+        container.status = ContainerStatus::Stopped;
+        let container_id = container.id.clone();
+        CONTAINERS.lock().unwrap().push(container);
+
+        let prompt = Prompt {
+            message_id: String::new(),
+            agent_id: "wake-1".to_string(),
+            text: "Summarize the news".to_string(),
+            show_ui: true,
+            files: Vec::new(),
+        };
+        submit_prompt("prompt-1".to_string(), prompt).await.unwrap();
+        assert_eq!(run_state::get("wake-1"), AgentRunState::Queued);
+        assert!(prompt_queue("wake-1").await.is_empty());
+
+        // The container is started, then the prompt waits for the agent to connect
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !runtime.is_running(&container_id) {
+            assert!(Instant::now() < deadline, "Agent was not woken");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(container_status("wake-1"), Some(ContainerStatus::Running));
+        assert_eq!(run_state::get("wake-1"), AgentRunState::Queued);
+
+        let mut agent = warp::test::ws().path("/ws").handshake(ws_route()).await.unwrap();
+        agent.send_text(serde_json::json!({
+            "connection-type": "agent",
+            "message-type": "init",
+            "container_id": "wake-1",
+            "token": "wake-token",
+            "prompt_running": "idle",
+            "last_seq": 0,
+        }).to_string()).await;
+        assert_eq!(recv_json(&mut agent).await["message-type"], "session");
+
+        // Sent in JSON text chunks, the agent didn't ask for binary frames
+        let mut chunks = Vec::new();
+        loop {
+            let chunk = recv_json(&mut agent).await;
+            chunks.push(chunk["data"].as_str().unwrap().to_string());
+            if chunks.len() == chunk["total_chunks"].as_u64().unwrap() as usize {
+                break;
+            }
+        }
+        let delivered: serde_json::Value = serde_json::from_str(&chunks.concat()).unwrap();
+        assert_eq!(delivered["message-type"], "prompt");
+        assert_eq!(delivered["text"], "Summarize the news");
+        assert_eq!(delivered["seq"], 1);
+
+        assert_eq!(run_state::get("wake-1"), AgentRunState::Running);
+        assert_eq!(RUNNING_PROMPTS.lock().unwrap().get("wake-1").map(String::as_str), Some("prompt-1"));
+        // Stored in the conversation once it went out
+        let message_ids = CONTAINERS.lock().unwrap().iter().find(|c| c.agent_id == "wake-1").unwrap().message_ids.clone();
+        assert_eq!(message_ids.len(), 1);
     }
 }
@@ -26,6 +26,43 @@ export interface BaseContainer {
 export interface Container extends BaseContainer {
   id: string;
   vnc_port: number;
+  image?: string;
+  resource_limits?: ResourceLimits;
+  status?: 'running' | 'paused' | 'stopped';
+  idle_timeout_minutes?: number | null;
+  snapshots?: Snapshot[];
+  mounts?: Mount[];
+}
+
+export interface Mount {
+  source:
+    | { type: 'agent_volume'; name: string }
+    | { type: 'shared_volume'; name: string }
+    | { type: 'host_path'; path: string };
+  target: string;
+  read_only: boolean;
+}
+
+export interface Snapshot {
+  id: string;
+  name: string;
+  image: string;
+  checkpoint: string | null;
+  message_ids: string[];
+  created_at: string;
+}
+
+export interface StorageNotice {
+  store: string;
+  message: string;
+  at: string;
+}
+
+export interface ResourceLimits {
+  cpus: number | null;
+  memory_mb: number | null;
+  pids: number | null;
+  storage_gb: number | null;
 }
 
 export interface BuildingContainer extends BaseContainer {
@@ -88,9 +125,33 @@ export default function App() {
     if (isSetupComplete) {
       loadUser();
       loadExistingAgents();
+      loadStorageNotices();
     }
   }, [isSetupComplete]);
 
+  useEffect(() => {
+    if (isWebPlatform) return;
+    const unlistenPromise = listen<StorageNotice>('storage-notice', (event) => {
+      setError({ primaryMessage: event.payload.message, timeout: 15000, type: 'warning' });
+    });
+    return () => {
+      unlistenPromise.then(fn => fn());
+    };
+  }, []);
+
+  // Tell the user if their data had to be restored from a backup while the app was starting
+  async function loadStorageNotices() {
+    if (isWebPlatform) return;
+    try {
+      const notices = await invoke<StorageNotice[]>('get_storage_notices');
+      if (notices.length > 0) {
+        setError({ primaryMessage: notices.map(notice => notice.message).join(' '), timeout: 15000, type: 'warning' });
+      }
+    } catch (error) {
+      console.log(error);
+    }
+  }
+
   async function loadUser() {
     try {
       const user = await invoke<User>('get_user_data');
@@ -1,12 +1,14 @@
 import { useEffect, useState } from "react";
 import { RightSidebar } from "./RightSidebar";
 import ViewAgent from "./view-agent/ViewAgent";
+import type { QueuedPrompt } from "./view-agent/PromptQueue";
 import type { Agent, User, Message } from "@/App";
 import { useError } from "@/hooks/ErrorContext";
 import {
     invoke,
     isWebPlatform,
     getWebSocketUrl,
+    PROTOCOL_VERSION,
     recordWebMessage,
     setWebPromptStatus,
     createWebAgentReply,
@@ -20,14 +22,16 @@ interface AgentProps {
     setEditSystemPromptPopup: (value: boolean) => void;
 }
 
-export type promptRunningType = "running" | "stopped" | "loading" | "na";
+// Run state of the agent, see run_state.rs
+export type promptRunningType = "idle" | "queued" | "running" | "stopping" | "disconnected" | "errored";
 
 export default function AgentSection({ user, currentAgent, setEditSystemPromptPopup }: AgentProps) {
     const { setError } = useError();
     const [messages, setMessages] = useState<Message[]>([]);
     const [ws, setWs] = useState<WebSocket | null>(null);
     const [isWebSocketOpen, setIsWebSocketOpen] = useState<boolean>(false);
-    const [promptRunning, setPromptRunning] = useState<promptRunningType>("na");
+    const [promptRunning, setPromptRunning] = useState<promptRunningType>("disconnected");
+    const [queue, setQueue] = useState<QueuedPrompt[]>([]);
     const [switchingAgent, setSwitchingAgent] = useState<boolean>(true);
     const agentId = currentAgent?.agent_id;
     //@ts-ignore
@@ -44,13 +48,15 @@ export default function AgentSection({ user, currentAgent, setEditSystemPromptPo
                 setMessages(messages);
                 const promptRunning = await invoke<string>('get_prompt_running', { agentId });
                 setPromptRunning(promptRunning as promptRunningType);
+                if (!isWebPlatform) setQueue(await invoke<QueuedPrompt[]>('get_prompt_queue', { agentId }));
                 setSwitchingAgent(false);
             } catch (error) {
                 setError({ primaryMessage: "Oops! We had an issue loading your agent. Refresh and try again.", timeout: 5000 });
             }
         };
         loadAgent();
-        setPromptRunning("na");
+        setPromptRunning("disconnected");
+        setQueue([]);
         setSwitchingAgent(true);
     }, [agentId, container_id]);
 
@@ -72,15 +78,19 @@ export default function AgentSection({ user, currentAgent, setEditSystemPromptPo
         const maxReconnectDelay = 30000; // Maximum delay of 30 seconds
         const baseDelay = 1000; // Start with 1 second delay
 
-        const connect = () => {
-            const socketUrl = getWebSocketUrl();
-            if (!socketUrl) return;
+        const connect = async () => {
+            const [port, token] = await Promise.all([
+                invoke<number>('get_websocket_port'),
+                invoke<string>('get_websocket_token'),
+            ]);
+            const socketUrl = getWebSocketUrl(port);
+            if (!socketUrl || closing) return;
             localWS = new WebSocket(socketUrl);
             setWs(localWS);
             localWS.addEventListener('open', () => {
                 setIsWebSocketOpen(true);
                 reconnectAttempt = 0; // Reset attempt counter on successful connection
-                const message = { "message-type": "init", "connection-type": "client" };
+                const message = { "message-type": "init", "connection-type": "client", "token": token, "subscribe": [agentId], "protocol_version": PROTOCOL_VERSION };
                 localWS?.send(JSON.stringify(message));
             });
 
@@ -88,10 +98,21 @@ export default function AgentSection({ user, currentAgent, setEditSystemPromptPo
                 const message = JSON.parse(event.data);
                 //TODO: Fix this (it is needed to manage multiple agents)
                 if (message.agent_id && message.agent_id !== agentId) return;
+                if (message['message-type'] === 'error') {
+                    setError({ primaryMessage: message.error, timeout: 5000 });
+                    // A rejected prompt or stop leaves the state as it was, so drop the optimistic one
+                    invoke<promptRunningType>('get_prompt_running', { agentId }).then(setPromptRunning).catch(() => {});
+                    return;
+                }
                 if (message.prompt_running) {
                     setPromptRunning(message.prompt_running as promptRunningType);
-                    if (message.prompt_running === "na") setError({ primaryMessage: "Oops! The connection to your agent was lost. We are trying to reconnect.", timeout: 2500, type: 'warning' });
+                    if (message.prompt_running === "disconnected") setError({ primaryMessage: "Oops! The connection to your agent was lost. We are trying to reconnect.", timeout: 2500, type: 'warning' });
+                }
+                if (message['message-type'] === 'queue') {
+                    setQueue(message.prompts as QueuedPrompt[]);
+                    return;
                 }
+                if (message['message-type'] === 'status') return;
                 setMessages(prevMessages => [...prevMessages, message]);
             });
 
@@ -121,13 +142,13 @@ export default function AgentSection({ user, currentAgent, setEditSystemPromptPo
         if (!agentId) return;
 
         if (isWebPlatform) {
-            setPromptRunning("stopped");
-            setWebPromptStatus(agentId, "stopped");
+            setPromptRunning("idle");
+            setWebPromptStatus(agentId, "idle");
             return;
         }
 
-        setPromptRunning("loading");
-        ws?.send(JSON.stringify({ "message-type": "stop", "agent_id": agentId, "show_ui": false }));
+        setPromptRunning("stopping");
+        ws?.send(JSON.stringify({ "message-type": "stop", "agent_id": agentId, "show_ui": false, "protocol_version": PROTOCOL_VERSION }));
     }
 
     const sendMessageWrapper = (prompt: string, files: { name: string; data: string }[] | undefined) => {
@@ -151,14 +172,29 @@ export default function AgentSection({ user, currentAgent, setEditSystemPromptPo
                 const reply = createWebAgentReply(agentId);
                 setMessages(prev => [...prev, reply]);
                 recordWebMessage(agentId, reply);
-                setPromptRunning("stopped");
-                setWebPromptStatus(agentId, "stopped");
+                setPromptRunning("idle");
+                setWebPromptStatus(agentId, "idle");
             }, 750);
             return;
         }
 
-        setPromptRunning("running");
-        sendMessage(JSON.stringify(message));
+        // Prompts for a busy agent wait behind the running one and show up in the queue instead
+        if (promptRunning === "idle" || promptRunning === "errored") setPromptRunning("queued");
+        sendMessage(JSON.stringify({ ...message, "protocol_version": PROTOCOL_VERSION }));
+    }
+
+    function cancelQueuedPrompt(promptId: string) {
+        if (!agentId) return;
+        invoke<QueuedPrompt[]>('cancel_queued_prompt', { agentId, promptId })
+            .then(setQueue)
+            .catch(() => setError({ primaryMessage: "That prompt was already sent to your agent.", timeout: 3000, type: 'warning' }));
+    }
+
+    function moveQueuedPrompt(promptId: string, position: number) {
+        if (!agentId) return;
+        invoke<QueuedPrompt[]>('move_queued_prompt', { agentId, promptId, position })
+            .then(setQueue)
+            .catch(() => setError({ primaryMessage: "That prompt was already sent to your agent.", timeout: 3000, type: 'warning' }));
     }
 
     return (
@@ -169,6 +205,9 @@ export default function AgentSection({ user, currentAgent, setEditSystemPromptPo
                 agentId={agentId}
                 sendMessage={sendMessageWrapper}
                 promptRunning={promptRunning}
+                queue={queue}
+                cancelQueuedPrompt={cancelQueuedPrompt}
+                moveQueuedPrompt={moveQueuedPrompt}
                 stopAgent={stopAgent}
                 isWebSocketOpen={isWebSocketOpen}
                 setEditSystemPromptPopup={setEditSystemPromptPopup}
@@ -4,6 +4,7 @@ import { ChevronRight, ChevronLeft } from "lucide-react"
 import { Message } from "@/App";
 import { MessageInput } from "./view-agent/MessageInput";
 import { MessageBubble } from "./view-agent/MessageBubble";
+import { PromptQueue, QueuedPrompt } from "./view-agent/PromptQueue";
 import { promptRunningType } from "./AgentSection";
 import { useRef, useEffect } from "react";
 
@@ -12,12 +13,15 @@ interface RightSidebarProps {
   agentId: string | undefined;
   sendMessage: (message: string, files: { name: string; data: string }[] | undefined) => void;
   promptRunning: promptRunningType;
+  queue: QueuedPrompt[];
+  cancelQueuedPrompt: (promptId: string) => void;
+  moveQueuedPrompt: (promptId: string, position: number) => void;
   stopAgent: () => void;
   isWebSocketOpen: boolean;
   setEditSystemPromptPopup: (value: boolean) => void;
 }
 
-export function RightSidebar({ messages, agentId, sendMessage, promptRunning, stopAgent, isWebSocketOpen, setEditSystemPromptPopup }: RightSidebarProps) {
+export function RightSidebar({ messages, agentId, sendMessage, promptRunning, queue, cancelQueuedPrompt, moveQueuedPrompt, stopAgent, isWebSocketOpen, setEditSystemPromptPopup }: RightSidebarProps) {
   const { isOpen } = useRightSidebar()
   const prevHeightRef = useRef(0);
   const prevMessagesLengthRef = useRef(0);
@@ -96,6 +100,7 @@ export function RightSidebar({ messages, agentId, sendMessage, promptRunning, st
             uniqueMessages.map((message, index) => <MessageBubble key={index} message={message} />)
           )}
         </div>
+        <PromptQueue prompts={queue} cancelPrompt={cancelQueuedPrompt} movePrompt={moveQueuedPrompt} />
         <div className="flex flex-row justify-end w-full px-4">
           {agentId && <MessageInput
             sendMessage={sendMessage}
@@ -76,11 +76,11 @@ export function MessageInput({ sendMessage, promptRunning, currentAgentID, stopA
     };
 
     const promptButton = () => {
-        if (promptRunning === "na" || !isWebSocketOpen) {
+        if (promptRunning === "disconnected" || !isWebSocketOpen) {
             return <div className="w-8 h-8 bg-slate-800 rounded-full flex items-center justify-center hover:cursor-not-allowed">
             </div>
         }
-        if (promptRunning === "stopped" && isWebSocketOpen) {
+        if ((promptRunning === "idle" || promptRunning === "errored") && isWebSocketOpen) {
             return <div className="w-8 h-8 bg-slate-800 rounded-full flex items-center justify-center hover:cursor-pointer hover:bg-slate-700" onClick={sendMessageWrapper}>
                 <ArrowUpIcon className="h-5 w-5 stroke-[1.5] text-white" />
             </div>
@@ -90,7 +90,7 @@ export function MessageInput({ sendMessage, promptRunning, currentAgentID, stopA
                 <SquareIcon className="h-3 w-3 stroke-[1.5] text-white bg-white" />
             </div>
         }
-        if ((promptRunning === "loading")) {
+        if (promptRunning === "queued" || promptRunning === "stopping") {
             return <div className="w-8 h-8 bg-slate-800 rounded-full flex items-center justify-center hover:cursor-not-allowed">
                 <Spinner size="small" className="border-slate-800" />
             </div>
@@ -134,7 +134,8 @@ export function MessageInput({ sendMessage, promptRunning, currentAgentID, stopA
                     target.style.height = `${target.scrollHeight}px`;
                 }}
                 onKeyDown={(e) => {
-                    if (e.key === 'Enter' && promptRunning === "stopped" && isWebSocketOpen) {
+                    // Prompts sent while the agent is busy wait in its queue
+                    if (e.key === 'Enter' && promptRunning !== "disconnected" && isWebSocketOpen) {
                         e.preventDefault(); // Prevents creating a new line
                         sendMessageWrapper();
                     }
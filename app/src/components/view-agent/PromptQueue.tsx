@@ -0,0 +1,50 @@
+import { ChevronUpIcon, Cross2Icon, FileTextIcon } from "@radix-ui/react-icons";
+
+// Prompt waiting for the agent to finish the one before it, see websocket.rs
+export interface QueuedPrompt {
+    id: string;
+    text: string;
+    file_names: string[];
+    queued_at: string;
+}
+
+interface PromptQueueInterface {
+    prompts: QueuedPrompt[];
+    cancelPrompt: (promptId: string) => void;
+    movePrompt: (promptId: string, position: number) => void;
+}
+
+export function PromptQueue({ prompts, cancelPrompt, movePrompt }: PromptQueueInterface) {
+    if (prompts.length === 0) return null;
+
+    return (
+        <div className="w-full flex flex-col gap-1 px-4 pb-2">
+            <p className="text-xs text-slate-500">Queued ({prompts.length})</p>
+            {prompts.map((prompt, index) => (
+                <div key={prompt.id} className="flex items-center gap-2 bg-slate-100 p-2 rounded-md">
+                    <span className="text-sm truncate flex-1">{prompt.text}</span>
+                    {prompt.file_names.length > 0 && (
+                        <span className="flex items-center gap-1 text-xs text-slate-500">
+                            <FileTextIcon className="h-3 w-3" />
+                            {prompt.file_names.length}
+                        </span>
+                    )}
+                    {index > 0 && (
+                        <button
+                            onClick={() => movePrompt(prompt.id, index - 1)}
+                            className="hover:bg-slate-200 p-1 rounded-md"
+                        >
+                            <ChevronUpIcon className="h-4 w-4" />
+                        </button>
+                    )}
+                    <button
+                        onClick={() => cancelPrompt(prompt.id)}
+                        className="hover:bg-slate-200 p-1 rounded-md"
+                    >
+                        <Cross2Icon className="h-4 w-4" />
+                    </button>
+                </div>
+            ))}
+        </div>
+    )
+}
@@ -4,7 +4,7 @@ type InvokeArgs = Record<string, unknown> | undefined;
 type Listener<T = unknown> = (event: { event: string; payload: T }) => void;
 export type UnlistenFn = () => void;
 
-type PromptStatus = "running" | "stopped" | "loading" | "na";
+type PromptStatus = "idle" | "queued" | "running" | "stopping" | "disconnected" | "errored";
 
 type Container = {
   id: string;
@@ -75,8 +75,12 @@ export async function emit<T>(eventName: string, payload?: T): Promise<void> {
   }
 }
 
-export function getWebSocketUrl(): string | null {
-  return isWebPlatform ? null : "ws://localhost:3030/ws";
+// Version of the websocket messages, see protocol.rs
+export const PROTOCOL_VERSION = 1;
+
+// The port comes from the get_websocket_port command, the server may not be on the default one
+export function getWebSocketUrl(port: number): string | null {
+  return isWebPlatform ? null : `ws://localhost:${port}/ws`;
 }
 
 let messageCounter = 1;
@@ -119,7 +123,7 @@ function ensureDefaultData(): void {
 
   stubState.containers.push(container);
   stubState.messages.set(defaultAgentId, [welcomeMessage]);
-  stubState.promptStatus.set(defaultAgentId, "stopped");
+  stubState.promptStatus.set(defaultAgentId, "idle");
 }
 
 ensureDefaultData();
@@ -186,7 +190,7 @@ async function webInvoke<T>(command: string, args?: InvokeArgs): Promise<T> {
 
       stubState.containers.push(container);
       stubState.messages.set(agentId, []);
-      stubState.promptStatus.set(agentId, "stopped");
+      stubState.promptStatus.set(agentId, "idle");
       return structuredClone(container) as T;
     }
     case "delete_agent_container": {
@@ -219,7 +223,7 @@ async function webInvoke<T>(command: string, args?: InvokeArgs): Promise<T> {
     }
     case "get_prompt_running": {
       const { agentId } = (args ?? {}) as { agentId: string };
-      return (stubState.promptStatus.get(agentId) ?? "stopped") as T;
+      return (stubState.promptStatus.get(agentId) ?? "idle") as T;
     }
     case "start_container":
       return undefined as T;